@@ -5,12 +5,15 @@ use axum::{
     routing::get,
     Router,
 };
-use futures_util::SinkExt;
+use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::timeout;
-use tokio_tungstenite::{connect_async_with_config, tungstenite::client::IntoClientRequest};
+use tokio_tungstenite::{
+    connect_async_with_config,
+    tungstenite::{client::IntoClientRequest, Message},
+};
 use tower_http::cors::{Any, CorsLayer};
 use tracing::{error, info};
 
@@ -18,6 +21,148 @@ use tracing::{error, info};
 struct AppState {
     websocket_url: String,
     api_key: String,
+    /// Applies to the connection and, separately, to the answer of the deep probe.
+    timeout: Duration,
+    /// Set when the health check goes beyond the connection and waits for the server to answer.
+    deep_probe: Option<DeepProbe>,
+}
+
+/// Message sent by the deep probe once connected, the server is considered healthy when it
+/// answers with any message.
+#[derive(Clone)]
+struct DeepProbe {
+    init: Message,
+}
+
+/// Framing of the deep probe init message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ProbeFraming {
+    /// A binary MessagePack message, as expected by the Kyutai server.
+    Msgpack,
+    /// A text message with the JSON as is.
+    Text,
+}
+
+impl DeepProbe {
+    /// 80ms of silence at 24kHz, the Kyutai server answers with a `Step` message once it has
+    /// been processed.
+    fn default_init() -> serde_json::Value {
+        serde_json::json!({ "type": "Audio", "pcm": vec![0.0; 1920] })
+    }
+
+    fn new(init: &serde_json::Value, framing: ProbeFraming) -> Self {
+        let init = match framing {
+            ProbeFraming::Msgpack => {
+                let mut buf = vec![];
+                encode_msgpack(init, &mut buf);
+                Message::Binary(buf)
+            }
+            ProbeFraming::Text => Message::Text(init.to_string()),
+        };
+        Self { init }
+    }
+}
+
+/// Encodes a JSON value as MessagePack, floats are encoded as f32 like the Kyutai clients do.
+fn encode_msgpack(value: &serde_json::Value, buf: &mut Vec<u8>) {
+    use serde_json::Value;
+
+    let encode_len = |buf: &mut Vec<u8>, len: usize, fix: u8, fix_max: usize, tag16: u8| {
+        if len <= fix_max {
+            buf.push(fix | len as u8)
+        } else if len <= u16::MAX as usize {
+            buf.push(tag16);
+            buf.extend_from_slice(&(len as u16).to_be_bytes())
+        } else {
+            buf.push(tag16 + 1);
+            buf.extend_from_slice(&(len as u32).to_be_bytes())
+        }
+    };
+    match value {
+        Value::Null => buf.push(0xc0),
+        Value::Bool(b) => buf.push(if *b { 0xc3 } else { 0xc2 }),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                buf.push(0xd3);
+                buf.extend_from_slice(&i.to_be_bytes())
+            } else if let Some(u) = n.as_u64() {
+                buf.push(0xcf);
+                buf.extend_from_slice(&u.to_be_bytes())
+            } else {
+                buf.push(0xca);
+                let f = n.as_f64().unwrap_or_default() as f32;
+                buf.extend_from_slice(&f.to_be_bytes())
+            }
+        }
+        Value::String(s) => {
+            if s.len() <= 31 {
+                buf.push(0xa0 | s.len() as u8)
+            } else if s.len() <= u8::MAX as usize {
+                buf.push(0xd9);
+                buf.push(s.len() as u8)
+            } else {
+                encode_len(buf, s.len(), 0xa0, 0, 0xda)
+            }
+            buf.extend_from_slice(s.as_bytes())
+        }
+        Value::Array(items) => {
+            encode_len(buf, items.len(), 0x90, 15, 0xdc);
+            for item in items {
+                encode_msgpack(item, buf)
+            }
+        }
+        Value::Object(map) => {
+            encode_len(buf, map.len(), 0x80, 15, 0xde);
+            for (key, value) in map {
+                encode_msgpack(&Value::String(key.clone()), buf);
+                encode_msgpack(value, buf)
+            }
+        }
+    }
+}
+
+/// The URL with the password and the values of the query parameters that look like credentials
+/// replaced, so that it can be reported by `/info`.
+fn redact_url(url: &str) -> String {
+    const SECRET_PARAMS: [&str; 6] = ["key", "token", "secret", "pass", "auth", "sig"];
+
+    let (scheme, rest) = match url.split_once("://") {
+        Some((scheme, rest)) => (format!("{}://", scheme), rest),
+        None => (String::new(), url),
+    };
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, rest) = rest.split_at(authority_end);
+    let authority = match authority.rsplit_once('@') {
+        Some((userinfo, host)) => match userinfo.split_once(':') {
+            Some((user, _)) => format!("{}:***@{}", user, host),
+            None => format!("***@{}", host),
+        },
+        None => authority.to_string(),
+    };
+    let (rest, fragment) = match rest.split_once('#') {
+        Some((rest, fragment)) => (rest, format!("#{}", fragment)),
+        None => (rest, String::new()),
+    };
+    let rest = match rest.split_once('?') {
+        Some((path, query)) => {
+            let query = query
+                .split('&')
+                .map(|param| match param.split_once('=') {
+                    Some((name, _))
+                        if SECRET_PARAMS
+                            .iter()
+                            .any(|s| name.to_ascii_lowercase().contains(s)) =>
+                    {
+                        format!("{}=***", name)
+                    }
+                    _ => param.to_string(),
+                })
+                .collect::<Vec<_>>();
+            format!("{}?{}", path, query.join("&"))
+        }
+        None => rest.to_string(),
+    };
+    format!("{}{}{}{}", scheme, authority, rest, fragment)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -30,7 +175,12 @@ struct HealthResponse {
     error: Option<String>,
 }
 
-async fn check_websocket(ws_url: &str, api_key: &str) -> Result<bool, String> {
+async fn check_websocket(
+    ws_url: &str,
+    api_key: &str,
+    probe_timeout: Duration,
+    deep_probe: Option<&DeepProbe>,
+) -> Result<bool, String> {
     // Create request with authentication header
     let mut request = ws_url.into_client_request()
         .map_err(|e| format!("Invalid WebSocket URL: {}", e))?;
@@ -40,11 +190,31 @@ async fn check_websocket(ws_url: &str, api_key: &str) -> Result<bool, String> {
         api_key.parse().map_err(|e| format!("Invalid API key: {}", e))?,
     );
 
-    match timeout(Duration::from_secs(5), connect_async_with_config(request, None, false)).await {
+    match timeout(probe_timeout, connect_async_with_config(request, None, false)).await {
         Ok(Ok((mut ws_stream, _))) => {
+            let answered = match deep_probe {
+                None => Ok(()),
+                Some(probe) => {
+                    let exchange = async {
+                        ws_stream
+                            .send(probe.init.clone())
+                            .await
+                            .map_err(|e| format!("Cannot send the probe message: {}", e))?;
+                        match ws_stream.next().await {
+                            Some(Ok(_)) => Ok(()),
+                            Some(Err(e)) => Err(format!("WebSocket probe failed: {}", e)),
+                            None => Err("WebSocket closed before answering the probe".to_string()),
+                        }
+                    };
+                    match timeout(probe_timeout, exchange).await {
+                        Ok(answered) => answered,
+                        Err(_) => Err("WebSocket probe timeout".to_string()),
+                    }
+                }
+            };
             // Try to close gracefully
             let _ = ws_stream.close(None).await;
-            Ok(true)
+            answered.map(|()| true)
         }
         Ok(Err(e)) => Err(format!("WebSocket connection failed: {}", e)),
         Err(_) => Err("WebSocket connection timeout".to_string()),
@@ -52,7 +222,13 @@ async fn check_websocket(ws_url: &str, api_key: &str) -> Result<bool, String> {
 }
 
 async fn health_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let ws_check = check_websocket(&state.websocket_url, &state.api_key).await;
+    let ws_check = check_websocket(
+        &state.websocket_url,
+        &state.api_key,
+        state.timeout,
+        state.deep_probe.as_ref(),
+    )
+    .await;
 
     let (ws_available, error) = match ws_check {
         Ok(available) => (available, None),
@@ -83,10 +259,19 @@ async fn health_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse
     (http_status, Json(response))
 }
 
-async fn info_handler() -> impl IntoResponse {
+async fn info_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     Json(serde_json::json!({
         "service": "Kyutai STT Health Check Service",
         "version": env!("CARGO_PKG_VERSION"),
+        "git_commit": env!("GIT_COMMIT"),
+        "build_timestamp": env!("BUILD_TIMESTAMP").parse::<u64>().ok(),
+        "config": {
+            "websocket_urls": [redact_url(&state.websocket_url)],
+            "timeout_secs": state.timeout.as_secs_f64(),
+            // The server is only probed when `/health` is requested, there is no polling.
+            "poll_interval_secs": null,
+            "deep_probe": state.deep_probe.is_some(),
+        },
         "endpoints": {
             "/health": "Health check endpoint",
             "/info": "Service information"
@@ -110,18 +295,47 @@ async fn main() {
     let api_key = std::env::var("API_KEY")
         .unwrap_or_else(|_| "public_token".to_string());
 
+    // The deep probe sends a message once connected and waits for the server to answer it, the
+    // message is `HEALTH_PROBE_INIT_JSON` encoded according to `HEALTH_PROBE_FRAMING`.
+    let deep_probe = std::env::var("HEALTH_DEEP_PROBE")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    let deep_probe = deep_probe.then(|| {
+        let framing = match std::env::var("HEALTH_PROBE_FRAMING").as_deref() {
+            Err(_) | Ok("msgpack") => ProbeFraming::Msgpack,
+            Ok("text") => ProbeFraming::Text,
+            Ok(framing) => panic!("HEALTH_PROBE_FRAMING must be msgpack or text, got {framing}"),
+        };
+        let init = match std::env::var("HEALTH_PROBE_INIT_JSON") {
+            Ok(init) => serde_json::from_str(&init)
+                .expect("HEALTH_PROBE_INIT_JSON must be a valid JSON value"),
+            Err(_) => DeepProbe::default_init(),
+        };
+        DeepProbe::new(&init, framing)
+    });
+
+    let probe_timeout = std::env::var("HEALTH_TIMEOUT_SECS")
+        .map(|t| t.parse::<f64>().expect("HEALTH_TIMEOUT_SECS must be a number of seconds"))
+        .unwrap_or(5.0);
+    let probe_timeout = Duration::from_secs_f64(probe_timeout);
+
     let health_port = std::env::var("HEALTH_PORT")
         .unwrap_or_else(|_| "8001".to_string())
         .parse::<u16>()
         .expect("HEALTH_PORT must be a valid port number");
 
     info!("Health check service starting...");
-    info!("Monitoring WebSocket at: {}", websocket_url);
+    info!("Monitoring WebSocket at: {}", redact_url(&websocket_url));
+    if deep_probe.is_some() {
+        info!("Deep probe enabled, waiting for the server to answer the init message");
+    }
     info!("Health endpoint will be available at: http://0.0.0.0:{}/health", health_port);
 
     let state = Arc::new(AppState {
         websocket_url: websocket_url.clone(),
         api_key: api_key.clone(),
+        timeout: probe_timeout,
+        deep_probe,
     });
 
     // Configure CORS to allow requests from any origin
@@ -0,0 +1,59 @@
+/* Copyright (c) Kyutai, all rights reserved.
+ * This source code is licensed under the license found in the
+ * LICENSE file in the root directory of this source tree.
+ *
+ * C API of the kyutai speech-to-text engine, link against the libkyutai_stt_rs
+ * shared library built by `cargo build --release --lib`.
+ *
+ * The audio is mono float pcm at the sample rate of the model, see
+ * stt_sample_rate. Functions returning an int return STT_OK on success and a
+ * negative error code otherwise, stt_last_error then describes the failure.
+ */
+#ifndef KYUTAI_STT_H
+#define KYUTAI_STT_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define STT_OK 0
+#define STT_NO_WORD 1
+#define STT_ERROR_NULL_POINTER (-1)
+#define STT_ERROR_INVALID_UTF8 (-2)
+#define STT_ERROR_INFERENCE (-3)
+/* The engine panicked, reset the model before using it again. */
+#define STT_ERROR_PANIC (-4)
+
+typedef struct SttModel SttModel;
+
+typedef struct {
+    /* Only valid until the next call on the same model. */
+    const char *text;
+    double start_time;
+    double stop_time;
+    float confidence;
+} SttWord;
+
+/* Loads the model from a Hugging Face repo, returns NULL on failure. */
+SttModel *stt_model_new(const char *hf_repo, int cpu);
+void stt_model_free(SttModel *model);
+
+/* The sample rate expected by stt_push_pcm. */
+int stt_sample_rate(const SttModel *model);
+int stt_push_pcm(SttModel *model, const float *pcm, size_t len);
+/* To be called once all the audio has been pushed, decodes the last words. */
+int stt_flush(SttModel *model);
+/* Starts a new audio stream. */
+int stt_reset(SttModel *model);
+/* Returns STT_NO_WORD when no completed word is available. */
+int stt_next_word(SttModel *model, SttWord *word);
+
+const char *stt_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,163 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Copies of the transcripts produced by the server, written to `--archive-dir` as one json file
+//! per session for auditing.
+use anyhow::{Context, Result};
+use kyutai_stt_rs::stream::Word;
+
+/// Longest session id used as is in a file name, longer ids are truncated.
+const MAX_ID_LEN: usize = 64;
+
+/// The directory where the transcripts are archived.
+#[derive(Debug)]
+pub struct Archive {
+    dir: std::path::PathBuf,
+    /// The version of the model, `<repo>@<commit>`, updated when the model is reloaded.
+    model: std::sync::Mutex<String>,
+}
+
+/// A transcription to archive once it is complete.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub archive: std::sync::Arc<Archive>,
+    /// The request id, see `--request-id-header`.
+    pub id: String,
+    /// The route the transcript has been requested on, e.g. `/transcribe/ws`.
+    pub route: &'static str,
+    pub client_ip: Option<std::net::IpAddr>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Record<'a> {
+    session_id: &'a str,
+    route: &'a str,
+    client_ip: Option<std::net::IpAddr>,
+    model: &'a str,
+    server_version: &'a str,
+    /// Duration of the transcribed audio in seconds.
+    audio_duration: f64,
+    /// When the transcription completed, in seconds since the unix epoch.
+    timestamp: u64,
+    words: &'a [Word],
+}
+
+impl Archive {
+    pub fn new(dir: std::path::PathBuf, model: String) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create the archive dir {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            model: std::sync::Mutex::new(model),
+        })
+    }
+
+    /// Records the transcripts completed from now on with this model version.
+    pub fn set_model(&self, model: String) {
+        *self.model.lock().unwrap_or_else(|e| e.into_inner()) = model
+    }
+}
+
+impl Session {
+    /// Writes the transcript to `<dir>/<session id>.json`, see `create_unique`. Returns the path
+    /// of the file.
+    pub fn write(&self, words: &[Word], audio_duration: f64) -> Result<std::path::PathBuf> {
+        use std::io::Write;
+
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let model = self
+            .archive
+            .model
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let record = Record {
+            session_id: &self.id,
+            route: self.route,
+            client_ip: self.client_ip,
+            model: &model,
+            server_version: env!("CARGO_PKG_VERSION"),
+            audio_duration,
+            timestamp,
+            words,
+        };
+        let json = serde_json::to_vec_pretty(&record)?;
+        let (mut file, path) = create_unique(&self.archive.dir, &self.id, "json")?;
+        file.write_all(&json)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Writes the transcript on a blocking thread, a failure is only logged so that it does not
+    /// affect the response.
+    pub fn save(self, words: Vec<Word>, audio_duration: f64) {
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = self.write(&words, audio_duration) {
+                eprintln!("[{}] Cannot archive the transcript: {err:#}", self.id)
+            }
+        });
+    }
+}
+
+/// Creates `<dir>/<session id>.<extension>`, a suffix is added when the file already exists, e.g.
+/// for a request id reused by a client.
+pub fn create_unique(
+    dir: &std::path::Path,
+    id: &str,
+    extension: &str,
+) -> Result<(std::fs::File, std::path::PathBuf)> {
+    let stem = file_stem(id);
+    for idx in 0.. {
+        let name = match idx {
+            0 => format!("{stem}.{extension}"),
+            idx => format!("{stem}-{idx}.{extension}"),
+        };
+        let path = dir.join(name);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path);
+        match file {
+            Ok(file) => return Ok((file, path)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(err).with_context(|| format!("cannot create {}", path.display()));
+            }
+        }
+    }
+    unreachable!("the suffixes are unbounded")
+}
+
+/// The session id with the characters that are not safe in a file name replaced by `_`.
+fn file_stem(id: &str) -> String {
+    let stem = id
+        .chars()
+        .take(MAX_ID_LEN)
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    if stem.is_empty() {
+        "session".to_string()
+    } else {
+        stem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_stems() {
+        assert_eq!(file_stem("0123abcd"), "0123abcd");
+        assert_eq!(file_stem("../etc/passwd"), "___etc_passwd");
+        assert_eq!(file_stem("a.b c"), "a_b_c");
+        assert_eq!(file_stem(""), "session");
+        assert_eq!(file_stem(&"x".repeat(100)).len(), MAX_ID_LEN);
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Decoding of the audio input files with symphonia, all the formats enabled by kaudio are
+//! supported: wav, flac, mp3, ogg/vorbis, mp4 with aac or alac, mkv/webm and caf.
+use anyhow::{Context, Result};
+use symphonia::core::audio::SampleBuffer;
+
+/// The extensions passed as a hint to the prober, other files are only recognized from their
+/// content.
+const EXTENSIONS: [&str; 13] = [
+    "wav", "wave", "flac", "mp3", "ogg", "oga", "m4a", "mp4", "aac", "mkv", "webm", "mka", "caf",
+];
+
+/// What was detected when decoding a file.
+#[derive(Debug, Clone)]
+pub struct AudioInfo {
+    pub container: &'static str,
+    pub codec: &'static str,
+    pub channels: usize,
+    pub sample_rate: u32,
+    /// Duration in seconds of the decoded audio.
+    pub duration: f64,
+}
+
+impl std::fmt::Display for AudioInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} container, {} codec, {} channels at {}Hz, {:.2}s",
+            self.container, self.codec, self.channels, self.sample_rate, self.duration
+        )
+    }
+}
+
+/// Level of a signal, the samples are expected in [-1, 1].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Levels {
+    pub peak: f32,
+    pub rms: f32,
+    /// Number of samples at full scale, a sign that the recording has been clipped.
+    pub clipped: usize,
+}
+
+impl Levels {
+    pub fn measure(pcm: &[f32]) -> Self {
+        let mut levels = Self::default();
+        let mut sum_squares = 0f64;
+        for &v in pcm.iter() {
+            levels.peak = levels.peak.max(v.abs());
+            sum_squares += (v * v) as f64;
+            // 16 bits full scale is 32767/32768, just under 1.
+            if v.abs() >= 0.9999 {
+                levels.clipped += 1
+            }
+        }
+        if !pcm.is_empty() {
+            levels.rms = (sum_squares / pcm.len() as f64).sqrt() as f32;
+        }
+        levels
+    }
+}
+
+pub fn to_dbfs(level: f32) -> f32 {
+    20. * level.log10()
+}
+
+/// The level that `--normalize-gain` brings the audio to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum GainTarget {
+    /// The loudest sample, -1dBFS by default.
+    Peak,
+    /// The root mean square of the samples, -20dBFS by default.
+    Rms,
+}
+
+impl GainTarget {
+    pub fn default_level(self) -> f32 {
+        match self {
+            Self::Peak => -1.,
+            Self::Rms => -20.,
+        }
+    }
+}
+
+/// Scales `pcm` so that its peak or rms is at `level` dBFS, the samples that end up outside of
+/// [-1, 1] are clamped. Silence is left as is.
+pub fn normalize(pcm: &mut [f32], target: GainTarget, level: f32) {
+    let levels = Levels::measure(pcm);
+    let measured = match target {
+        GainTarget::Peak => levels.peak,
+        GainTarget::Rms => levels.rms,
+    };
+    if measured <= 0. {
+        return;
+    }
+    let gain = 10f32.powf(level / 20.) / measured;
+    for v in pcm.iter_mut() {
+        *v = (*v * gain).clamp(-1., 1.)
+    }
+}
+
+/// The container format from the first bytes of a file.
+fn sniff_container(header: &[u8]) -> &'static str {
+    let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+    if at(0, b"RIFF") && at(8, b"WAVE") {
+        "wav"
+    } else if at(0, b"fLaC") {
+        "flac"
+    } else if at(0, b"OggS") {
+        "ogg"
+    } else if at(0, b"ID3") || matches!(header, [0xff, b, ..] if b & 0xe0 == 0xe0) {
+        "mp3"
+    } else if at(4, b"ftyp") {
+        "mp4"
+    } else if at(0, &[0x1a, 0x45, 0xdf, 0xa3]) {
+        "mkv"
+    } else if at(0, b"caff") {
+        "caf"
+    } else {
+        "unknown"
+    }
+}
+
+/// An audio file whose format has been probed, ready to be decoded.
+struct Opened {
+    format: Box<dyn symphonia::core::formats::FormatReader>,
+    track: symphonia::core::formats::Track,
+    container: &'static str,
+    codec: &'static str,
+    sample_rate: u32,
+}
+
+fn open(path: &std::path::Path) -> Result<Opened> {
+    use std::io::Read;
+
+    let mut header = [0u8; 12];
+    let header_len = std::fs::File::open(path)?.read(&mut header)?;
+    let container = sniff_container(&header[..header_len]);
+
+    let file = std::fs::File::open(path)?;
+    let mss = symphonia::core::io::MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = symphonia::core::probe::Hint::new();
+    let extension = path.extension().and_then(|e| e.to_str());
+    if let Some(extension) = extension.filter(|e| EXTENSIONS.contains(&e.to_lowercase().as_str())) {
+        hint.with_extension(extension);
+    }
+    let probed = symphonia::default::get_probe()
+        .format(&hint, mss, &Default::default(), &Default::default())
+        .context(
+            "unsupported audio format, the supported ones are wav, flac, mp3, ogg/vorbis, mp4 \
+             with aac or alac, mkv/webm and caf",
+        )?;
+    let format = probed.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != symphonia::core::codecs::CODEC_TYPE_NULL)
+        .context("no supported audio track")?
+        .clone();
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .context("unknown sample rate")?;
+    let codec = symphonia::default::get_codecs()
+        .get_codec(track.codec_params.codec)
+        .map_or("unknown", |c| c.short_name);
+    Ok(Opened {
+        format,
+        track,
+        container,
+        codec,
+        sample_rate,
+    })
+}
+
+/// Reads the header of an audio file without decoding it, the duration is only known when the
+/// container tells the number of frames, it is 0 otherwise. Fails if the codec is not supported.
+pub fn probe(path: &std::path::Path) -> Result<AudioInfo> {
+    let Opened {
+        track,
+        container,
+        codec,
+        sample_rate,
+        ..
+    } = open(path)?;
+    symphonia::default::get_codecs().make(&track.codec_params, &Default::default())?;
+    let frames = track.codec_params.n_frames.unwrap_or(0);
+    Ok(AudioInfo {
+        container,
+        codec,
+        channels: track.codec_params.channels.map_or(0, |c| c.count()),
+        sample_rate,
+        duration: frames as f64 / sample_rate as f64,
+    })
+}
+
+/// Decodes the first channel of an audio file, returns the pcm and what was detected.
+pub fn decode(path: &std::path::Path) -> Result<(Vec<f32>, AudioInfo)> {
+    let Opened {
+        mut format,
+        track,
+        container,
+        codec,
+        sample_rate,
+    } = open(path)?;
+    let track_id = track.id;
+    let codecs = symphonia::default::get_codecs();
+    let mut decoder = codecs.make(&track.codec_params, &Default::default())?;
+
+    let mut channels = track.codec_params.channels.map_or(0, |c| c.count());
+    let mut pcm = vec![];
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(symphonia::core::errors::Error::IoError(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupted packet, the following ones can still be decoded.
+            Err(symphonia::core::errors::Error::DecodeError(_)) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        channels = decoded.spec().channels.count();
+        let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+        samples.copy_interleaved_ref(decoded);
+        pcm.extend(samples.samples().iter().step_by(channels).copied());
+    }
+    let info = AudioInfo {
+        container,
+        codec,
+        channels,
+        sample_rate,
+        duration: pcm.len() as f64 / sample_rate as f64,
+    };
+    Ok((pcm, info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff() {
+        assert_eq!(sniff_container(b"RIFF\x24\x00\x00\x00WAVEfmt "), "wav");
+        assert_eq!(sniff_container(b"fLaC\x00\x00\x00\x22"), "flac");
+        assert_eq!(sniff_container(b"ID3\x04\x00"), "mp3");
+        assert_eq!(sniff_container(&[0xff, 0xfb, 0x90, 0x64]), "mp3");
+        assert_eq!(sniff_container(b"\x00\x00\x00\x20ftypM4A "), "mp4");
+        assert_eq!(sniff_container(b"RIFF\x24\x00\x00\x00AVI "), "unknown");
+        assert_eq!(sniff_container(b""), "unknown");
+    }
+
+    #[test]
+    fn normalize_levels() {
+        let tone = (0..24_000)
+            .map(|i| 0.1 * (i as f32 * 0.1).sin())
+            .collect::<Vec<_>>();
+        let levels = Levels::measure(&tone);
+        assert!((levels.peak - 0.1).abs() < 1e-3, "{levels:?}");
+        assert!((levels.rms - 0.1 / 2f32.sqrt()).abs() < 1e-3, "{levels:?}");
+        assert_eq!(levels.clipped, 0);
+
+        let mut pcm = tone.clone();
+        normalize(&mut pcm, GainTarget::Peak, -6.);
+        assert!((to_dbfs(Levels::measure(&pcm).peak) + 6.).abs() < 0.01);
+
+        let mut pcm = tone.clone();
+        normalize(&mut pcm, GainTarget::Rms, -20.);
+        assert!((to_dbfs(Levels::measure(&pcm).rms) + 20.).abs() < 0.01);
+
+        // A gain that pushes the peaks over full scale, they are clamped.
+        let mut pcm = tone;
+        normalize(&mut pcm, GainTarget::Rms, 0.);
+        let levels = Levels::measure(&pcm);
+        assert_eq!(levels.peak, 1.);
+        assert!(levels.clipped > 0);
+
+        let mut silence = vec![0f32; 100];
+        normalize(&mut silence, GainTarget::Peak, -1.);
+        assert!(silence.iter().all(|&v| v == 0.));
+    }
+
+    #[test]
+    fn decode_wav_fixture() -> Result<()> {
+        let path =
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tone_16k.wav");
+        let (pcm, info) = decode(&path)?;
+        assert_eq!(info.container, "wav");
+        assert_eq!(info.channels, 1);
+        assert_eq!(info.sample_rate, 16_000);
+        assert_eq!(pcm.len(), 32_000);
+        assert!((info.duration - 2.).abs() < 1e-6);
+        let probed = probe(&path)?;
+        assert_eq!(probed.sample_rate, 16_000);
+        assert!((probed.duration - 2.).abs() < 1e-6);
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Throughput benchmark, the same audio buffer is transcribed several times and the time spent
+//! on each 80ms chunk is reported.
+use anyhow::Result;
+use kyutai_stt_rs::resample::Resampling;
+
+#[derive(Debug, clap::Args)]
+pub struct BenchArgs {
+    #[command(flatten)]
+    model: crate::ModelArgs,
+
+    /// Audio file to transcribe, a 440Hz tone of `--duration` seconds is used if not set.
+    #[arg(long)]
+    audio: Option<String>,
+
+    /// Duration in seconds of the generated audio.
+    #[arg(long, default_value_t = 10.)]
+    duration: f64,
+
+    /// Number of times the audio is transcribed.
+    #[arg(long, default_value_t = 5)]
+    iterations: usize,
+
+    /// Print the results as json on stdout, in addition to the table on stderr.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct BenchResult {
+    iterations: usize,
+    batch_size: usize,
+    audio_seconds: f64,
+    chunks: usize,
+    chunk_min_ms: f64,
+    chunk_median_ms: f64,
+    chunk_p95_ms: f64,
+    rtf: f64,
+    peak_memory_mb: Option<f64>,
+}
+
+/// A 440Hz tone at `sample_rate`.
+pub fn tone(duration: f64, sample_rate: usize) -> Vec<f32> {
+    let len = (duration * sample_rate as f64) as usize;
+    let tone =
+        |i: usize| 0.1 * (2. * std::f32::consts::PI * 440. * i as f32 / sample_rate as f32).sin();
+    (0..len).map(tone).collect()
+}
+
+pub fn run(args: &BenchArgs) -> Result<()> {
+    if args.iterations == 0 {
+        anyhow::bail!("--iterations must be at least 1");
+    }
+    let device = crate::device(args.model.cpu, args.model.strict_device)?;
+    eprintln!("Using device: {:?}", device);
+    let mut model = crate::Model::load(&args.model, false, &device)?;
+    let pcm = match args.audio.as_ref() {
+        Some(audio) => crate::load_audio(audio, &Resampling::new(model.sample_rate))?,
+        None => tone(args.duration, model.sample_rate),
+    };
+    let audio_seconds = pcm.len() as f64 / model.sample_rate as f64;
+
+    eprintln!("Warming up with {} steps", crate::WARMUP_STEPS);
+    model.warmup()?;
+    model.chunk_timings = Some(vec![]);
+    let mut processing_time = std::time::Duration::ZERO;
+    for iteration in 0..args.iterations {
+        eprintln!("Iteration {}/{}", iteration + 1, args.iterations);
+        let start_time = std::time::Instant::now();
+        model.run(vec![pcm.clone(); model.batch_size])?;
+        device.synchronize()?;
+        processing_time += start_time.elapsed();
+    }
+
+    let timings = model.chunk_timings.take().unwrap_or_default();
+    let mut latencies = timings.iter().map(|t| t.duration).collect::<Vec<_>>();
+    latencies.sort();
+    let percentile = |p: f64| {
+        let idx = ((latencies.len() - 1) as f64 * p).round() as usize;
+        latencies[idx].as_secs_f64() * 1000.
+    };
+    let total_audio = audio_seconds * (args.iterations * model.batch_size) as f64;
+    let result = BenchResult {
+        iterations: args.iterations,
+        batch_size: model.batch_size,
+        audio_seconds,
+        chunks: latencies.len(),
+        chunk_min_ms: percentile(0.),
+        chunk_median_ms: percentile(0.5),
+        chunk_p95_ms: percentile(0.95),
+        rtf: processing_time.as_secs_f64() / total_audio,
+        // The peak resident set size.
+        peak_memory_mb: crate::proc_status_mb("VmHWM"),
+    };
+
+    eprintln!("iterations       {}", result.iterations);
+    eprintln!("batch size       {}", result.batch_size);
+    eprintln!("audio            {:.2}s", result.audio_seconds);
+    eprintln!("chunks           {}", result.chunks);
+    eprintln!("chunk min        {:.2}ms", result.chunk_min_ms);
+    eprintln!("chunk median     {:.2}ms", result.chunk_median_ms);
+    eprintln!("chunk p95        {:.2}ms", result.chunk_p95_ms);
+    eprintln!("rtf              {:.3}", result.rtf);
+    match result.peak_memory_mb {
+        Some(peak_memory_mb) => eprintln!("peak memory      {peak_memory_mb:.1}MB"),
+        None => eprintln!("peak memory      n/a"),
+    }
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&result)?)
+    }
+    Ok(())
+}
@@ -0,0 +1,140 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Real-time factors measured by the `calibrate` subcommand, used by `--dry-run-estimate` to
+//! predict the processing time without loading the model.
+use anyhow::{Context, Result};
+use candle::Device;
+use std::collections::BTreeMap;
+
+#[derive(Debug, clap::Args)]
+pub struct CalibrateArgs {
+    #[command(flatten)]
+    model: crate::ModelArgs,
+
+    /// Duration in seconds of the audio transcribed to measure the real-time factor.
+    #[arg(long, default_value_t = 30.)]
+    duration: f64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DeviceCalibration {
+    /// Processing time divided by the audio duration.
+    pub rtf: f64,
+    pub batch_size: usize,
+    pub quantized: bool,
+}
+
+/// The calibrations indexed by device kind, `cpu`, `cuda` or `metal`.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Calibration {
+    pub devices: BTreeMap<String, DeviceCalibration>,
+}
+
+impl Calibration {
+    /// `$XDG_CONFIG_HOME/stt-rs/calibration.json`, defaulting to `~/.config`.
+    pub fn path() -> Result<std::path::PathBuf> {
+        let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None => {
+                let home = std::env::var_os("HOME").context("HOME is not set")?;
+                std::path::PathBuf::from(home).join(".config")
+            }
+        };
+        Ok(config_dir.join("stt-rs").join("calibration.json"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let calibration = serde_json::from_str(&std::fs::read_to_string(&path)?)
+            .with_context(|| format!("invalid calibration file {}", path.display()))?;
+        Ok(calibration)
+    }
+
+    pub fn save(&self) -> Result<std::path::PathBuf> {
+        let path = Self::path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?
+        }
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(path)
+    }
+}
+
+pub fn device_kind(dev: &Device) -> &'static str {
+    match dev {
+        Device::Cpu => "cpu",
+        Device::Cuda(_) => "cuda",
+        Device::Metal(_) => "metal",
+    }
+}
+
+/// Transcribes a generated tone once after a warmup and stores the resulting real-time factor
+/// for the current device.
+pub fn calibrate(args: &CalibrateArgs) -> Result<()> {
+    let device = crate::device(args.model.cpu, args.model.strict_device)?;
+    eprintln!("Using device: {:?}", device);
+    let mut model = crate::Model::load(&args.model, false, &device)?;
+    let pcm = crate::bench::tone(args.duration, model.sample_rate);
+    model.warmup()?;
+    let start_time = std::time::Instant::now();
+    model.run(vec![pcm; model.batch_size])?;
+    device.synchronize()?;
+    let audio_seconds = args.duration * model.batch_size as f64;
+    let rtf = start_time.elapsed().as_secs_f64() / audio_seconds;
+
+    let mut calibration = Calibration::load()?;
+    let kind = device_kind(&device);
+    calibration.devices.insert(
+        kind.to_string(),
+        DeviceCalibration {
+            rtf,
+            batch_size: model.batch_size,
+            quantized: args.model.quantized,
+        },
+    );
+    let path = calibration.save()?;
+    eprintln!(
+        "Measured RTF {rtf:.3} on {kind}, saved to {}",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Prints the expected processing time of the input files from the calibrated real-time factor
+/// of the device, the durations are read from the container headers and the model is not loaded.
+pub fn estimate(in_files: &[String], dev: &Device) -> Result<()> {
+    let kind = device_kind(dev);
+    let calibration = Calibration::load()?;
+    let device_calibration = calibration.devices.get(kind).with_context(|| {
+        format!("no calibration for {kind}, run the calibrate subcommand first")
+    })?;
+    let mut audio_seconds = 0.;
+    for in_file in in_files.iter() {
+        let path = std::path::Path::new(in_file);
+        let info = crate::audio::probe(path).with_context(|| format!("cannot read {in_file}"))?;
+        // The duration is unknown for some containers, these files are decoded.
+        let duration = match info.duration {
+            duration if duration > 0. => duration,
+            _ => {
+                let (_, info) = crate::audio::decode(path)
+                    .with_context(|| format!("cannot decode {in_file}"))?;
+                info.duration
+            }
+        };
+        audio_seconds += duration
+    }
+    let estimate = audio_seconds * device_calibration.rtf;
+    println!(
+        "{} file(s), {audio_seconds:.1}s of audio, estimated processing time {estimate:.1}s \
+         (RTF {:.3} on {kind} with a batch size of {})",
+        in_files.len(),
+        device_calibration.rtf,
+        device_calibration.batch_size,
+    );
+    Ok(())
+}
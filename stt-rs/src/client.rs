@@ -0,0 +1,233 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Client for the REST API of the `serve` subcommand, an alternative to loading the model in the
+//! current process.
+use anyhow::{Context, Result};
+use futures_util::{SinkExt, StreamExt};
+
+pub use crate::stream::AsrEvent;
+use crate::stream::Word;
+
+/// Decodes an audio file as mono pcm at `sample_rate`.
+pub fn load_pcm(path: &std::path::Path, sample_rate: usize) -> Result<Vec<f32>> {
+    let (pcm, pcm_rate) =
+        kaudio::pcm_decode(path).with_context(|| format!("cannot decode {}", path.display()))?;
+    crate::resample::Resampling::new(sample_rate).apply(pcm, pcm_rate as usize)
+}
+
+/// The sample rate announced by the server in its response to the websocket upgrade, see
+/// `SAMPLE_RATE_HEADER`.
+pub fn server_sample_rate(
+    response: &tokio_tungstenite::tungstenite::handshake::client::Response,
+) -> Result<usize> {
+    let value = response
+        .headers()
+        .get(crate::stream::SAMPLE_RATE_HEADER)
+        .context("the server did not send its sample rate")?;
+    let sample_rate = value.to_str().ok().and_then(|v| v.parse().ok());
+    sample_rate.with_context(|| format!("invalid sample rate {value:?}"))
+}
+
+/// An encoded audio file, in any format supported by the server.
+pub enum AudioInput {
+    Bytes(Vec<u8>),
+    File(std::path::PathBuf),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TranscribeOptions {
+    /// Abort the request if the transcript has not been received after this duration.
+    pub timeout: Option<std::time::Duration>,
+}
+
+/// The response of `POST /transcribe`.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Transcript {
+    pub text: String,
+    pub words: Vec<Word>,
+}
+
+pub struct SttClient {
+    base_url: String,
+    client: reqwest::Client,
+    /// Sent as a bearer token, for servers started with `--rest-api-key`.
+    api_key: Option<String>,
+}
+
+impl SttClient {
+    /// `base_url` is the address of the server, e.g. `http://localhost:8080`.
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+            api_key: None,
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = Some(api_key.to_string());
+        self
+    }
+
+    pub async fn transcribe(
+        &self,
+        audio: AudioInput,
+        opts: TranscribeOptions,
+    ) -> Result<Transcript> {
+        let body = match audio {
+            AudioInput::Bytes(bytes) => bytes,
+            AudioInput::File(path) => {
+                std::fs::read(&path).with_context(|| format!("cannot read {}", path.display()))?
+            }
+        };
+        let mut request = self
+            .client
+            .post(format!("{}/transcribe", self.base_url))
+            .body(body);
+        if let Some(timeout) = opts.timeout {
+            request = request.timeout(timeout)
+        }
+        if let Some(api_key) = self.api_key.as_ref() {
+            request = request.bearer_auth(api_key)
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let msg = response.text().await.unwrap_or_default();
+            anyhow::bail!("transcription failed with {status}: {msg}")
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Uploads the file as is, the server decodes and resamples it to the sample rate of the
+    /// model.
+    pub async fn transcribe_file(
+        &self,
+        path: &std::path::Path,
+        opts: TranscribeOptions,
+    ) -> Result<Transcript> {
+        self.transcribe(AudioInput::File(path.to_path_buf()), opts)
+            .await
+    }
+
+    /// Streams the audio of the file through the websocket API, the words are returned as soon
+    /// as the server has decoded them.
+    pub async fn transcribe_file_streaming(
+        &self,
+        path: &std::path::Path,
+        opts: TranscribeOptions,
+    ) -> Result<impl futures_util::Stream<Item = AsrEvent> + use<>> {
+        use tokio_tungstenite::tungstenite::{Message, client::IntoClientRequest};
+
+        let url = match self.base_url.split_once("://") {
+            Some(("https", rest)) => format!("wss://{rest}/transcribe/ws"),
+            Some((_, rest)) => format!("ws://{rest}/transcribe/ws"),
+            None => format!("ws://{}/transcribe/ws", self.base_url),
+        };
+        let mut request = url.into_client_request()?;
+        if let Some(api_key) = self.api_key.as_ref() {
+            let value = format!("Bearer {api_key}").parse()?;
+            request.headers_mut().insert("authorization", value);
+        }
+        let connect = tokio_tungstenite::connect_async(request);
+        let (socket, response) = match opts.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect).await??,
+            None => connect.await?,
+        };
+        let sample_rate = server_sample_rate(&response)?;
+        let path = path.to_path_buf();
+        let pcm = tokio::task::spawn_blocking(move || load_pcm(&path, sample_rate)).await??;
+        let (mut sender, receiver) = socket.split();
+        tokio::spawn(async move {
+            // Half a second of audio per message.
+            for chunk in pcm.chunks(sample_rate / 2) {
+                let data = chunk.iter().flat_map(|v| v.to_le_bytes()).collect();
+                if sender.send(Message::Binary(data)).await.is_err() {
+                    return;
+                }
+            }
+            let _ = sender.send(Message::Text("end".to_string())).await;
+        });
+        let events = receiver.filter_map(|msg| async move {
+            match msg {
+                Ok(Message::Text(text)) => serde_json::from_str(&text).ok(),
+                Ok(_) => None,
+                Err(err) => Some(AsrEvent::Error {
+                    message: err.to_string(),
+                }),
+            }
+        });
+        Ok(events)
+    }
+}
+
+/// A fixed set of clients, each with its own connection pool, that can be shared between tasks
+/// to run several transcriptions concurrently.
+#[derive(Clone)]
+pub struct SttClientPool {
+    clients: std::sync::Arc<std::sync::Mutex<Vec<SttClient>>>,
+    /// One permit per idle client.
+    available: std::sync::Arc<tokio::sync::Semaphore>,
+}
+
+impl SttClientPool {
+    pub fn new(base_url: &str, pool_size: usize) -> Self {
+        let clients = (0..pool_size).map(|_| SttClient::new(base_url)).collect();
+        Self {
+            clients: std::sync::Arc::new(std::sync::Mutex::new(clients)),
+            available: std::sync::Arc::new(tokio::sync::Semaphore::new(pool_size)),
+        }
+    }
+
+    pub fn with_api_key(self, api_key: &str) -> Self {
+        if let Ok(mut clients) = self.clients.lock() {
+            for client in clients.iter_mut() {
+                client.api_key = Some(api_key.to_string())
+            }
+        }
+        self
+    }
+
+    /// Borrows a client, waiting for one to be returned if they are all in use.
+    pub async fn acquire(&self) -> Result<SttClientGuard> {
+        let permit = self.available.clone().acquire_owned().await?;
+        let client = self
+            .clients
+            .lock()
+            .map_err(|_| anyhow::anyhow!("client pool lock poisoned"))?
+            .pop()
+            .context("no idle client in the pool")?;
+        Ok(SttClientGuard {
+            client: Some(client),
+            clients: self.clients.clone(),
+            _permit: permit,
+        })
+    }
+}
+
+/// A client borrowed from a `SttClientPool`, it is returned to the pool when dropped.
+pub struct SttClientGuard {
+    client: Option<SttClient>,
+    clients: std::sync::Arc<std::sync::Mutex<Vec<SttClient>>>,
+    // Fields are dropped after `drop` has put the client back in the pool.
+    _permit: tokio::sync::OwnedSemaphorePermit,
+}
+
+impl std::ops::Deref for SttClientGuard {
+    type Target = SttClient;
+
+    fn deref(&self) -> &SttClient {
+        // Only taken in drop.
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl Drop for SttClientGuard {
+    fn drop(&mut self) {
+        if let (Some(client), Ok(mut clients)) = (self.client.take(), self.clients.lock()) {
+            clients.push(client)
+        }
+    }
+}
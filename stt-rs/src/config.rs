@@ -0,0 +1,220 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+use candle::Device;
+use std::path::Path;
+
+use crate::error::{Result, SttError};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SttConfig {
+    pub audio_silence_prefix_seconds: f64,
+    pub audio_delay_seconds: f64,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Config {
+    pub mimi_name: String,
+    pub tokenizer_name: String,
+    pub card: usize,
+    pub text_card: usize,
+    pub dim: usize,
+    pub n_q: usize,
+    pub context: usize,
+    pub max_period: f64,
+    pub num_heads: usize,
+    pub num_layers: usize,
+    pub causal: bool,
+    pub stt_config: SttConfig,
+    /// The time horizons in seconds of the VAD heads, in head order. Each head predicts the
+    /// probability that there is no voice activity over its horizon.
+    #[serde(default)]
+    pub vad_horizons: Option<Vec<f64>>,
+    /// Number of VAD heads, only loaded when the VAD is enabled.
+    #[serde(default = "default_vad_num_heads")]
+    pub vad_num_heads: usize,
+    /// Output dimension of each VAD head.
+    #[serde(default = "default_vad_dim")]
+    pub vad_dim: usize,
+}
+
+/// The VAD head layout of the released models.
+fn default_vad_num_heads() -> usize {
+    4
+}
+
+fn default_vad_dim() -> usize {
+    6
+}
+
+/// The horizons of the VAD heads of the released models, e.g. `kyutai/stt-1b-en_fr-candle`.
+const DEFAULT_VAD_HORIZONS: [f64; 4] = [0.5, 1., 2., 3.];
+
+/// The shape of the VAD heads stored in a checkpoint, as `(dim, d_model)` per head.
+fn checkpoint_vad_heads(model_file: &Path, quantized: bool) -> Result<Vec<Vec<usize>>> {
+    let is_head = |name: &str| {
+        name.strip_prefix("extra_heads.")
+            .and_then(|name| name.strip_suffix(".weight"))
+            .is_some_and(|idx| idx.parse::<usize>().is_ok())
+    };
+    let mut heads = if quantized {
+        let mut file = std::fs::File::open(model_file)?;
+        let content = candle::quantized::gguf_file::Content::read(&mut file)?;
+        content
+            .tensor_infos
+            .iter()
+            .filter(|(name, _)| is_head(name))
+            .map(|(name, info)| (name.clone(), info.shape.dims().to_vec()))
+            .collect::<Vec<_>>()
+    } else {
+        let st = unsafe { candle::safetensors::MmapedSafetensors::new(model_file)? };
+        st.tensors()
+            .into_iter()
+            .filter(|(name, _)| is_head(name))
+            .map(|(name, view)| (name, view.shape().to_vec()))
+            .collect::<Vec<_>>()
+    };
+    heads.sort();
+    Ok(heads.into_iter().map(|(_, shape)| shape).collect())
+}
+
+impl Config {
+    /// The horizon of each VAD head, `None` when it is neither in the config nor known.
+    pub fn vad_horizons(&self) -> Vec<Option<f64>> {
+        let horizons = match self.vad_horizons.as_ref() {
+            Some(horizons) => horizons.as_slice(),
+            None => DEFAULT_VAD_HORIZONS.as_slice(),
+        };
+        (0..self.vad_num_heads)
+            .map(|idx| horizons.get(idx).copied())
+            .collect()
+    }
+
+    pub fn model_config(&self, vad: bool) -> moshi::lm::Config {
+        let lm_cfg = moshi::transformer::Config {
+            d_model: self.dim,
+            num_heads: self.num_heads,
+            num_layers: self.num_layers,
+            dim_feedforward: self.dim * 4,
+            causal: self.causal,
+            norm_first: true,
+            bias_ff: false,
+            bias_attn: false,
+            layer_scale: None,
+            context: self.context,
+            max_period: self.max_period as usize,
+            use_conv_block: false,
+            use_conv_bias: true,
+            cross_attention: None,
+            gating: Some(candle_nn::Activation::Silu),
+            norm: moshi::NormType::RmsNorm,
+            positional_embedding: moshi::transformer::PositionalEmbedding::Rope,
+            conv_layout: false,
+            conv_kernel_size: 3,
+            kv_repeat: 1,
+            max_seq_len: 4096 * 4,
+            shared_cross_attn: false,
+        };
+        let extra_heads = if vad {
+            Some(moshi::lm::ExtraHeadsConfig {
+                num_heads: self.vad_num_heads,
+                dim: self.vad_dim,
+            })
+        } else {
+            None
+        };
+        moshi::lm::Config {
+            transformer: lm_cfg,
+            depformer: None,
+            audio_vocab_size: self.card + 1,
+            text_in_vocab_size: self.text_card + 1,
+            text_out_vocab_size: self.text_card,
+            audio_codebooks: self.n_q,
+            conditioners: Default::default(),
+            extra_heads,
+        }
+    }
+}
+
+impl Config {
+    /// Parses the `config.json` of a model repo.
+    pub fn load(config_file: &Path) -> Result<Self> {
+        let config = std::fs::read_to_string(config_file)?;
+        serde_json::from_str(&config).map_err(|err| {
+            SttError::Config(format!("cannot parse {}: {err}", config_file.display()))
+        })
+    }
+
+    /// Whether the checkpoint has VAD heads at all.
+    pub fn has_vad_heads(&self, model_file: &Path, quantized: bool) -> Result<bool> {
+        Ok(!checkpoint_vad_heads(model_file, quantized)?.is_empty())
+    }
+
+    /// Checks that the checkpoint has the VAD heads described by the config, the weights would
+    /// otherwise fail to load with a shape error.
+    fn check_vad_heads(&self, model_file: &Path, quantized: bool) -> Result<()> {
+        let heads = checkpoint_vad_heads(model_file, quantized).map_err(|err| {
+            SttError::Config(format!("cannot read {}: {err}", model_file.display()))
+        })?;
+        if heads.is_empty() {
+            return Err(SttError::Config(format!(
+                "{} has no VAD heads, this model does not support the VAD",
+                model_file.display()
+            )));
+        }
+        let expected = [self.vad_dim, self.dim];
+        if heads.len() != self.vad_num_heads || heads.iter().any(|h| h[..] != expected) {
+            return Err(SttError::Config(format!(
+                "the config expects {} VAD heads of shape {expected:?} (vad_num_heads, vad_dim) \
+                 but {} has {} heads of shape {:?}",
+                self.vad_num_heads,
+                model_file.display(),
+                heads.len(),
+                heads[0],
+            )));
+        }
+        Ok(())
+    }
+
+    /// Loads the language model, from a gguf file when `quantized` is set and from a safetensors
+    /// file otherwise. `batch_size` is only required when batch elements have to be masked.
+    pub fn load_lm(
+        &self,
+        model_file: &Path,
+        quantized: bool,
+        batch_size: Option<usize>,
+        vad: bool,
+        dev: &Device,
+    ) -> Result<moshi::lm::LmModel> {
+        if vad {
+            self.check_vad_heads(model_file, quantized)?
+        }
+        let weights_error = |source| SttError::Weights {
+            path: model_file.to_path_buf(),
+            source,
+        };
+        let lm = if quantized {
+            let vb_lm =
+                candle_transformers::quantized_var_builder::VarBuilder::from_gguf(model_file, dev)
+                    .map_err(weights_error)?;
+            moshi::lm::LmModel::new_(
+                batch_size,
+                &self.model_config(vad),
+                moshi::nn::MaybeQuantizedVarBuilder::Quantized(vb_lm),
+            )
+        } else {
+            let dtype = dev.bf16_default_to_f32();
+            let vb_lm = unsafe {
+                candle_nn::VarBuilder::from_mmaped_safetensors(&[model_file], dtype, dev)
+                    .map_err(weights_error)?
+            };
+            moshi::lm::LmModel::new_(
+                batch_size,
+                &self.model_config(vad),
+                moshi::nn::MaybeQuantizedVarBuilder::Real(vb_lm),
+            )
+        };
+        lm.map_err(weights_error)
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Conversion of the word tokens to text.
+use crate::error::{Result, SttError};
+
+/// Checks the result of decoding the tokens of each word. A word that cannot be decoded, which
+/// usually means that the tokenizer does not match the model, is reported with its token ids and
+/// replaced with an empty word, or is an error in `strict` mode.
+#[derive(Debug, Clone, Default)]
+pub struct WordDecoder {
+    pub strict: bool,
+    /// Number of words that could not be decoded.
+    pub failures: usize,
+}
+
+impl WordDecoder {
+    pub fn new(strict: bool) -> Self {
+        Self {
+            strict,
+            failures: 0,
+        }
+    }
+
+    pub fn word<E: std::fmt::Display>(
+        &mut self,
+        tokens: &[u32],
+        decoded: std::result::Result<String, E>,
+    ) -> Result<String> {
+        match decoded {
+            Ok(text) => Ok(text),
+            Err(err) if self.strict => Err(SttError::Decode {
+                tokens: tokens.to_vec(),
+                message: err.to_string(),
+            }),
+            Err(err) => {
+                self.failures += 1;
+                eprintln!("Warning: cannot decode the tokens {tokens:?}, skipping the word: {err}");
+                Ok(String::new())
+            }
+        }
+    }
+
+    /// Decodes the tokens with a sentencepiece tokenizer.
+    pub fn decode(
+        &mut self,
+        tokenizer: &sentencepiece::SentencePieceProcessor,
+        tokens: &[u32],
+    ) -> Result<String> {
+        self.word(tokens, tokenizer.decode_piece_ids(tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands for a tokenizer with a two pieces vocabulary.
+    fn decode(tokens: &[u32]) -> std::result::Result<String, String> {
+        let vocab = ["▁hello", "▁world"];
+        let pieces = tokens.iter().map(|&id| {
+            let piece = vocab
+                .get(id as usize)
+                .ok_or(format!("id {id} is out of range"))?;
+            Ok::<_, String>(piece.replace('▁', ""))
+        });
+        pieces.collect()
+    }
+
+    #[test]
+    fn valid_tokens() -> Result<()> {
+        let mut decoder = WordDecoder::new(true);
+        assert_eq!(decoder.word(&[1], decode(&[1]))?, "world");
+        assert_eq!(decoder.failures, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_range_token() -> Result<()> {
+        let mut decoder = WordDecoder::new(false);
+        assert_eq!(decoder.word(&[0], decode(&[0]))?, "hello");
+        assert_eq!(decoder.word(&[0, 42], decode(&[0, 42]))?, "");
+        assert_eq!(decoder.failures, 1);
+
+        let mut decoder = WordDecoder::new(true);
+        let err = decoder.word(&[0, 42], decode(&[0, 42])).unwrap_err();
+        assert!(err.to_string().contains("[0, 42]"), "{err}");
+        Ok(())
+    }
+}
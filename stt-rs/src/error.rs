@@ -0,0 +1,68 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! The errors returned by the library, the variants tell at which stage a call failed so that
+//! embedders can branch on them, e.g. only retry on `SttError::Download`.
+use std::path::PathBuf;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SttError {
+    /// A file cannot be retrieved from the Hugging Face hub, usually a network failure.
+    #[error("cannot download {file}")]
+    Download {
+        /// `<repo>/<file>`, or the repo alone when the hub client itself failed.
+        file: String,
+        #[source]
+        source: hf_hub::api::sync::ApiError,
+    },
+
+    /// The model config cannot be parsed or does not match the checkpoint.
+    #[error("{0}")]
+    Config(String),
+
+    /// The sentencepiece model of the text tokenizer cannot be loaded.
+    #[error("cannot load the tokenizer {}", path.display())]
+    Tokenizer {
+        path: PathBuf,
+        #[source]
+        source: sentencepiece::SentencePieceError,
+    },
+
+    /// The cuda or metal device has been detected but cannot be initialized.
+    #[error("cannot initialize the {device} device")]
+    Device {
+        device: &'static str,
+        #[source]
+        source: candle::Error,
+    },
+
+    /// The weights cannot be loaded, e.g. a truncated file or a device without gguf support.
+    #[error("cannot load the weights from {}", path.display())]
+    Weights {
+        path: PathBuf,
+        #[source]
+        source: candle::Error,
+    },
+
+    /// The model failed while processing the audio.
+    #[error(transparent)]
+    Inference(#[from] candle::Error),
+
+    /// The audio or the options passed by the caller are invalid.
+    #[error("{0}")]
+    InvalidInput(String),
+
+    /// The audio cannot be resampled to the rate of the model.
+    #[error("cannot resample the audio: {0}")]
+    Resample(String),
+
+    /// The tokens of a word cannot be decoded, only returned by a strict `WordDecoder`.
+    #[error("cannot decode the tokens {tokens:?}: {message}")]
+    Decode { tokens: Vec<u32>, message: String },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, SttError>;
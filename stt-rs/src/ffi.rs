@@ -0,0 +1,312 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! C API, a model handle is created with `stt_model_new`, fed with mono pcm at `stt_sample_rate`
+//! through `stt_push_pcm` and the decoded words are pulled with `stt_next_word`. Functions return
+//! `STT_OK` on success and a negative error code otherwise, the error message of the last
+//! failing call on the current thread is available through `stt_last_error`.
+use std::ffi::{CStr, CString, c_char, c_int};
+
+use crate::Result;
+use crate::stream::Transcriber;
+
+pub const STT_OK: c_int = 0;
+pub const STT_NO_WORD: c_int = 1;
+pub const STT_ERROR_NULL_POINTER: c_int = -1;
+pub const STT_ERROR_INVALID_UTF8: c_int = -2;
+pub const STT_ERROR_INFERENCE: c_int = -3;
+/// The engine panicked, the handle should be reset before being used again.
+pub const STT_ERROR_PANIC: c_int = -4;
+
+thread_local! {
+    static LAST_ERROR: std::cell::RefCell<Option<CString>> = const { std::cell::RefCell::new(None) };
+}
+
+fn set_last_error(err: impl Into<anyhow::Error>) {
+    let err = err.into();
+    let msg = CString::new(format!("{err:#}").replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg))
+}
+
+/// Runs `f`, a panic is stored as the last error and `on_panic` is returned instead of unwinding
+/// into the C caller, which would abort the process.
+fn catch_panic<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(payload) => {
+            let msg = match payload.downcast_ref::<&str>() {
+                Some(msg) => msg.to_string(),
+                None => payload
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .unwrap_or_default(),
+            };
+            set_last_error(anyhow::anyhow!("panic: {msg}"));
+            on_panic
+        }
+    }
+}
+
+/// A word event as returned by `stt_next_word`, `text` is only valid until the next call on the
+/// same handle.
+#[repr(C)]
+pub struct SttWord {
+    pub text: *const c_char,
+    pub start_time: f64,
+    pub stop_time: f64,
+    pub confidence: f32,
+}
+
+pub struct SttModel {
+    transcriber: Transcriber,
+    /// Owns the text of the word last returned by `stt_next_word`.
+    current: Option<CString>,
+}
+
+/// # Safety
+/// `s` must be null or a valid nul terminated string.
+unsafe fn str_arg<'a>(s: *const c_char) -> std::result::Result<&'a str, c_int> {
+    if s.is_null() {
+        return Err(STT_ERROR_NULL_POINTER);
+    }
+    let s = unsafe { CStr::from_ptr(s) };
+    s.to_str().map_err(|_| STT_ERROR_INVALID_UTF8)
+}
+
+fn to_code(res: Result<()>) -> c_int {
+    match res {
+        Ok(()) => STT_OK,
+        Err(err) => {
+            set_last_error(err);
+            STT_ERROR_INFERENCE
+        }
+    }
+}
+
+/// Loads the model from a Hugging Face repo, e.g. `kyutai/stt-1b-en_fr-candle`. Returns null on
+/// failure.
+///
+/// # Safety
+/// `hf_repo` must be a valid nul terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn stt_model_new(hf_repo: *const c_char, cpu: c_int) -> *mut SttModel {
+    catch_panic(std::ptr::null_mut(), || unsafe { model_new(hf_repo, cpu) })
+}
+
+/// # Safety
+/// See `stt_model_new`.
+unsafe fn model_new(hf_repo: *const c_char, cpu: c_int) -> *mut SttModel {
+    let hf_repo = match unsafe { str_arg(hf_repo) } {
+        Ok(hf_repo) => hf_repo,
+        Err(_) => {
+            set_last_error(anyhow::anyhow!("invalid hf_repo"));
+            return std::ptr::null_mut();
+        }
+    };
+    match Transcriber::load(hf_repo, cpu != 0) {
+        Ok(transcriber) => {
+            let model = SttModel {
+                transcriber,
+                current: None,
+            };
+            Box::into_raw(Box::new(model))
+        }
+        Err(err) => {
+            set_last_error(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// # Safety
+/// `model` must be null or a handle returned by `stt_model_new` that has not been freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn stt_model_free(model: *mut SttModel) {
+    if !model.is_null() {
+        drop(unsafe { Box::from_raw(model) })
+    }
+}
+
+/// Feeds `len` samples of mono audio at `stt_sample_rate`, the words decoded so far can then be
+/// retrieved with `stt_next_word`.
+///
+/// # Safety
+/// `model` must be a live handle and `pcm` must point to at least `len` floats.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn stt_push_pcm(model: *mut SttModel, pcm: *const f32, len: usize) -> c_int {
+    if model.is_null() || (pcm.is_null() && len > 0) {
+        return STT_ERROR_NULL_POINTER;
+    }
+    let model = unsafe { &mut *model };
+    let pcm = if len == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(pcm, len) }
+    };
+    catch_panic(STT_ERROR_PANIC, || to_code(model.transcriber.push_pcm(pcm)))
+}
+
+/// The sample rate of the model, the audio given to `stt_push_pcm` is expected at this rate.
+///
+/// # Safety
+/// `model` must be a live handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn stt_sample_rate(model: *const SttModel) -> c_int {
+    if model.is_null() {
+        return STT_ERROR_NULL_POINTER;
+    }
+    unsafe { &*model }.transcriber.sample_rate() as c_int
+}
+
+/// Flushes the end of the audio, to be called once all the audio has been pushed.
+///
+/// # Safety
+/// `model` must be a live handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn stt_flush(model: *mut SttModel) -> c_int {
+    if model.is_null() {
+        return STT_ERROR_NULL_POINTER;
+    }
+    let model = unsafe { &mut *model };
+    catch_panic(STT_ERROR_PANIC, || to_code(model.transcriber.flush()))
+}
+
+/// Discards the current audio stream so that a new one can be transcribed.
+///
+/// # Safety
+/// `model` must be a live handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn stt_reset(model: *mut SttModel) -> c_int {
+    if model.is_null() {
+        return STT_ERROR_NULL_POINTER;
+    }
+    let model = unsafe { &mut *model };
+    catch_panic(STT_ERROR_PANIC, || to_code(model.transcriber.reset()))
+}
+
+/// Pops the next completed word into `word`, returns `STT_NO_WORD` if there is none.
+///
+/// # Safety
+/// `model` must be a live handle and `word` must point to a writable `SttWord`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn stt_next_word(model: *mut SttModel, word: *mut SttWord) -> c_int {
+    if model.is_null() || word.is_null() {
+        return STT_ERROR_NULL_POINTER;
+    }
+    let model = unsafe { &mut *model };
+    let next = match catch_panic(Err(STT_ERROR_PANIC), || Ok(model.transcriber.next_word())) {
+        Err(code) => return code,
+        Ok(None) => return STT_NO_WORD,
+        Ok(Some(next)) => next,
+    };
+    let text = CString::new(next.text.replace('\0', "")).unwrap_or_default();
+    let text = model.current.insert(text);
+    let out = SttWord {
+        text: text.as_ptr(),
+        start_time: next.start_time,
+        stop_time: next.stop_time,
+        confidence: next.confidence,
+    };
+    unsafe { word.write(out) };
+    STT_OK
+}
+
+/// The message of the last error on this thread, null if there was none. The string is valid
+/// until the next failing call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn stt_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| match e.borrow().as_ref() {
+        Some(msg) => msg.as_ptr(),
+        None => std::ptr::null(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn panics_are_caught() {
+        let code = catch_panic(STT_ERROR_PANIC, || -> c_int { panic!("shape mismatch") });
+        assert_eq!(code, STT_ERROR_PANIC);
+        let err = unsafe { CStr::from_ptr(stt_last_error()) };
+        assert_eq!(err.to_str(), Ok("panic: shape mismatch"));
+        assert_eq!(catch_panic(STT_ERROR_PANIC, || STT_OK), STT_OK);
+    }
+
+    #[test]
+    fn null_handles() {
+        let mut word = SttWord {
+            text: std::ptr::null(),
+            start_time: 0.,
+            stop_time: 0.,
+            confidence: 0.,
+        };
+        unsafe {
+            assert_eq!(
+                stt_push_pcm(std::ptr::null_mut(), [0f32].as_ptr(), 1),
+                STT_ERROR_NULL_POINTER
+            );
+            assert_eq!(stt_sample_rate(std::ptr::null()), STT_ERROR_NULL_POINTER);
+            assert_eq!(stt_flush(std::ptr::null_mut()), STT_ERROR_NULL_POINTER);
+            assert_eq!(stt_reset(std::ptr::null_mut()), STT_ERROR_NULL_POINTER);
+            assert_eq!(
+                stt_next_word(std::ptr::null_mut(), &mut word),
+                STT_ERROR_NULL_POINTER
+            );
+            assert!(stt_model_new(std::ptr::null(), 1).is_null());
+            assert!(!stt_last_error().is_null());
+            stt_model_free(std::ptr::null_mut());
+        }
+    }
+
+    /// Transcribes a clip through the C API and compares the words with the ones returned by the
+    /// asr state directly. Downloads the model from the hub.
+    #[test]
+    #[ignore]
+    fn round_trip() -> anyhow::Result<()> {
+        let clip = std::env::var("STT_TEST_CLIP").context("STT_TEST_CLIP is not set")?;
+        let (pcm, sample_rate) = kaudio::pcm_decode(&clip)?;
+        let hf_repo = CString::new("kyutai/stt-1b-en_fr-candle")?;
+
+        let model = unsafe { stt_model_new(hf_repo.as_ptr(), 1) };
+        assert!(!model.is_null());
+        let model_rate = unsafe { stt_sample_rate(model) };
+        assert!(model_rate > 0);
+        let pcm = kaudio::resample(&pcm, sample_rate as usize, model_rate as usize)?;
+        let mut words = vec![];
+        let mut word = SttWord {
+            text: std::ptr::null(),
+            start_time: 0.,
+            stop_time: 0.,
+            confidence: 0.,
+        };
+        unsafe {
+            for chunk in pcm.chunks(1000) {
+                assert_eq!(stt_push_pcm(model, chunk.as_ptr(), chunk.len()), STT_OK);
+            }
+            assert_eq!(stt_flush(model), STT_OK);
+            while stt_next_word(model, &mut word) == STT_OK {
+                assert!(word.start_time <= word.stop_time);
+                words.push(CStr::from_ptr(word.text).to_str()?.to_string());
+            }
+        }
+
+        // The same audio pushed in a single call goes through the same chunks.
+        let expected = {
+            let transcriber = unsafe { &mut (*model).transcriber };
+            transcriber.reset()?;
+            transcriber.push_pcm(&pcm)?;
+            transcriber.flush()?;
+            std::iter::from_fn(|| transcriber.next_word())
+                .map(|w| w.text)
+                .collect::<Vec<_>>()
+        };
+        unsafe { stt_model_free(model) };
+        assert!(!words.is_empty());
+        assert_eq!(words, expected);
+        Ok(())
+    }
+}
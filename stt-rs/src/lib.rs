@@ -0,0 +1,42 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! The speech-to-text engine shared by the command line tool and the C API in `ffi`, see
+//! `include/kyutai_stt.h` for the C declarations.
+use candle::Device;
+
+pub mod asr;
+pub mod client;
+pub mod config;
+pub mod decode;
+pub mod error;
+pub mod ffi;
+pub mod resample;
+pub mod stream;
+
+pub use error::{Result, SttError};
+
+/// Picks the first cuda or metal device available, falling back to the cpu. When an accelerator
+/// is detected but cannot be initialized, e.g. because of a driver mismatch, a warning is printed
+/// and the cpu is used instead unless `strict` is set.
+pub fn device(cpu: bool, strict: bool) -> Result<Device> {
+    let accelerator = if cpu {
+        None
+    } else if candle::utils::cuda_is_available() {
+        Some(("cuda", Device::new_cuda(0)))
+    } else if candle::utils::metal_is_available() {
+        Some(("metal", Device::new_metal(0)))
+    } else {
+        None
+    };
+    match accelerator {
+        None => Ok(Device::Cpu),
+        Some((_, Ok(device))) => Ok(device),
+        Some((device, Err(source))) if strict => Err(SttError::Device { device, source }),
+        Some((name, Err(err))) => {
+            eprintln!("Warning: cannot initialize {name} ({err}), falling back to the cpu");
+            Ok(Device::Cpu)
+        }
+    }
+}
@@ -2,14 +2,332 @@
 // This source code is licensed under the license found in the
 // LICENSE file in the root directory of this source tree.
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use candle::{Device, Tensor};
 use clap::Parser;
 
+mod archive;
+mod audio;
+mod bench;
+mod calibration;
+mod output;
+mod playlist;
+mod proxy_protocol;
+mod recording;
+mod remote;
+mod server;
+mod trace;
+mod upload;
+
+use kyutai_stt_rs::asr;
+use kyutai_stt_rs::config::Config;
+use kyutai_stt_rs::device;
+use kyutai_stt_rs::resample;
+
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// The audio input file, in wav/mp3/ogg/... format.
-    in_file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The audio input files, in wav, flac, mp3, ogg/vorbis, mp4 (aac or alac), mkv/webm or caf
+    /// format. The format is detected from the content of the files.
+    #[arg(required_unless_present_any = ["list_horizons", "manifest"])]
+    in_files: Vec<String>,
+
+    /// Read the input files from this manifest rather than from the command line, either a json
+    /// array of `{"path", "out_file", "start", "end"}` objects or a tsv file with these columns
+    /// in this order. Only `path` is required, `start` and `end` override `--start` and `--end`,
+    /// the transcript of an entry with an `out_file` is written there rather than with the other
+    /// outputs. The entries that fail are skipped, a summary is printed at the end and the exit
+    /// status tells whether some entries failed, unless `--ignore-errors` is set.
+    #[arg(long, conflicts_with = "in_files")]
+    manifest: Option<std::path::PathBuf>,
+
+    /// Only transcribe the audio after this time in seconds, the timestamps are relative to it.
+    #[arg(long)]
+    start: Option<f64>,
+
+    /// Only transcribe the audio before this time in seconds.
+    #[arg(long)]
+    end: Option<f64>,
+
+    /// Format of the transcripts, the text format is printed as the words are decoded.
+    #[arg(long, value_enum, default_value_t = output::Format::Text)]
+    format: output::Format,
+
+    /// Write the transcript of each input file to this directory rather than on stdout, the
+    /// files are only written once all the inputs have been transcribed.
+    #[arg(long)]
+    output_dir: Option<std::path::PathBuf>,
+
+    /// Write the transcript of each input file in a zip archive rather than on stdout.
+    #[arg(long, conflicts_with = "output_dir")]
+    zip_output: Option<std::path::PathBuf>,
+
+    /// Write the transcript of each input file in a gzipped tar archive rather than on stdout.
+    #[arg(long, conflicts_with_all = ["output_dir", "zip_output"])]
+    tar_output: Option<std::path::PathBuf>,
+
+    /// Append the SHA-256 of each written file to a `.checksums` file in the same directory,
+    /// requires `--output-dir`, `--zip-output` or `--tar-output`.
+    #[arg(long)]
+    checksum_output: bool,
+
+    /// Also write the json output to this file, the `--format` output is produced as usual.
+    #[arg(long)]
+    json_sidecar: Option<std::path::PathBuf>,
+
+    #[command(flatten)]
+    model: ModelArgs,
+
+    /// Display word level timestamps.
+    #[arg(long)]
+    timestamps: bool,
+
+    /// Display the level of voice activity detection (VAD).
+    #[arg(long)]
+    vad: bool,
+
+    /// The VAD head used to detect the end of turns, see `--list-horizons` for the horizon of
+    /// each head. A longer horizon needs a longer pause to end a turn.
+    #[arg(long, default_value_t = 2)]
+    vad_horizon: usize,
+
+    /// Drop the words decoded over long stretches without speech, such as music or noise, which
+    /// the model may transcribe as made up words. A stretch starts once the `--vad-horizon` head
+    /// has reported no voice activity with a probability over `--nonspeech-threshold` for
+    /// `--nonspeech-window` seconds, the audio is still processed so the words resume with
+    /// accurate timestamps once speech is detected again.
+    #[arg(long)]
+    skip_nonspeech: bool,
+
+    /// Seconds of no voice activity after which the words are dropped with `--skip-nonspeech`.
+    #[arg(long, default_value_t = 2., requires = "skip_nonspeech")]
+    nonspeech_window: f64,
+
+    /// Probability of no voice activity over which a step counts as non-speech with
+    /// `--skip-nonspeech`.
+    #[arg(long, default_value_t = 0.9, requires = "skip_nonspeech")]
+    nonspeech_threshold: f32,
+
+    /// Stream the input files to the WebSocket endpoint of a server started with `serve`, e.g.
+    /// `ws://localhost:8080/transcribe/ws`, rather than running the model locally. The files are
+    /// decoded locally, resampled to the sample rate announced by the server, and the words are
+    /// printed as they are received. A dropped connection is reopened with an exponential backoff
+    /// and the stream resumes after the last word received. Servers started with `--tls-cert` are
+    /// reached with `wss://`, their certificate has to chain to a public root authority.
+    #[arg(long, conflicts_with = "manifest")]
+    remote: Option<String>,
+
+    /// Bearer token sent with `--remote`, for servers started with `--rest-api-key`.
+    #[arg(
+        long,
+        requires = "remote",
+        env = "REST_API_KEY",
+        hide_env_values = true
+    )]
+    remote_api_key: Option<String>,
+
+    /// Number of reconnections attempted in a row with `--remote` before giving up.
+    #[arg(long, default_value_t = 5, requires = "remote")]
+    remote_retries: usize,
+
+    /// Encoding of the samples sent with `--remote`, `f32` or `i16`, i16 halves the bandwidth.
+    #[arg(long, default_value = "f32", requires = "remote")]
+    remote_sample_format: kyutai_stt_rs::stream::SampleFormat,
+
+    /// Print the VAD heads of the model and their horizons, then exit.
+    #[arg(long)]
+    list_horizons: bool,
+
+    /// Only output the speech segments detected by the VAD heads, one per turn, rather than the
+    /// transcript. Supports the text and json formats.
+    #[arg(long)]
+    vad_only: bool,
+
+    /// Split the transcript in turns at the end of turn events of the VAD heads, each turn is
+    /// printed on its own line as `[Turn N] text`. This is a heuristic, not speaker diarization:
+    /// a pause of a single speaker also starts a new turn and overlapping speech is not
+    /// separated. Supports the text and json formats.
+    #[arg(long)]
+    turns: bool,
+
+    /// With `--turns`, label the turns `Speaker A` and `Speaker B` in alternation, which is a
+    /// reasonable first guess for two-party conversations.
+    #[arg(long, requires = "turns")]
+    alternate_speakers: bool,
+
+    /// With `--turns` or `--vad-only`, flag the turns whose mean word confidence is under this
+    /// threshold. The mean and minimum confidence of each turn are part of the json output in
+    /// any case.
+    #[arg(long)]
+    min_segment_confidence: Option<f32>,
+
+    /// Print the usage statistics of the mimi codebooks once all the audio has been processed.
+    #[arg(long)]
+    mimi_stats: bool,
+
+    /// Save the mimi codes of the audio, with shape `(n_q, T)`, as a safetensors file. When
+    /// several files are transcribed this is a directory with one file per input.
+    #[arg(long)]
+    export_mimi_codes: Option<std::path::PathBuf>,
+
+    /// Save the logits of the text head as a npy file of f32 with shape `(T, text_vocab)`, a
+    /// softmax over the last dimension gives the distribution of the text token at each step.
+    /// Row `i` is the output of the model after `i + 1` frames of 80ms, it covers the audio up to
+    /// `(i + 1) * 0.08 - audio_delay` seconds, counted from the start of the silence prefix. The
+    /// rows span the whole clip, including the silence prefix and the flush suffix. When several
+    /// files are transcribed this is a directory with one file per input.
+    #[arg(long)]
+    dump_logits: Option<std::path::PathBuf>,
+
+    /// Display the words that are still being decoded, these partial words are replaced once
+    /// the final word is available. Only used when the output is printed as it is produced.
+    #[arg(long)]
+    partials: bool,
+
+    /// Audio file fed to the model before each input without producing any output, so that the
+    /// transcription starts with this context already in the model state.
+    #[arg(long)]
+    context_prepend_audio: Option<String>,
+
+    /// Resampler used for the files that are not at the sample rate of the model, `fast` for
+    /// real-time use and `high` for archival material.
+    #[arg(long, value_enum, default_value_t = resample::ResampleQuality::Balanced)]
+    resample_quality: resample::ResampleQuality,
+
+    /// Resample the inputs whose sample rate is within 0.01% of the model sample rate rather than
+    /// using them as is.
+    #[arg(long)]
+    always_resample: bool,
+
+    /// Drop the delay of the `balanced` resampler so that the timestamps of the resampled inputs
+    /// are not shifted, by a few tens of milliseconds, and the output has exactly the duration of
+    /// the input. The `fast` and `high` resamplers are always aligned.
+    #[arg(long)]
+    compensate_resample_delay: bool,
+
+    /// Scale each input, once resampled, so that its peak or rms reaches `--normalize-level`. A
+    /// warning is printed for the inputs that have been clipped.
+    #[arg(long, value_enum)]
+    normalize_gain: Option<audio::GainTarget>,
+
+    /// Target of `--normalize-gain` in dBFS, -1 for the peak and -20 for the rms by default.
+    #[arg(long, requires = "normalize_gain", allow_negative_numbers = true)]
+    normalize_level: Option<f32>,
+
+    /// Unit used for the timestamps, milliseconds are rounded to the nearest integer.
+    #[arg(long, value_enum, default_value_t = TimeUnit::Seconds)]
+    time_unit: TimeUnit,
+
+    /// Number of fractional digits of the timestamps in seconds printed in the text output, e.g.
+    /// 3 for milliseconds or 0 for whole seconds. The json and srt outputs are not affected.
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(0..=9))]
+    time_precision: u8,
+
+    /// With `--timestamps`, never print a word as starting before the previous one stops, its
+    /// start is moved to that stop. The srt output is always smoothed.
+    #[arg(long, requires = "timestamps")]
+    smooth_timestamps: bool,
+
+    /// Print the token ids of each word on stderr, and include them in the json output as
+    /// `token_ids`.
+    #[arg(long)]
+    debug_tokens: bool,
+
+    /// Fail when the tokens of a word cannot be decoded rather than printing a warning and
+    /// skipping the word, this usually means that the tokenizer does not match the model.
+    #[arg(long)]
+    strict: bool,
+
+    /// Do not show the progress bar of the transcription, it is also hidden when stderr is not a
+    /// terminal or when the text is printed as it is produced.
+    #[arg(long)]
+    quiet: bool,
+
+    /// Print the processing time and real-time factor once all the inputs have been
+    /// transcribed, this implies `--warmup`.
+    #[arg(long)]
+    stats: bool,
+
+    /// Record the time taken by each 80ms chunk and print a histogram of these timings once all
+    /// the inputs have been transcribed. The device is synchronized after each chunk so that
+    /// the timings are accurate, which slows down the transcription a bit.
+    #[arg(long)]
+    trace_chunks: bool,
+
+    /// Write the timing of each chunk to this csv file, this implies `--trace-chunks`.
+    #[arg(long)]
+    trace_out: Option<std::path::PathBuf>,
+
+    /// Run a few steps on silence before transcribing so that the kernel compilation and
+    /// allocations do not count towards the timings of the first file.
+    #[arg(long)]
+    warmup: bool,
+
+    /// Skip the files that cannot be decoded or transcribed rather than aborting, a summary of
+    /// the failures is printed at the end. When a batch fails all its files are skipped.
+    #[arg(long)]
+    ignore_errors: bool,
+
+    /// Retry the files that failed up to N times, waiting 1s before the first retry and doubling
+    /// the wait for each following one. The files are retried one at a time rather than in
+    /// batches.
+    #[arg(long, default_value_t = 0)]
+    retry_failed: u32,
+
+    /// Write a json manifest listing, for each input file, the output path, the audio duration,
+    /// the word count, the average confidence, the model and the processing time. Files that
+    /// could not be transcribed are listed with `"success": false`.
+    #[arg(long)]
+    output_manifest: Option<std::path::PathBuf>,
+
+    /// Skip the input files listed as successful in the `--output-manifest` file of a previous
+    /// run, their entries are kept in the new manifest. The archive outputs only contain the
+    /// files transcribed by the current run.
+    #[arg(long, requires = "output_manifest")]
+    skip_existing: bool,
+
+    /// Split the input files between N processes, each with its own copy of the model. The
+    /// transcripts printed by the workers are shown one worker after the other.
+    #[arg(long, default_value_t = 1)]
+    workers: usize,
+
+    /// Index of the current worker, set by the parent process when using `--workers`.
+    #[arg(long, hide = true)]
+    worker_index: Option<usize>,
+
+    /// Print the expected processing time of the inputs, based on the real-time factor measured
+    /// by the calibrate subcommand, without running the model.
+    #[arg(long)]
+    dry_run_estimate: bool,
+
+    /// Load the model and read the header of each input, then report the problems and exit
+    /// without running the model, e.g. to catch missing or unreadable files before a long batch.
+    /// The exit status is non-zero if anything failed.
+    #[arg(long, conflicts_with = "dry_run_estimate")]
+    check: bool,
+
+    /// Color the words according to their confidence: red below 50%, dimmed below 80%. In auto
+    /// mode colors are only used when stdout is a terminal.
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Flush stdout after each word printed while transcribing. By default this is only done
+    /// when stdout is a terminal, otherwise the output is buffered and flushed every half second,
+    /// which is faster when redirecting long transcripts to a file.
+    #[arg(long, visible_alias = "flush-each")]
+    line_buffered: bool,
+}
+
+/// The options used to load the model, shared by the transcription and the benchmark.
+#[derive(Debug, clap::Args)]
+struct ModelArgs {
+    /// Number of files transcribed in parallel, shorter files are padded and masked so that
+    /// clips of different lengths can share a batch.
+    #[arg(long, default_value_t = 1)]
+    batch_size: usize,
 
     /// The repo where to get the model from.
     #[arg(long, default_value = "kyutai/stt-1b-en_fr-candle")]
@@ -19,242 +337,1564 @@ struct Args {
     #[arg(long, default_value = "model.safetensors")]
     model_path: String,
 
+    /// Local SentencePiece model to use instead of the tokenizer referenced by the model config.
+    #[arg(long)]
+    tokenizer: Option<std::path::PathBuf>,
+
+    /// Load the model weights as a quantized gguf file, `--model-path` must then point at a
+    /// `.gguf` file.
+    #[arg(long)]
+    quantized: bool,
+
     /// Run the model on cpu.
     #[arg(long)]
     cpu: bool,
 
-    /// Display word level timestamps.
+    /// Fail if the cuda or metal device cannot be initialized rather than falling back to the
+    /// cpu.
     #[arg(long)]
-    timestamps: bool,
+    strict_device: bool,
 
-    /// Display the level of voice activity detection (VAD).
+    /// Seconds of silence inserted before the audio, overriding `audio_silence_prefix_seconds`
+    /// from the model config. Converted to samples at the mimi sample rate.
     #[arg(long)]
-    vad: bool,
+    silence_prefix: Option<f64>,
+
+    /// Delay in seconds between the audio and the text stream, overriding
+    /// `audio_delay_seconds` from the model config. The delay is rounded down to a whole number
+    /// of text tokens, a multiple of 80ms at the 12.5Hz frame rate of mimi.
+    #[arg(long)]
+    audio_delay: Option<f64>,
+
+    /// Seconds of silence appended to the audio to flush the last words out of the model, the
+    /// audio delay plus one second by default. Shorter tails lower the latency of the last words
+    /// but may truncate them, or drop them altogether when set to zero.
+    #[arg(long)]
+    flush_seconds: Option<f64>,
+
+    /// Only feed the first N mimi codebooks to the language model, N must not exceed `n_q` from
+    /// the model config.
+    #[arg(long)]
+    mimi_codebook_count: Option<usize>,
 }
 
-fn device(cpu: bool) -> Result<Device> {
-    if cpu {
-        Ok(Device::Cpu)
-    } else if candle::utils::cuda_is_available() {
-        Ok(Device::new_cuda(0)?)
-    } else if candle::utils::metal_is_available() {
-        Ok(Device::new_metal(0)?)
-    } else {
-        Ok(Device::Cpu)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum TimeUnit {
+    Seconds,
+    Ms,
+}
+
+impl TimeUnit {
+    /// Formats a `[start-stop]` span, the stop time is left blank when not known yet. Both ends
+    /// go through the same rounding so that adjacent spans share their boundary. The seconds
+    /// have `precision` fractional digits.
+    fn span(self, precision: u8, start_time: f64, stop_time: Option<f64>) -> String {
+        let precision = precision as usize;
+        // Two integer digits and the decimal point if any.
+        let width = precision + if precision > 0 { 3 } else { 2 };
+        match (self, stop_time) {
+            (Self::Seconds, Some(stop_time)) => {
+                format!("[{start_time:width$.precision$}-{stop_time:width$.precision$}]")
+            }
+            (Self::Seconds, None) => {
+                format!("[{start_time:width$.precision$}-{:width$}]", "")
+            }
+            (Self::Ms, Some(stop_time)) => {
+                format!(
+                    "[{:6}-{:6}]",
+                    Self::to_ms(start_time),
+                    Self::to_ms(stop_time)
+                )
+            }
+            (Self::Ms, None) => format!("[{:6}-      ]", Self::to_ms(start_time)),
+        }
+    }
+
+    fn to_ms(time: f64) -> u64 {
+        (time * 1000.).round() as u64
     }
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct SttConfig {
-    audio_silence_prefix_seconds: f64,
-    audio_delay_seconds: f64,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        use std::io::IsTerminal;
+
+        match self {
+            Self::Auto => std::io::stdout().is_terminal(),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Decode the mimi codes saved with `--export-mimi-codes` back to audio.
+    MimiResynth(MimiResynthArgs),
+    /// Measure the latency of each chunk and the real-time factor on a fixed audio buffer.
+    Bench(bench::BenchArgs),
+    /// Measure the real-time factor of the current device for `--dry-run-estimate`.
+    Calibrate(calibration::CalibrateArgs),
+    /// Run an HTTP server streaming the transcripts of the uploaded audio files.
+    Serve(server::ServeArgs),
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct Config {
-    mimi_name: String,
-    tokenizer_name: String,
+#[derive(Debug, clap::Args)]
+struct MimiResynthArgs {
+    /// The safetensors file holding the codes.
+    #[arg(long)]
+    codes: std::path::PathBuf,
+
+    /// The wav file where to write the decoded audio.
+    #[arg(long)]
+    output: std::path::PathBuf,
+
+    /// The repo where to get the mimi model from.
+    #[arg(long, default_value = "kyutai/stt-1b-en_fr-candle")]
+    hf_repo: String,
+
+    /// Run the model on cpu.
+    #[arg(long)]
+    cpu: bool,
+}
+
+/// Usage counts of the codes of each mimi codebook, used to spot codebook collapse.
+struct CodebookStats {
     card: usize,
-    text_card: usize,
-    dim: usize,
-    n_q: usize,
-    context: usize,
-    max_period: f64,
-    num_heads: usize,
-    num_layers: usize,
-    causal: bool,
-    stt_config: SttConfig,
+    counts: Vec<std::collections::HashMap<u32, usize>>,
+}
+
+impl CodebookStats {
+    fn new(n_q: usize, card: usize) -> Self {
+        Self {
+            card,
+            counts: vec![Default::default(); n_q],
+        }
+    }
+
+    /// `audio_codes` holds the codes of each batch element for each codebook, the padding tokens
+    /// used on the first step and the tokens of inactive batch elements are skipped.
+    fn update(&mut self, audio_codes: &[Vec<u32>], active: &[bool]) {
+        for (counts, codes) in self.counts.iter_mut().zip(audio_codes.iter()) {
+            for (&code, &active) in codes.iter().zip(active.iter()) {
+                if active && (code as usize) < self.card {
+                    *counts.entry(code).or_default() += 1
+                }
+            }
+        }
+    }
+
+    fn print(&self) {
+        let max_entropy = (self.card as f64).log2();
+        println!("Mimi codebook usage (max entropy {max_entropy:.2} bits)");
+        for (codebook_idx, counts) in self.counts.iter().enumerate() {
+            let total = counts.values().sum::<usize>();
+            if total == 0 {
+                println!("codebook {codebook_idx:2}: no tokens");
+                continue;
+            }
+            let entropy = counts
+                .values()
+                .map(|&c| {
+                    let p = c as f64 / total as f64;
+                    -p * p.log2()
+                })
+                .sum::<f64>();
+            let mut counts = counts.iter().collect::<Vec<_>>();
+            counts.sort_by(|(t1, c1), (t2, c2)| c2.cmp(c1).then(t1.cmp(t2)));
+            let top = counts
+                .iter()
+                .take(10)
+                .map(|(token, c)| format!("{token}:{:.1}%", 100. * **c as f64 / total as f64))
+                .collect::<Vec<_>>();
+            println!(
+                "codebook {codebook_idx:2}: entropy {entropy:5.2} bits, {:4} distinct codes, top {}",
+                counts.len(),
+                top.join(" ")
+            );
+        }
+    }
+}
+
+/// The result of transcribing a single clip.
+struct ClipOutput {
+    /// The formatted transcript, `None` when it has already been printed.
+    text: Option<String>,
+    transcript: output::Transcript,
+    /// The mimi codes of the clip with shape `(n_q, T)`, only recorded with `--export-mimi-codes`.
+    mimi_codes: Option<Tensor>,
+    /// The text logits of the clip with shape `(T, text_vocab)`, only recorded with
+    /// `--dump-logits`.
+    text_logits: Option<Tensor>,
 }
 
-impl Config {
-    fn model_config(&self, vad: bool) -> moshi::lm::Config {
-        let lm_cfg = moshi::transformer::Config {
-            d_model: self.dim,
-            num_heads: self.num_heads,
-            num_layers: self.num_layers,
-            dim_feedforward: self.dim * 4,
-            causal: self.causal,
-            norm_first: true,
-            bias_ff: false,
-            bias_attn: false,
-            layer_scale: None,
-            context: self.context,
-            max_period: self.max_period as usize,
-            use_conv_block: false,
-            use_conv_bias: true,
-            cross_attention: None,
-            gating: Some(candle_nn::Activation::Silu),
-            norm: moshi::NormType::RmsNorm,
-            positional_embedding: moshi::transformer::PositionalEmbedding::Rope,
-            conv_layout: false,
-            conv_kernel_size: 3,
-            kv_repeat: 1,
-            max_seq_len: 4096 * 4,
-            shared_cross_attn: false,
+/// How often the text printed while transcribing is flushed to stdout, unless it is flushed
+/// after each word.
+const LIVE_FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Formats the messages for a single batch element, either directly on stdout or in a buffer
+/// that is printed once the whole batch has been processed.
+struct Printer {
+    timestamps: bool,
+    time_unit: TimeUnit,
+    time_precision: u8,
+    color: bool,
+    last_word: Option<(String, f64)>,
+    /// Move the start of the words after the stop of the previous one.
+    smooth: bool,
+    last_stop: f64,
+    printed_eot: bool,
+    buffer: Option<String>,
+    stdout: std::io::BufWriter<std::io::Stdout>,
+    /// Flush stdout after each write rather than every `LIVE_FLUSH_INTERVAL`.
+    flush_each: bool,
+    last_flush: std::time::Instant,
+    // Number of characters of the partial word currently displayed.
+    partial_len: usize,
+}
+
+impl Printer {
+    fn new(
+        timestamps: bool,
+        time_unit: TimeUnit,
+        time_precision: u8,
+        color: bool,
+        live: bool,
+        flush_each: bool,
+        smooth: bool,
+    ) -> Self {
+        Self {
+            timestamps,
+            time_unit,
+            time_precision,
+            color,
+            last_word: None,
+            smooth,
+            last_stop: 0.,
+            printed_eot: false,
+            buffer: if live { None } else { Some(String::new()) },
+            stdout: std::io::BufWriter::new(std::io::stdout()),
+            flush_each,
+            last_flush: std::time::Instant::now(),
+            partial_len: 0,
+        }
+    }
+
+    fn write(&mut self, s: &str) -> Result<()> {
+        use std::io::Write;
+
+        if self.partial_len > 0 {
+            // Move back over the partial word and clear the end of the line.
+            write!(self.stdout, "\x1b[{}D\x1b[K", self.partial_len)?;
+            self.partial_len = 0;
+        }
+        match self.buffer.as_mut() {
+            Some(buffer) => buffer.push_str(s),
+            None => {
+                self.stdout.write_all(s.as_bytes())?;
+                if self.flush_each || self.last_flush.elapsed() >= LIVE_FLUSH_INTERVAL {
+                    self.flush()?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        use std::io::Write;
+
+        self.stdout.flush()?;
+        self.last_flush = std::time::Instant::now();
+        Ok(())
+    }
+
+    /// Formats the span of a word, with `smooth` the word starts at the earliest when the
+    /// previously printed one stops.
+    fn span(&mut self, start_time: f64, stop_time: Option<f64>) -> String {
+        if !self.smooth {
+            return self
+                .time_unit
+                .span(self.time_precision, start_time, stop_time);
+        }
+        let start_time = start_time.max(self.last_stop);
+        let stop_time = stop_time.map(|stop_time| stop_time.max(start_time));
+        self.last_stop = stop_time.unwrap_or(start_time);
+        self.time_unit
+            .span(self.time_precision, start_time, stop_time)
+    }
+
+    fn end_of_turn(&mut self, pr: f32) -> Result<()> {
+        if self.printed_eot {
+            return Ok(());
+        }
+        self.printed_eot = true;
+        if !self.timestamps {
+            self.write(&format!(" <endofturn pr={pr}>"))
+        } else {
+            self.write(&format!("<endofturn pr={pr}>\n"))
+        }
+    }
+
+    fn end_word(&mut self, stop_time: f64) -> Result<()> {
+        self.printed_eot = false;
+        #[allow(clippy::collapsible_if)]
+        if self.timestamps {
+            if let Some((word, start_time)) = self.last_word.take() {
+                let span = self.span(start_time, Some(stop_time));
+                self.write(&format!("{span} {word}\n"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn word(&mut self, word: String, start_time: f64, confidence: f32) -> Result<()> {
+        self.printed_eot = false;
+        let word = if !self.color {
+            word
+        } else if confidence < 0.5 {
+            format!("\x1b[31m{word}\x1b[0m")
+        } else if confidence < 0.8 {
+            format!("\x1b[2m{word}\x1b[0m")
+        } else {
+            word
         };
-        let extra_heads = if vad {
-            Some(moshi::lm::ExtraHeadsConfig {
-                num_heads: 4,
-                dim: 6,
-            })
+        if !self.timestamps {
+            self.write(&format!(" {word}"))
+        } else {
+            if let Some((word, prev_start_time)) = self.last_word.take() {
+                let span = self.span(prev_start_time, Some(start_time));
+                self.write(&format!("{span} {word}\n"))?;
+            }
+            self.last_word = Some((word, start_time));
+            Ok(())
+        }
+    }
+
+    /// Displays a word that is still being decoded, it gets erased on the next write. This is
+    /// a no-op when the output is buffered.
+    fn partial(&mut self, text: &str, start_time: f64) -> Result<()> {
+        if self.buffer.is_some() {
+            return Ok(());
+        }
+        let partial = if !self.timestamps {
+            format!(" {text}")
         } else {
-            None
+            let start_time = if self.smooth {
+                start_time.max(self.last_stop)
+            } else {
+                start_time
+            };
+            let span = self.time_unit.span(self.time_precision, start_time, None);
+            format!("{span} {text}")
         };
-        moshi::lm::Config {
-            transformer: lm_cfg,
-            depformer: None,
-            audio_vocab_size: self.card + 1,
-            text_in_vocab_size: self.text_card + 1,
-            text_out_vocab_size: self.text_card,
-            audio_codebooks: self.n_q,
-            conditioners: Default::default(),
-            extra_heads,
+        self.write(&partial)?;
+        self.partial_len = partial.chars().count();
+        // The partial word is only useful if it is shown right away.
+        self.flush()
+    }
+
+    /// Flushes the last pending word and returns the buffered output if any.
+    fn finish(mut self) -> Result<Option<String>> {
+        if let Some((word, start_time)) = self.last_word.take() {
+            let span = self.span(start_time, None);
+            self.write(&format!("{span} {word}\n"))?;
         }
+        self.write("\n")?;
+        self.flush()?;
+        Ok(self.buffer)
     }
 }
 
+/// The settings of `--skip-nonspeech`.
+#[derive(Debug, Clone, Copy)]
+struct NonSpeech {
+    threshold: f32,
+    /// Number of consecutive non-speech steps after which the words are dropped.
+    window_steps: usize,
+}
+
 struct Model {
-    state: moshi::asr::State,
+    state: asr::State,
     text_tokenizer: sentencepiece::SentencePieceProcessor,
     timestamps: bool,
+    smooth_timestamps: bool,
+    time_unit: TimeUnit,
+    time_precision: u8,
+    color: bool,
+    /// Report the end of turns detected by the VAD heads.
     vad: bool,
-    config: Config,
+    nonspeech: Option<NonSpeech>,
+    silence_prefix_seconds: f64,
+    audio_delay_seconds: f64,
+    /// Duration of the silence appended to each clip.
+    flush_seconds: f64,
+    batch_size: usize,
+    mimi_stats: Option<CodebookStats>,
+    export_mimi_codes: bool,
+    dump_logits: bool,
+    n_q: usize,
+    card: usize,
+    /// The horizon in seconds of each VAD head, if known.
+    vad_horizons: Vec<Option<f64>>,
+    /// Index of the VAD head used for the end of turns.
+    vad_horizon: usize,
+    /// The sample rate expected by mimi.
+    sample_rate: usize,
+    /// Number of samples consumed by each model step.
+    frame_size: usize,
+    context_pcm: Option<Vec<f32>>,
+    word_decoder: kyutai_stt_rs::decode::WordDecoder,
+    /// Print the text output as it is produced.
+    live: bool,
+    /// Flush stdout after each word in live mode.
+    flush_each: bool,
+    /// Show a progress bar on stderr while transcribing.
+    progress: bool,
+    debug_tokens: bool,
+    /// When set, the time spent on each chunk is appended here.
+    chunk_timings: Option<Vec<trace::ChunkTiming>>,
     dev: Device,
 }
 
 impl Model {
-    fn load_from_hf(args: &Args, dev: &Device) -> Result<Self> {
+    /// Loads the model with all the output options disabled.
+    fn load(args: &ModelArgs, vad: bool, dev: &Device) -> Result<Self> {
+        let start_time = std::time::Instant::now();
         // Retrieve the model files from the Hugging Face Hub
         let api = hf_hub::api::sync::Api::new()?;
         let repo = api.model(args.hf_repo.to_string());
         let config_file = repo.get("config.json")?;
-        let config: Config = serde_json::from_str(&std::fs::read_to_string(&config_file)?)?;
-        let tokenizer_file = repo.get(&config.tokenizer_name)?;
+        let mut config = Config::load(&config_file)?;
+        if let Some(n_q) = args.mimi_codebook_count {
+            if n_q == 0 || n_q > config.n_q {
+                anyhow::bail!(
+                    "--mimi-codebook-count must be between 1 and {}, got {n_q}",
+                    config.n_q
+                );
+            }
+            config.n_q = n_q;
+        }
+        let tokenizer_file = match args.tokenizer.as_ref() {
+            Some(tokenizer_file) => tokenizer_file.clone(),
+            None => repo.get(&config.tokenizer_name)?,
+        };
         let model_file = repo.get(&args.model_path)?;
         let mimi_file = repo.get(&config.mimi_name)?;
-        let is_quantized = model_file.to_str().unwrap().ends_with(".gguf");
+        let download_time = start_time.elapsed();
+        let is_gguf = model_file.extension().is_some_and(|ext| ext == "gguf");
+        if is_gguf && !args.quantized {
+            anyhow::bail!(
+                "{} is a gguf file, pass --quantized to load quantized weights",
+                args.model_path
+            );
+        }
+        if !is_gguf && args.quantized {
+            anyhow::bail!("--quantized expects a gguf file, got {}", args.model_path);
+        }
 
-        let text_tokenizer = sentencepiece::SentencePieceProcessor::open(&tokenizer_file)?;
+        let weights_start_time = std::time::Instant::now();
+        let text_tokenizer = sentencepiece::SentencePieceProcessor::open(&tokenizer_file)
+            .with_context(|| {
+                format!(
+                    "{} is not a valid SentencePiece model",
+                    tokenizer_file.display()
+                )
+            })?;
 
-        let lm = if is_quantized {
-            let vb_lm = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(
-                &model_file,
-                dev,
-            )?;
-            moshi::lm::LmModel::new(
-                &config.model_config(args.vad),
-                moshi::nn::MaybeQuantizedVarBuilder::Quantized(vb_lm),
-            )?
+        if args.batch_size == 0 {
+            anyhow::bail!("--batch-size must be at least 1");
+        }
+        // The batched transformer supports masking individual batch elements, this is only
+        // required when several clips of different lengths are processed together.
+        let batch_size = (args.batch_size > 1).then_some(args.batch_size);
+        if args.quantized {
+            eprintln!("Loading quantized weights from {}", args.model_path);
         } else {
-            let dtype = dev.bf16_default_to_f32();
-            let vb_lm = unsafe {
-                candle_nn::VarBuilder::from_mmaped_safetensors(&[&model_file], dtype, dev)?
-            };
-            moshi::lm::LmModel::new(
-                &config.model_config(args.vad),
-                moshi::nn::MaybeQuantizedVarBuilder::Real(vb_lm),
-            )?
+            eprintln!("Loading real weights from {}", args.model_path);
+        }
+        let lm = config.load_lm(&model_file, args.quantized, batch_size, vad, dev);
+        let lm = if args.quantized {
+            lm.with_context(|| format!("cannot load the quantized weights on {dev:?}, use --cpu if this device does not support gguf models"))?
+        } else {
+            lm?
         };
+        let weights_time = weights_start_time.elapsed();
 
-        let audio_tokenizer = moshi::mimi::load(mimi_file.to_str().unwrap(), Some(32), dev)?;
-        let asr_delay_in_tokens = (config.stt_config.audio_delay_seconds * 12.5) as usize;
-        let state = moshi::asr::State::new(1, asr_delay_in_tokens, 0., audio_tokenizer, lm)?;
+        let silence_prefix_seconds = args
+            .silence_prefix
+            .unwrap_or(config.stt_config.audio_silence_prefix_seconds);
+        let audio_delay_seconds = args
+            .audio_delay
+            .unwrap_or(config.stt_config.audio_delay_seconds);
+        let flush_seconds = args.flush_seconds.unwrap_or(audio_delay_seconds + 1.0);
+        if silence_prefix_seconds < 0.0 || audio_delay_seconds < 0.0 || flush_seconds < 0.0 {
+            anyhow::bail!("silence prefix, audio delay and flush seconds must be non-negative");
+        }
+
+        // The asr state expects exactly one token per codebook consumed by the language model so
+        // the quantizer is truncated to the same number of codebooks.
+        let mimi_start_time = std::time::Instant::now();
+        let audio_tokenizer = moshi::mimi::load_b(
+            batch_size,
+            mimi_file.to_str().unwrap(),
+            Some(config.n_q),
+            dev,
+        )?;
+        let mimi_time = mimi_start_time.elapsed();
+        let mimi_config = audio_tokenizer.config();
+        let (sample_rate, frame_rate) = (mimi_config.sample_rate, mimi_config.frame_rate);
+        let frame_size = sample_rate / frame_rate;
+        if sample_rate.fract() != 0. || frame_size.fract() != 0. || frame_size < 1. {
+            anyhow::bail!(
+                "mimi frame rate {frame_rate}Hz does not split its sample rate {sample_rate}Hz \
+                 in whole frames"
+            );
+        }
+        let (sample_rate, frame_size) = (sample_rate as usize, frame_size as usize);
+        let asr_delay_in_tokens = (audio_delay_seconds * frame_rate) as usize;
+        let state = asr::State::new(
+            args.batch_size,
+            asr_delay_in_tokens,
+            0.,
+            audio_tokenizer,
+            lm,
+        )?;
+        eprintln!(
+            "Model loaded in {:.2}s: download {:.2}s, tokenizer and weights {:.2}s, mimi {:.2}s",
+            start_time.elapsed().as_secs_f64(),
+            download_time.as_secs_f64(),
+            weights_time.as_secs_f64(),
+            mimi_time.as_secs_f64(),
+        );
+        if let Some(rss) = proc_status_mb("VmRSS") {
+            eprintln!("Resident memory after load: {rss:.1}MB")
+        }
         Ok(Model {
             state,
-            config,
             text_tokenizer,
-            timestamps: args.timestamps,
-            vad: args.vad,
+            silence_prefix_seconds,
+            audio_delay_seconds,
+            flush_seconds,
+            timestamps: false,
+            smooth_timestamps: false,
+            time_unit: TimeUnit::Seconds,
+            time_precision: 2,
+            color: false,
+            vad,
+            nonspeech: None,
+            batch_size: args.batch_size,
+            mimi_stats: None,
+            export_mimi_codes: false,
+            dump_logits: false,
+            n_q: config.n_q,
+            card: config.card,
+            vad_horizons: config.vad_horizons(),
+            vad_horizon: 2,
+            sample_rate,
+            frame_size,
+            context_pcm: None,
+            word_decoder: Default::default(),
+            live: false,
+            flush_each: false,
+            progress: false,
+            debug_tokens: false,
+            chunk_timings: None,
             dev: dev.clone(),
         })
     }
 
-    fn run(&mut self, mut pcm: Vec<f32>) -> Result<()> {
-        use std::io::Write;
+    fn load_from_hf(args: &Args, dev: &Device) -> Result<Self> {
+        use std::io::IsTerminal;
 
-        // Add the silence prefix to the audio.
-        if self.config.stt_config.audio_silence_prefix_seconds > 0.0 {
-            let silence_len =
-                (self.config.stt_config.audio_silence_prefix_seconds * 24000.0) as usize;
-            pcm.splice(0..0, vec![0.0; silence_len]);
-        }
-        // Add some silence at the end to ensure all the audio is processed.
-        let suffix = (self.config.stt_config.audio_delay_seconds * 24000.0) as usize;
-        pcm.resize(pcm.len() + suffix + 24000, 0.0);
-
-        let mut last_word = None;
-        let mut printed_eot = false;
-        for pcm in pcm.chunks(1920) {
-            let pcm = Tensor::new(pcm, &self.dev)?.reshape((1, 1, ()))?;
-            let asr_msgs = self.state.step_pcm(pcm, None, &().into(), |_, _, _| ())?;
+        let vad = args.vad || args.vad_only || args.turns;
+        let mut model = Self::load(&args.model, vad || args.skip_nonspeech, dev)?;
+        model.vad = vad;
+        model.state.set_partials(args.partials);
+        model.timestamps = args.timestamps;
+        model.smooth_timestamps = args.smooth_timestamps;
+        model.time_unit = args.time_unit;
+        model.time_precision = args.time_precision;
+        model.color = args.color.enabled();
+        model.mimi_stats = args
+            .mimi_stats
+            .then(|| CodebookStats::new(model.n_q, model.card));
+        model.export_mimi_codes = args.export_mimi_codes.is_some();
+        model.dump_logits = args.dump_logits.is_some();
+        model.state.set_text_logits(model.dump_logits);
+        model.live = args.model.batch_size == 1
+            && !args.vad_only
+            && !args.turns
+            && args.format == output::Format::Text
+            && args.output_dir.is_none()
+            && args.zip_output.is_none()
+            && args.tar_output.is_none()
+            && args.manifest.is_none();
+        model.flush_each = args.line_buffered || std::io::stdout().is_terminal();
+        model.debug_tokens = args.debug_tokens;
+        model.word_decoder.strict = args.strict;
+        // The progress bar would be mixed with the text printed as it is produced. indicatif
+        // hides it when stderr is not a terminal.
+        model.progress = !args.quiet && !model.live;
+        if args.trace_chunks || args.trace_out.is_some() {
+            model.chunk_timings = Some(vec![]);
+        }
+        if args.vad_horizon >= model.vad_horizons.len() {
+            anyhow::bail!(
+                "--vad-horizon must be lower than the number of VAD heads, {}",
+                model.vad_horizons.len()
+            )
+        }
+        model.vad_horizon = args.vad_horizon;
+        if args.skip_nonspeech {
+            let frame_rate = model.sample_rate as f64 / model.frame_size as f64;
+            model.nonspeech = Some(NonSpeech {
+                threshold: args.nonspeech_threshold,
+                window_steps: (args.nonspeech_window * frame_rate).ceil() as usize,
+            });
+        }
+        Ok(model)
+    }
+
+    /// Runs the context audio through the model for all the batch elements, discarding the
+    /// resulting messages. Returns the duration of the context in seconds, which is then
+    /// subtracted from the timestamps.
+    fn prime_with_context(&mut self) -> Result<f64> {
+        let context_pcm = match self.context_pcm.as_ref() {
+            None => return Ok(0.),
+            Some(context_pcm) => context_pcm,
+        };
+        let frame_size = self.frame_size;
+        let num_chunks = context_pcm.len().div_ceil(frame_size);
+        for chunk in context_pcm.chunks(frame_size) {
+            let mut batch_pcm = vec![0f32; self.batch_size * frame_size];
+            for batch_pcm in batch_pcm.chunks_mut(frame_size) {
+                batch_pcm[..chunk.len()].copy_from_slice(chunk)
+            }
+            let pcm = Tensor::new(batch_pcm, &self.dev)?.reshape((self.batch_size, 1, ()))?;
+            self.state.step_pcm(pcm, None, &().into(), |_, _, _| ())?;
+        }
+        Ok((num_chunks * frame_size) as f64 / self.sample_rate as f64)
+    }
+
+    /// Clears the streaming state between files, the weights and the tokenizer are kept.
+    fn reset(&mut self) -> Result<()> {
+        self.state.reset()?;
+        Ok(())
+    }
+
+    /// Steps the model on `WARMUP_STEPS` chunks of silence, the messages are discarded and the
+    /// state is reset afterwards so that the next run starts from scratch.
+    fn warmup(&mut self) -> Result<()> {
+        self.reset()?;
+        for _ in 0..WARMUP_STEPS {
+            let pcm = Tensor::zeros(
+                (self.batch_size, 1, self.frame_size),
+                candle::DType::F32,
+                &self.dev,
+            )?;
+            self.state.step_pcm(pcm, None, &().into(), |_, _, _| ())?;
+        }
+        self.dev.synchronize()?;
+        self.reset()?;
+        Ok(())
+    }
+
+    /// Transcribes up to `batch_size` clips in parallel. In live mode the text output is printed
+    /// as it is produced, otherwise it is returned once all the clips have been processed.
+    fn run(&mut self, pcms: Vec<Vec<f32>>) -> Result<Vec<ClipOutput>> {
+        if pcms.len() > self.batch_size {
+            anyhow::bail!(
+                "got {} clips for a batch size of {}",
+                pcms.len(),
+                self.batch_size
+            );
+        }
+        let (sample_rate, frame_size) = (self.sample_rate as f64, self.frame_size);
+        let silence_len = (self.silence_prefix_seconds * sample_rate) as usize;
+        // The range of mimi frames that overlap with the original audio.
+        let audio_frames = pcms
+            .iter()
+            .map(|pcm| silence_len / frame_size..(silence_len + pcm.len()).div_ceil(frame_size))
+            .collect::<Vec<_>>();
+        let end_times = pcms
+            .iter()
+            .map(|pcm| (silence_len + pcm.len()) as f64 / sample_rate)
+            .collect::<Vec<_>>();
+        let pcms = pcms
+            .into_iter()
+            .map(|mut pcm| {
+                // Add the silence prefix to the audio.
+                if silence_len > 0 {
+                    pcm.splice(0..0, vec![0.0; silence_len]);
+                }
+                // Add some silence at the end to ensure all the audio is processed.
+                let suffix = (self.flush_seconds * sample_rate) as usize;
+                pcm.resize(pcm.len() + suffix, 0.0);
+                pcm
+            })
+            .collect::<Vec<_>>();
+        let mut printers = (0..pcms.len())
+            .map(|_| {
+                Printer::new(
+                    self.timestamps,
+                    self.time_unit,
+                    self.time_precision,
+                    self.color,
+                    self.live,
+                    self.flush_each,
+                    self.smooth_timestamps,
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut transcripts = vec![output::Transcript::default(); pcms.len()];
+        let num_chunks = pcms.iter().map(|pcm| pcm.len().div_ceil(frame_size)).max();
+        let num_chunks = num_chunks.unwrap_or(0);
+        let record_codes = self.mimi_stats.is_some() || self.export_mimi_codes;
+        // The codes for each batch element and each codebook.
+        let mut mimi_codes = vec![vec![vec![]; self.n_q]; pcms.len()];
+        // The text logits for each batch element and each step.
+        let mut text_logits = vec![vec![]; pcms.len()];
+        // Number of consecutive non-speech steps of each batch element, the words are dropped
+        // once it reaches the window of `--skip-nonspeech`.
+        let mut nonspeech_steps = vec![0; pcms.len()];
+        // Whether the last word has been dropped, its end is then dropped too.
+        let mut dropped_word = vec![false; pcms.len()];
+        let nonspeech = self.nonspeech;
+        let skip_word = |nonspeech_steps: &[usize], batch_idx: usize| {
+            nonspeech.is_some_and(|nonspeech| {
+                nonspeech_steps.get(batch_idx) >= Some(&nonspeech.window_steps)
+            })
+        };
+
+        self.reset()?;
+        let time_offset = self.prime_with_context()?;
+        let progress = self.progress.then(|| {
+            let style = indicatif::ProgressStyle::with_template(
+                "{bar:40} {percent:>3}% elapsed {elapsed} eta {eta}",
+            )
+            .unwrap_or_else(|_| indicatif::ProgressStyle::default_bar());
+            indicatif::ProgressBar::new(num_chunks as u64).with_style(style)
+        });
+        for chunk_idx in 0..num_chunks {
+            // Clips that are over, as well as the unused batch elements, are padded with zeros
+            // and masked so that they do not update the model state.
+            let mut active = vec![false; self.batch_size];
+            let mut batch_pcm = vec![0f32; self.batch_size * frame_size];
+            for (batch_idx, pcm) in pcms.iter().enumerate() {
+                let start = chunk_idx * frame_size;
+                if start < pcm.len() {
+                    let chunk = &pcm[start..usize::min(start + frame_size, pcm.len())];
+                    batch_pcm[batch_idx * frame_size..batch_idx * frame_size + chunk.len()]
+                        .copy_from_slice(chunk);
+                    active[batch_idx] = true;
+                }
+            }
+            let mask = if self.batch_size == 1 {
+                ().into()
+            } else {
+                moshi::StreamMask::new(active.clone(), &self.dev)?
+            };
+            let chunk_start = std::time::Instant::now();
+            let pcm = Tensor::new(batch_pcm, &self.dev)?.reshape((self.batch_size, 1, ()))?;
+            let step_codes = std::cell::RefCell::new(vec![]);
+            let asr_msgs = self
+                .state
+                .step_pcm(pcm, None, &mask, |_, _, audio_tokens| {
+                    if record_codes {
+                        let codes = audio_tokens
+                            .iter()
+                            .map(|t| t.flatten_all()?.to_vec1::<u32>())
+                            .collect::<candle::Result<Vec<_>>>();
+                        step_codes.borrow_mut().push(codes)
+                    }
+                })?;
+            if let Some(chunk_timings) = self.chunk_timings.as_mut() {
+                self.dev.synchronize()?;
+                chunk_timings.push(trace::ChunkTiming {
+                    start: chunk_start,
+                    duration: chunk_start.elapsed(),
+                })
+            }
+            for codes in step_codes.into_inner() {
+                let codes = codes?;
+                if let Some(mimi_stats) = self.mimi_stats.as_mut() {
+                    mimi_stats.update(&codes, &active)
+                }
+                for (batch_idx, clip_codes) in mimi_codes.iter_mut().enumerate() {
+                    if active[batch_idx] {
+                        for (clip_codes, codes) in clip_codes.iter_mut().zip(codes.iter()) {
+                            clip_codes.push(codes[batch_idx])
+                        }
+                    }
+                }
+            }
             for asr_msg in asr_msgs.iter() {
                 match asr_msg {
-                    moshi::asr::AsrMsg::Step { prs, .. } => {
+                    asr::AsrMsg::Step { prs, .. } => {
                         // prs is the probability of having no voice activity for different time
-                        // horizons.
-                        // In kyutai/stt-1b-en_fr-candle, these horizons are 0.5s, 1s, 2s, and 3s.
-                        if self.vad && prs[2][0] > 0.5 && !printed_eot {
-                            printed_eot = true;
-                            if !self.timestamps {
-                                print!(" <endofturn pr={}>", prs[2][0]);
-                            } else {
-                                println!("<endofturn pr={}>", prs[2][0]);
+                        // horizons, see `Config::vad_horizons`.
+                        let prs = &prs[self.vad_horizon];
+                        if let Some(nonspeech) = self.nonspeech {
+                            for (batch_idx, steps) in nonspeech_steps.iter_mut().enumerate() {
+                                if !active[batch_idx] {
+                                    continue;
+                                }
+                                if prs[batch_idx] > nonspeech.threshold {
+                                    *steps += 1
+                                } else {
+                                    *steps = 0
+                                }
+                            }
+                        }
+                        if !self.vad {
+                            continue;
+                        }
+                        for (batch_idx, printer) in printers.iter_mut().enumerate() {
+                            if active[batch_idx] && prs[batch_idx] > 0.5 {
+                                printer.end_of_turn(prs[batch_idx])?;
+                                transcripts[batch_idx].end_of_turn();
                             }
                         }
                     }
-                    moshi::asr::AsrMsg::EndWord { stop_time, .. } => {
-                        printed_eot = false;
-                        #[allow(clippy::collapsible_if)]
-                        if self.timestamps {
-                            if let Some((word, start_time)) = last_word.take() {
-                                println!("[{start_time:5.2}-{stop_time:5.2}] {word}");
+                    asr::AsrMsg::EndWord {
+                        stop_time,
+                        batch_idx,
+                    } => {
+                        if dropped_word.get_mut(*batch_idx).is_some_and(std::mem::take) {
+                            continue;
+                        }
+                        let stop_time = f64::max(stop_time - time_offset, 0.);
+                        if let Some(transcript) = transcripts.get_mut(*batch_idx) {
+                            transcript.end_word(stop_time)
+                        }
+                        if let Some(printer) = printers.get_mut(*batch_idx) {
+                            printer.end_word(stop_time)?;
+                        }
+                    }
+                    asr::AsrMsg::Word {
+                        tokens,
+                        start_time,
+                        batch_idx,
+                        confidence,
+                    } => {
+                        let word = self.word_decoder.decode(&self.text_tokenizer, tokens)?;
+                        if self.debug_tokens {
+                            eprintln!("[{batch_idx}] {tokens:?} -> {word:?}");
+                        }
+                        let skip = skip_word(&nonspeech_steps, *batch_idx);
+                        if let Some(dropped) = dropped_word.get_mut(*batch_idx) {
+                            *dropped = skip
+                        }
+                        if skip {
+                            if self.debug_tokens {
+                                eprintln!("[{batch_idx}] dropping {word:?} over non-speech");
+                            }
+                            continue;
+                        }
+                        let start_time = f64::max(start_time - time_offset, 0.);
+                        if let Some(transcript) = transcripts.get_mut(*batch_idx) {
+                            transcript.word(word.clone(), tokens.clone(), start_time, *confidence)
+                        }
+                        if let Some(printer) = printers.get_mut(*batch_idx) {
+                            printer.word(word, start_time, *confidence)?;
+                        }
+                    }
+                    asr::AsrMsg::TextLogits { logits, .. } => {
+                        for (batch_idx, clip_logits) in text_logits.iter_mut().enumerate() {
+                            if active[batch_idx] {
+                                clip_logits.push(logits[batch_idx].clone())
                             }
                         }
                     }
-                    moshi::asr::AsrMsg::Word {
-                        tokens, start_time, ..
+                    asr::AsrMsg::Partial {
+                        tokens,
+                        start_time,
+                        batch_idx,
                     } => {
-                        printed_eot = false;
-                        let word = self
+                        if skip_word(&nonspeech_steps, *batch_idx) {
+                            continue;
+                        }
+                        // The tokens are checked once the word is complete.
+                        let text = self
                             .text_tokenizer
                             .decode_piece_ids(tokens)
-                            .unwrap_or_else(|_| String::new());
-                        if !self.timestamps {
-                            print!(" {word}");
-                            std::io::stdout().flush()?
-                        } else {
-                            if let Some((word, prev_start_time)) = last_word.take() {
-                                println!("[{prev_start_time:5.2}-{start_time:5.2}] {word}");
-                            }
-                            last_word = Some((word, *start_time));
+                            .unwrap_or_default();
+                        if let Some(printer) = printers.get_mut(*batch_idx) {
+                            printer.partial(&text, f64::max(start_time - time_offset, 0.))?;
                         }
                     }
                 }
             }
+            if let Some(progress) = progress.as_ref() {
+                progress.inc(1)
+            }
+        }
+        if let Some(progress) = progress {
+            progress.finish_and_clear()
+        }
+        printers
+            .into_iter()
+            .zip(transcripts)
+            .zip(mimi_codes.into_iter().zip(text_logits))
+            .zip(audio_frames.into_iter().zip(end_times))
+            .map(
+                |(((printer, mut transcript), (codes, logits)), (audio_frames, end_time))| {
+                    let mimi_codes = if self.export_mimi_codes {
+                        // The asr state delays the audio tokens by one step, the first step only
+                        // contains padding tokens.
+                        let codes = codes
+                            .iter()
+                            .map(|codes| {
+                                let end = usize::min(audio_frames.end + 1, codes.len());
+                                // Empty rather than out of bounds for audio shorter than a step,
+                                // e.g. with `--flush-seconds 0`.
+                                let start = usize::min(audio_frames.start + 1, end);
+                                codes[start..end].to_vec()
+                            })
+                            .collect::<Vec<_>>();
+                        Some(Tensor::new(codes, &Device::Cpu)?)
+                    } else {
+                        None
+                    };
+                    let text_logits = if self.dump_logits {
+                        Some(Tensor::new(logits, &Device::Cpu)?)
+                    } else {
+                        None
+                    };
+                    let text = printer.finish()?;
+                    transcript.finish(f64::max(end_time - time_offset, 0.));
+                    Ok(ClipOutput {
+                        text,
+                        transcript,
+                        mimi_codes,
+                        text_logits,
+                    })
+                },
+            )
+            .collect()
+    }
+}
+
+/// Reads a memory field of `/proc/self/status`, e.g. `VmRSS` or `VmHWM`, in MB. Only available
+/// on linux.
+fn proc_status_mb(key: &str) -> Option<f64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status
+        .lines()
+        .find(|l| l.strip_prefix(key).is_some_and(|l| l.starts_with(':')))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<f64>().ok()?;
+    Some(kb / 1024.)
+}
+
+/// Number of chunks of 80ms processed by `--warmup`, a handful of steps is enough for all the
+/// kernels used during inference to have been run once.
+const WARMUP_STEPS: usize = 4;
+
+/// Reads the header of each input and reports the problems, fails if any input cannot be read.
+fn check_inputs(inputs: &[playlist::Entry]) -> Result<()> {
+    let mut failed = 0;
+    for input in inputs.iter() {
+        let info = audio::probe(std::path::Path::new(&input.path));
+        let info = info.and_then(|info| match (input.start, info.duration) {
+            // The duration is unknown for some containers.
+            (Some(start), duration) if duration > 0. && start >= duration => {
+                anyhow::bail!("--start {start}s is past the end of the {duration:.2}s of audio")
+            }
+            _ => Ok(info),
+        });
+        match info {
+            Ok(info) => eprintln!("ok     {}: {info}", input.path),
+            Err(err) => {
+                eprintln!("failed {}: {err:#}", input.path);
+                failed += 1
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} of the {} inputs cannot be read", inputs.len())
+    }
+    eprintln!("The model and the {} inputs are ready", inputs.len());
+    Ok(())
+}
+
+fn load_audio(in_file: &str, resampling: &resample::Resampling) -> Result<Vec<f32>> {
+    let (pcm, _) = load_audio_levels(in_file, resampling)?;
+    Ok(pcm)
+}
+
+/// Same as `load_audio`, also returns the levels of the decoded audio before resampling.
+fn load_audio_levels(
+    in_file: &str,
+    resampling: &resample::Resampling,
+) -> Result<(Vec<f32>, audio::Levels)> {
+    eprintln!("Loading audio file from: {in_file}");
+    let (pcm, info) = audio::decode(std::path::Path::new(in_file))
+        .with_context(|| format!("cannot decode {in_file}"))?;
+    eprintln!("Detected {info}");
+    let levels = audio::Levels::measure(&pcm);
+    Ok((resampling.apply(pcm, info.sample_rate as usize)?, levels))
+}
+
+fn mimi_resynth(args: &MimiResynthArgs) -> Result<()> {
+    let device = device(args.cpu, false)?;
+    let codes = candle::safetensors::load(&args.codes, &device)?;
+    let codes = codes
+        .get("codes")
+        .with_context(|| format!("no codes tensor in {}", args.codes.display()))?;
+    let (n_q, steps) = codes.dims2()?;
+    println!("Loaded mimi codes ({n_q} codebooks, {steps} steps)");
+
+    let api = hf_hub::api::sync::Api::new()?;
+    let repo = api.model(args.hf_repo.to_string());
+    let config_file = repo.get("config.json")?;
+    let config = Config::load(&config_file)?;
+    let mimi_file = repo.get(&config.mimi_name)?;
+    let mut mimi = moshi::mimi::load(mimi_file.to_str().unwrap(), Some(n_q), &device)?;
+
+    let pcm = mimi.decode(&codes.unsqueeze(0)?)?;
+    let pcm = pcm.flatten_all()?.to_vec1::<f32>()?;
+    let mut output = std::io::BufWriter::new(std::fs::File::create(&args.output)?);
+    let sample_rate = mimi.config().sample_rate;
+    kaudio::wav::write_pcm_as_wav(&mut output, &pcm, sample_rate as u32, 1)?;
+    println!(
+        "Saved {:.2}s of audio to {}",
+        pcm.len() as f64 / sample_rate,
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// Transcribes the input files batch by batch and collects the results.
+struct Runner {
+    model: Model,
+    results: output::BatchInferenceResult,
+    /// Record the files that fail in `failed` rather than aborting.
+    ignore_errors: bool,
+    failed: Vec<String>,
+    /// The inputs with the range of audio to transcribe.
+    inputs: Vec<playlist::Entry>,
+    multiple_files: bool,
+    export_mimi_codes: Option<std::path::PathBuf>,
+    dump_logits: Option<std::path::PathBuf>,
+    resampling: resample::Resampling,
+    /// The target and level in dBFS of `--normalize-gain`.
+    normalize_gain: Option<(audio::GainTarget, f32)>,
+    /// The highest peak level of the inputs, before normalization.
+    peak: f32,
+    audio_seconds: f64,
+    processing_time: std::time::Duration,
+}
+
+impl Runner {
+    fn fail(&mut self, in_files: &[&str], err: anyhow::Error) -> Result<()> {
+        if !self.ignore_errors {
+            return Err(err);
+        }
+        eprintln!("Skipping {}: {err:#}", in_files.join(", "));
+        self.failed.extend(in_files.iter().map(|f| f.to_string()));
+        Ok(())
+    }
+
+    /// Loads, trims and normalizes an input.
+    fn load(&mut self, in_file: &str) -> Result<Vec<f32>> {
+        let (mut pcm, levels) = load_audio_levels(in_file, &self.resampling)?;
+        let input = self.inputs.iter().find(|i| i.path == in_file);
+        if let Some(input) = input.filter(|i| i.start.is_some() || i.end.is_some()) {
+            let sample_rate = self.model.sample_rate;
+            pcm = playlist::trim(pcm, sample_rate, input.start, input.end)
+                .with_context(|| format!("cannot trim {in_file}"))?;
+        }
+        self.peak = self.peak.max(levels.peak);
+        if let Some((target, level)) = self.normalize_gain {
+            if levels.clipped > 0 {
+                eprintln!("Warning: {in_file} has {} clipped samples", levels.clipped);
+            }
+            audio::normalize(&mut pcm, target, level);
+        }
+        Ok(pcm)
+    }
+
+    fn transcribe(&mut self, in_files: &[&str]) -> Result<()> {
+        let mut loaded = vec![];
+        let mut pcms = vec![];
+        for &in_file in in_files.iter() {
+            match self.load(in_file) {
+                Ok(pcm) => {
+                    loaded.push(in_file);
+                    pcms.push(pcm)
+                }
+                Err(err) => self.fail(&[in_file], err)?,
+            }
         }
-        if let Some((word, start_time)) = last_word.take() {
-            println!("[{start_time:5.2}-     ] {word}");
+        if pcms.is_empty() {
+            return Ok(());
+        }
+        let in_files = loaded;
+        eprintln!("Running inference");
+        if self.multiple_files && self.model.live {
+            println!("==> {} <==", in_files[0]);
+        }
+        let durations = pcms
+            .iter()
+            .map(|pcm| pcm.len() as f64 / self.model.sample_rate as f64)
+            .collect::<Vec<_>>();
+        let start_time = std::time::Instant::now();
+        let outputs = match self.model.run(pcms) {
+            Ok(outputs) => outputs,
+            Err(err) => return self.fail(&in_files, err),
+        };
+        self.model.dev.synchronize()?;
+        let batch_time = start_time.elapsed();
+        self.processing_time += batch_time;
+        self.audio_seconds += durations.iter().sum::<f64>();
+        for ((in_file, output), duration) in in_files.iter().zip(outputs).zip(durations) {
+            if let (Some(path), Some(codes)) = (self.export_mimi_codes.as_ref(), output.mimi_codes)
+            {
+                let path = if self.multiple_files {
+                    std::fs::create_dir_all(path)?;
+                    let stem = std::path::Path::new(in_file)
+                        .file_stem()
+                        .unwrap_or_default();
+                    path.join(stem).with_extension("safetensors")
+                } else {
+                    path.clone()
+                };
+                codes.save_safetensors("codes", &path)?;
+                eprintln!("Saved mimi codes {:?} to {}", codes.shape(), path.display());
+            }
+            if let (Some(path), Some(logits)) = (self.dump_logits.as_ref(), output.text_logits) {
+                let path = if self.multiple_files {
+                    std::fs::create_dir_all(path)?;
+                    let stem = std::path::Path::new(in_file)
+                        .file_stem()
+                        .unwrap_or_default();
+                    path.join(stem).with_extension("npy")
+                } else {
+                    path.clone()
+                };
+                logits.write_npy(&path)?;
+                eprintln!(
+                    "Saved text logits {:?} to {}",
+                    logits.shape(),
+                    path.display()
+                );
+            }
+            self.results.push(output::FileResult {
+                file: in_file.to_string(),
+                text: output.text,
+                transcript: output.transcript,
+                duration,
+                processing_time: batch_time.as_secs_f64(),
+            })
         }
-        println!();
         Ok(())
     }
 }
 
+/// The manifest written by a worker, merged into `manifest` by the parent process.
+fn worker_manifest(manifest: &std::path::Path, worker_index: usize) -> std::path::PathBuf {
+    manifest.with_extension(format!("worker{worker_index}.json"))
+}
+
+/// Runs `args.workers` copies of the current command, each worker transcribes one file out of
+/// `args.workers` with its own copy of the model. The standard outputs of the workers are
+/// printed one after the other and their manifests are merged.
+fn run_workers(args: &Args) -> Result<()> {
+    if args.zip_output.is_some() || args.tar_output.is_some() {
+        anyhow::bail!("--workers does not support --zip-output and --tar-output");
+    }
+    let exe = std::env::current_exe()?;
+    let mut workers = vec![];
+    for worker_index in 0..args.workers {
+        let child = std::process::Command::new(&exe)
+            .args(std::env::args_os().skip(1))
+            .arg("--worker-index")
+            .arg(worker_index.to_string())
+            .stdout(std::process::Stdio::piped())
+            .spawn()?;
+        workers.push(std::thread::spawn(move || child.wait_with_output()));
+    }
+    let mut failed_workers = 0;
+    for (worker_index, worker) in workers.into_iter().enumerate() {
+        let output = match worker.join() {
+            Ok(output) => output?,
+            Err(_) => anyhow::bail!("worker {worker_index} panicked"),
+        };
+        std::io::Write::write_all(&mut std::io::stdout(), &output.stdout)?;
+        if !output.status.success() {
+            eprintln!("Worker {worker_index} failed: {}", output.status);
+            failed_workers += 1
+        }
+    }
+    if let Some(manifest) = args.output_manifest.as_ref() {
+        let mut entries = vec![];
+        if args.skip_existing && manifest.exists() {
+            let previous_entries = output::read_manifest(manifest)?;
+            entries.extend(previous_entries.into_iter().filter(|e| e.success))
+        }
+        for worker_index in 0..args.workers {
+            let worker_manifest = worker_manifest(manifest, worker_index);
+            if worker_manifest.exists() {
+                entries.extend(output::read_manifest(&worker_manifest)?);
+                std::fs::remove_file(&worker_manifest)?
+            }
+        }
+        output::write_manifest(manifest, &entries)?;
+        eprintln!("Wrote {}", manifest.display())
+    }
+    if failed_workers > 0 {
+        anyhow::bail!("{failed_workers} of {} workers failed", args.workers)
+    }
+    Ok(())
+}
+
+/// Prints the VAD heads of the model, only the config is downloaded.
+fn list_horizons(args: &ModelArgs) -> Result<()> {
+    let api = hf_hub::api::sync::Api::new()?;
+    let repo = api.model(args.hf_repo.to_string());
+    let config_file = repo.get("config.json")?;
+    let config = Config::load(&config_file)?;
+    let horizons = config.vad_horizons();
+    println!("{} VAD heads in {}", horizons.len(), args.hf_repo);
+    for (idx, horizon) in horizons.iter().enumerate() {
+        match horizon {
+            Some(horizon) => println!("{idx}: no voice activity for the next {horizon}s"),
+            None => println!("{idx}: unknown horizon"),
+        }
+    }
+    Ok(())
+}
+
+fn transcribe_remote(args: &Args, url: &str) -> Result<()> {
+    use std::io::IsTerminal;
+
+    if args.format != output::Format::Text {
+        anyhow::bail!("--remote only supports the text format");
+    }
+    let remote = remote::Remote {
+        url: url.to_string(),
+        api_key: args.remote_api_key.clone(),
+        retries: args.remote_retries,
+        sample_format: args.remote_sample_format,
+    };
+    let runtime = tokio::runtime::Runtime::new()?;
+    for path in args.in_files.iter() {
+        let (pcm, info) = audio::decode(std::path::Path::new(path))
+            .with_context(|| format!("cannot decode {path}"))?;
+        let mut printer = Printer::new(
+            args.timestamps,
+            args.time_unit,
+            args.time_precision,
+            args.color.enabled(),
+            true,
+            args.line_buffered || std::io::stdout().is_terminal(),
+            args.smooth_timestamps,
+        );
+        runtime
+            .block_on(remote.transcribe(&pcm, info.sample_rate as usize, &mut printer))
+            .with_context(|| format!("cannot transcribe {path} on {url}"))?;
+        printer.finish()?;
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
-    let device = device(args.cpu)?;
-    println!("Using device: {:?}", device);
+    match &args.command {
+        Some(Command::MimiResynth(args)) => return mimi_resynth(args),
+        Some(Command::Bench(args)) => return bench::run(args),
+        Some(Command::Calibrate(args)) => return calibration::calibrate(args),
+        Some(Command::Serve(args)) => return server::run(args),
+        None => {}
+    }
+    if args.list_horizons {
+        return list_horizons(&args.model);
+    }
+    if let Some(url) = args.remote.as_ref() {
+        return transcribe_remote(&args, url);
+    }
+    if args.checksum_output
+        && args.output_dir.is_none()
+        && args.zip_output.is_none()
+        && args.tar_output.is_none()
+    {
+        anyhow::bail!("--checksum-output requires --output-dir, --zip-output or --tar-output");
+    }
+    if args.vad_only && args.format == output::Format::Srt {
+        anyhow::bail!("--vad-only only supports the text and json formats");
+    }
+    if args.turns && args.format == output::Format::Srt {
+        anyhow::bail!("--turns only supports the text and json formats");
+    }
+    if args.min_segment_confidence.is_some() && !args.turns && !args.vad_only {
+        anyhow::bail!("--min-segment-confidence requires --turns or --vad-only");
+    }
+    if args.workers > 1 && args.worker_index.is_none() && !args.check {
+        return run_workers(&args);
+    }
+    let inputs = match args.manifest.as_ref() {
+        Some(manifest) => {
+            let entries = playlist::read(manifest)?.into_iter();
+            let entries = entries.map(|entry| playlist::Entry {
+                start: entry.start.or(args.start),
+                end: entry.end.or(args.end),
+                ..entry
+            });
+            entries.collect::<Vec<_>>()
+        }
+        None => {
+            let entries = args.in_files.iter().map(|path| playlist::Entry {
+                path: path.clone(),
+                out_file: None,
+                start: args.start,
+                end: args.end,
+            });
+            entries.collect()
+        }
+    };
+    let in_files = inputs.iter().map(|i| i.path.clone()).collect::<Vec<_>>();
+    let device = device(args.model.cpu, args.model.strict_device)?;
+    if args.dry_run_estimate {
+        return calibration::estimate(&in_files, &device);
+    }
+    eprintln!("Using device: {:?}", device);
 
-    println!("Loading audio file from: {}", args.in_file);
-    let (pcm, sample_rate) = kaudio::pcm_decode(&args.in_file)?;
-    let pcm = if sample_rate != 24_000 {
-        kaudio::resample(&pcm, sample_rate as usize, 24_000)?
+    eprintln!("Loading model from repository: {}", args.model.hf_repo);
+    let mut model = Model::load_from_hf(&args, &device)?;
+    let resampling = resample::Resampling {
+        target_rate: model.sample_rate,
+        quality: args.resample_quality,
+        always: args.always_resample,
+        compensate_delay: args.compensate_resample_delay,
+    };
+    if let Some(context_file) = args.context_prepend_audio.as_ref() {
+        model.context_pcm = Some(load_audio(context_file, &resampling)?);
+    }
+    if args.check {
+        return check_inputs(&inputs);
+    }
+    if args.warmup || args.stats {
+        eprintln!("Warming up with {WARMUP_STEPS} steps");
+        model.warmup()?;
+    }
+    let mut results = output::BatchInferenceResult::new(args.format, args.time_unit);
+    results.token_ids = args.debug_tokens;
+    results.time_precision = args.time_precision;
+    results.vad_only = args.vad_only;
+    results.min_segment_confidence = args.min_segment_confidence;
+    results.meta = Some(output::Meta {
+        model_repo: args.model.hf_repo.clone(),
+        model_path: args.model.model_path.clone(),
+        sample_rate: model.sample_rate,
+        delay_seconds: model.audio_delay_seconds,
+    });
+    results.turns = match (args.turns, args.alternate_speakers) {
+        (false, _) => None,
+        (true, false) => Some(output::TurnLabel::Index),
+        (true, true) => Some(output::TurnLabel::AlternateSpeakers),
+    };
+    let mut runner = Runner {
+        model,
+        results,
+        ignore_errors: args.ignore_errors || args.retry_failed > 0 || args.manifest.is_some(),
+        failed: vec![],
+        inputs: inputs.clone(),
+        multiple_files: in_files.len() > 1,
+        export_mimi_codes: args.export_mimi_codes.clone(),
+        dump_logits: args.dump_logits.clone(),
+        resampling,
+        normalize_gain: args.normalize_gain.map(|target| {
+            (
+                target,
+                args.normalize_level.unwrap_or(target.default_level()),
+            )
+        }),
+        peak: 0.,
+        audio_seconds: 0.,
+        processing_time: std::time::Duration::ZERO,
+    };
+    // The successful entries of a previous run, these files are not transcribed again.
+    let previous_entries = match args.output_manifest.as_ref() {
+        Some(manifest) if args.skip_existing && manifest.exists() => {
+            let entries = output::read_manifest(manifest)?;
+            entries.into_iter().filter(|e| e.success).collect()
+        }
+        _ => vec![],
+    };
+    // A worker only handles one file out of `workers`.
+    let worker_files = in_files
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| args.worker_index.is_none_or(|w| idx % args.workers == w))
+        .map(|(_, f)| f.as_str())
+        .collect::<Vec<_>>();
+    let in_files = worker_files
+        .iter()
+        .filter(|f| !previous_entries.iter().any(|e| e.input_path == **f))
+        .copied()
+        .collect::<Vec<_>>();
+    if in_files.len() < worker_files.len() {
+        eprintln!(
+            "Skipping {} files already in the manifest",
+            worker_files.len() - in_files.len()
+        );
+    }
+    for in_files in in_files.chunks(args.model.batch_size) {
+        runner.transcribe(in_files)?
+    }
+    for attempt in 1..=args.retry_failed {
+        if runner.failed.is_empty() {
+            break;
+        }
+        let backoff = std::time::Duration::from_secs(1 << (attempt - 1).min(10));
+        eprintln!(
+            "Retrying {} failed files in {}s, attempt {attempt}/{}",
+            runner.failed.len(),
+            backoff.as_secs(),
+            args.retry_failed
+        );
+        std::thread::sleep(backoff);
+        // The failed files are retried one at a time so that a file that does not fit in memory
+        // as part of a batch gets a chance to go through on its own.
+        for in_file in std::mem::take(&mut runner.failed) {
+            runner.transcribe(&[&in_file])?
+        }
+    }
+    if !runner.failed.is_empty() && !args.ignore_errors && args.manifest.is_none() {
+        anyhow::bail!("cannot transcribe {}", runner.failed.join(", "))
+    }
+    let Runner {
+        model,
+        mut results,
+        failed,
+        audio_seconds,
+        processing_time,
+        peak,
+        ..
+    } = runner;
+    // Retried files come last, restore the order of the inputs.
+    results
+        .results
+        .sort_by_key(|r| in_files.iter().position(|f| *f == r.file));
+    // The entries of the manifest with their own output file are left out of the other outputs.
+    let out_file = |file: &str| {
+        let input = inputs.iter().find(|i| i.path == file);
+        input.and_then(|i| i.out_file.clone())
+    };
+    let (own_outputs, other_outputs) = std::mem::take(&mut results.results)
+        .into_iter()
+        .partition::<Vec<_>, _>(|r| out_file(&r.file).is_some());
+    let mut written = vec![];
+    for result in own_outputs.iter() {
+        let path = out_file(&result.file).expect("partitioned on out_file");
+        results.write_file(result, &path)?;
+        written.push(path)
+    }
+    results.results = other_outputs;
+    let other_written = if results.results.is_empty() && !own_outputs.is_empty() {
+        vec![]
+    } else if let Some(output_dir) = args.output_dir.as_ref() {
+        results.write_dir(output_dir)?
+    } else if let Some(zip_output) = args.zip_output.as_ref() {
+        results.write_zip(zip_output)?;
+        vec![zip_output.clone()]
+    } else if let Some(tar_output) = args.tar_output.as_ref() {
+        results.write_tar_gz(tar_output)?;
+        vec![tar_output.clone()]
     } else {
-        pcm
+        results.print()?;
+        vec![]
     };
-    println!("Loading model from repository: {}", args.hf_repo);
-    let mut model = Model::load_from_hf(&args, &device)?;
-    println!("Running inference");
-    model.run(pcm)?;
+    written.extend(other_written);
+    results.results.extend(own_outputs);
+    results
+        .results
+        .sort_by_key(|r| in_files.iter().position(|f| *f == r.file));
+    if let Some(json_sidecar) = args.json_sidecar.as_ref() {
+        results.write_json(json_sidecar)?;
+        written.push(json_sidecar.clone());
+    }
+    for path in written.iter() {
+        eprintln!("Wrote {}", path.display())
+    }
+    if args.checksum_output {
+        for path in output::append_checksums(&written)? {
+            eprintln!("Updated {}", path.display())
+        }
+    }
+    if let Some(manifest) = args.output_manifest.as_ref() {
+        let model_name = format!("{}/{}", args.model.hf_repo, args.model.model_path);
+        // The previous entries are merged by the parent process when running as a worker.
+        let (manifest, mut entries) = match args.worker_index {
+            Some(worker_index) => (worker_manifest(manifest, worker_index), vec![]),
+            None => (manifest.clone(), previous_entries),
+        };
+        for result in results.results.iter() {
+            let output_path = match (&args.output_dir, &args.zip_output, &args.tar_output) {
+                _ if out_file(&result.file).is_some() => out_file(&result.file),
+                (Some(dir), _, _) => Some(results.output_path(dir, &result.file)),
+                (None, Some(archive), _) | (None, None, Some(archive)) => Some(archive.clone()),
+                (None, None, None) => None,
+            };
+            let output_path = output_path.map(|p| p.display().to_string());
+            entries.push(output::ManifestEntry::new(result, output_path, &model_name))
+        }
+        for in_file in failed.iter() {
+            entries.push(output::ManifestEntry::failed(in_file, &model_name))
+        }
+        output::write_manifest(&manifest, &entries)?;
+        eprintln!("Wrote {}", manifest.display())
+    }
+    if let Some(mimi_stats) = model.mimi_stats.as_ref() {
+        mimi_stats.print()
+    }
+    if args.manifest.is_some() {
+        for &in_file in worker_files.iter() {
+            let status = if failed.iter().any(|f| f == in_file) {
+                "failed"
+            } else if results.results.iter().any(|r| r.file == in_file) {
+                "ok"
+            } else {
+                // Successful in the `--output-manifest` of a previous run.
+                "skipped"
+            };
+            match out_file(in_file).filter(|_| status == "ok") {
+                Some(path) => eprintln!("{status:>7} {in_file} -> {}", path.display()),
+                None => eprintln!("{status:>7} {in_file}"),
+            }
+        }
+    }
+    if args.ignore_errors || args.retry_failed > 0 || args.manifest.is_some() {
+        eprintln!(
+            "{} files succeeded, {} files failed",
+            results.results.len(),
+            failed.len()
+        );
+    }
+    if args.stats {
+        let processing_time = processing_time.as_secs_f64();
+        eprintln!(
+            "Processed {audio_seconds:.2}s of audio in {processing_time:.2}s, RTF {:.3}",
+            processing_time / audio_seconds
+        );
+        eprintln!("Peak level {:.1}dBFS", audio::to_dbfs(peak));
+    }
+    if let Some(chunk_timings) = model.chunk_timings.as_ref() {
+        trace::print_histogram(chunk_timings);
+        if let Some(trace_out) = args.trace_out.as_ref() {
+            trace::write_csv(trace_out, chunk_timings)?;
+            eprintln!("Wrote {}", trace_out.display())
+        }
+    }
+    if args.manifest.is_some() && !failed.is_empty() && !args.ignore_errors {
+        anyhow::bail!("{} of {} entries failed", failed.len(), worker_files.len())
+    }
     Ok(())
 }
@@ -0,0 +1,737 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+use anyhow::{Context, Result};
+
+use crate::TimeUnit;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Text,
+    Json,
+    Srt,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Text => "txt",
+            Self::Json => "json",
+            Self::Srt => "srt",
+        }
+    }
+}
+
+/// How the turns are labelled with `--turns`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TurnLabel {
+    /// `Turn 1`, `Turn 2`, ...
+    Index,
+    /// `Speaker A` and `Speaker B` in alternation, assuming that each end of turn is a change of
+    /// speaker between two parties.
+    AlternateSpeakers,
+}
+
+impl TurnLabel {
+    pub fn label(self, turn: usize) -> String {
+        match self {
+            Self::Index => format!("Turn {}", turn + 1),
+            Self::AlternateSpeakers if turn.is_multiple_of(2) => "Speaker A".to_string(),
+            Self::AlternateSpeakers => "Speaker B".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Word {
+    pub text: String,
+    pub tokens: Vec<u32>,
+    pub start_time: f64,
+    pub stop_time: f64,
+    pub confidence: f32,
+    /// Index of the turn the word belongs to, turns are delimited by the end of turn events of
+    /// the VAD heads.
+    pub turn: usize,
+}
+
+/// A turn of the transcript with the aggregate confidence of its words.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub start_time: f64,
+    pub stop_time: f64,
+    /// Mean confidence of the words.
+    pub confidence: f32,
+    /// Confidence of the least confident word.
+    pub min_confidence: f32,
+}
+
+impl Segment {
+    fn new(words: &[Word]) -> Self {
+        let confidences = words.iter().map(|w| w.confidence);
+        Self {
+            start_time: words[0].start_time,
+            stop_time: words[words.len() - 1].stop_time,
+            confidence: confidences.clone().sum::<f32>() / words.len() as f32,
+            min_confidence: confidences.fold(f32::INFINITY, f32::min),
+        }
+    }
+
+    /// Whether the mean confidence is under `threshold`, when set.
+    pub fn is_low_confidence(&self, threshold: Option<f32>) -> bool {
+        threshold.is_some_and(|threshold| self.confidence < threshold)
+    }
+
+    fn to_json(self, time_unit: TimeUnit, threshold: Option<f32>) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "start_time": json_time(self.start_time, time_unit),
+            "stop_time": json_time(self.stop_time, time_unit),
+            "confidence": self.confidence,
+            "min_confidence": self.min_confidence,
+        });
+        if threshold.is_some() {
+            json["low_confidence"] = serde_json::json!(self.is_low_confidence(threshold));
+        }
+        json
+    }
+}
+
+/// The words of a single clip, a word stops either at the next `EndWord` message or at the start
+/// of the following word.
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    pub words: Vec<Word>,
+    stop_pending: bool,
+    turn: usize,
+}
+
+impl Transcript {
+    pub fn word(&mut self, text: String, tokens: Vec<u32>, start_time: f64, confidence: f32) {
+        self.end_word(start_time);
+        self.words.push(Word {
+            text,
+            tokens,
+            start_time,
+            stop_time: start_time,
+            confidence,
+            turn: self.turn,
+        });
+        self.stop_pending = true;
+    }
+
+    /// Starts a new turn, unless no word has been added since the previous end of turn.
+    pub fn end_of_turn(&mut self) {
+        if self.words.last().is_some_and(|w| w.turn == self.turn) {
+            self.turn += 1
+        }
+    }
+
+    /// The words grouped by turn.
+    pub fn turns(&self) -> Vec<&[Word]> {
+        self.words.chunk_by(|w1, w2| w1.turn == w2.turn).collect()
+    }
+
+    /// The speech segments, one per turn.
+    pub fn segments(&self) -> Vec<Segment> {
+        self.turns().into_iter().map(Segment::new).collect()
+    }
+
+    /// The segments with their confidence, segments with a mean confidence under `threshold`
+    /// are flagged with `low_confidence`.
+    pub fn segments_to_json(
+        &self,
+        file: &str,
+        time_unit: TimeUnit,
+        threshold: Option<f32>,
+    ) -> serde_json::Value {
+        let segments = self.segments().into_iter();
+        let segments = segments
+            .map(|segment| segment.to_json(time_unit, threshold))
+            .collect::<Vec<_>>();
+        serde_json::json!({ "file": file, "segments": segments })
+    }
+
+    /// One `[label] text` line per turn, the turns with a mean confidence under `threshold` are
+    /// marked as such.
+    pub fn turns_to_text(&self, label: TurnLabel, threshold: Option<f32>) -> String {
+        let turns = self.turns().into_iter().enumerate();
+        turns
+            .map(|(turn, words)| {
+                let text = words.iter().map(|w| w.text.as_str()).collect::<Vec<_>>();
+                let segment = Segment::new(words);
+                let flag = if segment.is_low_confidence(threshold) {
+                    format!(" (low confidence {:.2})", segment.confidence)
+                } else {
+                    String::new()
+                };
+                format!("[{}] {}{flag}\n", label.label(turn), text.join(" "))
+            })
+            .collect()
+    }
+
+    pub fn segments_to_text(
+        &self,
+        time_unit: TimeUnit,
+        time_precision: u8,
+        threshold: Option<f32>,
+    ) -> String {
+        let segments = self.segments().into_iter();
+        segments
+            .map(|segment| {
+                let (start_time, stop_time) = (segment.start_time, Some(segment.stop_time));
+                let span = time_unit.span(time_precision, start_time, stop_time);
+                if segment.is_low_confidence(threshold) {
+                    format!("{span} (low confidence {:.2})\n", segment.confidence)
+                } else {
+                    format!("{span}\n")
+                }
+            })
+            .collect()
+    }
+
+    pub fn end_word(&mut self, stop_time: f64) {
+        if self.stop_pending {
+            if let Some(word) = self.words.last_mut() {
+                word.stop_time = stop_time
+            }
+            self.stop_pending = false;
+        }
+    }
+
+    /// Closes the last word at `end_time` if it has not been ended yet.
+    pub fn finish(&mut self, end_time: f64) {
+        let end_time = self
+            .words
+            .last()
+            .map_or(end_time, |w| f64::max(w.start_time, end_time));
+        self.end_word(end_time)
+    }
+
+    pub fn text(&self) -> String {
+        let words = self.words.iter().map(|w| w.text.as_str());
+        words.collect::<Vec<_>>().join(" ")
+    }
+
+    pub fn to_json(&self, file: &str, time_unit: TimeUnit, token_ids: bool) -> serde_json::Value {
+        let time = |t: f64| json_time(t, time_unit);
+        let words = self
+            .words
+            .iter()
+            .map(|w| {
+                let mut word = serde_json::json!({
+                    "text": w.text,
+                    "start_time": time(w.start_time),
+                    "stop_time": time(w.stop_time),
+                    "confidence": w.confidence,
+                });
+                if token_ids {
+                    word["token_ids"] = serde_json::json!(w.tokens);
+                }
+                word
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({ "file": file, "text": self.text(), "words": words })
+    }
+
+    /// Groups the words in cues of at most `MAX_WORDS` words, a new cue is also started after a
+    /// pause of `MAX_GAP` seconds.
+    pub fn to_srt(&self) -> String {
+        const MAX_WORDS: usize = 8;
+        const MAX_GAP: f64 = 0.8;
+
+        // Overlapping cues are rejected by some players.
+        let mut words = self.words.clone();
+        smooth_timings(&mut words);
+        let mut cues: Vec<&[Word]> = vec![];
+        let mut start = 0;
+        for (idx, word) in words.iter().enumerate() {
+            let prev_stop = idx.checked_sub(1).map(|idx| words[idx].stop_time);
+            let gap = prev_stop.is_some_and(|prev_stop| word.start_time - prev_stop >= MAX_GAP);
+            if idx > start && (idx - start >= MAX_WORDS || gap) {
+                cues.push(&words[start..idx]);
+                start = idx;
+            }
+        }
+        if start < words.len() {
+            cues.push(&words[start..]);
+        }
+        let mut srt = String::new();
+        for (cue_idx, words) in cues.iter().enumerate() {
+            let start_time = srt_time(words[0].start_time);
+            let stop_time = srt_time(words[words.len() - 1].stop_time);
+            let text = words.iter().map(|w| w.text.as_str()).collect::<Vec<_>>();
+            srt.push_str(&format!(
+                "{}\n{start_time} --> {stop_time}\n{}\n\n",
+                cue_idx + 1,
+                text.join(" ")
+            ));
+        }
+        srt
+    }
+}
+
+/// Makes the word spans monotonic and non-overlapping. A word that starts before the previous one
+/// stops is clamped to start at that stop, unless the previous word also stops after it, the
+/// word is then split in the middle between the two.
+pub fn smooth_timings(words: &mut [Word]) {
+    for idx in 0..words.len() {
+        let word = &mut words[idx];
+        word.stop_time = word.stop_time.max(word.start_time);
+        let Some(prev_idx) = idx.checked_sub(1) else {
+            continue;
+        };
+        let (prev_start, prev_stop) = (words[prev_idx].start_time, words[prev_idx].stop_time);
+        let word = &mut words[idx];
+        word.start_time = word.start_time.max(prev_start);
+        word.stop_time = word.stop_time.max(word.start_time);
+        if word.start_time >= prev_stop {
+            continue;
+        }
+        let boundary = if prev_stop <= word.stop_time {
+            prev_stop
+        } else {
+            (word.start_time + word.stop_time) / 2.
+        };
+        word.start_time = boundary;
+        words[prev_idx].stop_time = boundary;
+    }
+}
+
+fn json_time(time: f64, time_unit: TimeUnit) -> serde_json::Value {
+    match time_unit {
+        TimeUnit::Seconds => serde_json::json!(time),
+        TimeUnit::Ms => serde_json::json!(TimeUnit::to_ms(time)),
+    }
+}
+
+fn srt_time(time: f64) -> String {
+    let ms = TimeUnit::to_ms(time);
+    let (h, m, s, ms) = (ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000);
+    format!("{h:02}:{m:02}:{s:02},{ms:03}")
+}
+
+/// The result of transcribing a single input file.
+pub struct FileResult {
+    pub file: String,
+    /// The text output as formatted for the terminal, `None` when it has already been printed.
+    pub text: Option<String>,
+    pub transcript: Transcript,
+    /// Duration of the audio in seconds.
+    pub duration: f64,
+    /// Time spent transcribing the batch that contained this file, in seconds.
+    pub processing_time: f64,
+}
+
+/// An entry of the `--output-manifest` file, failed files only have their input path and model
+/// set.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ManifestEntry {
+    pub input_path: String,
+    pub output_path: Option<String>,
+    pub success: bool,
+    pub duration: Option<f64>,
+    pub word_count: Option<usize>,
+    pub average_confidence: Option<f32>,
+    pub model: String,
+    pub processing_time: Option<f64>,
+}
+
+impl ManifestEntry {
+    pub fn new(result: &FileResult, output_path: Option<String>, model: &str) -> Self {
+        let words = &result.transcript.words;
+        let average_confidence = (!words.is_empty())
+            .then(|| words.iter().map(|w| w.confidence).sum::<f32>() / words.len() as f32);
+        Self {
+            input_path: result.file.clone(),
+            output_path,
+            success: true,
+            duration: Some(result.duration),
+            word_count: Some(words.len()),
+            average_confidence,
+            model: model.to_string(),
+            processing_time: Some(result.processing_time),
+        }
+    }
+
+    pub fn failed(input_path: &str, model: &str) -> Self {
+        Self {
+            input_path: input_path.to_string(),
+            output_path: None,
+            success: false,
+            duration: None,
+            word_count: None,
+            average_confidence: None,
+            model: model.to_string(),
+            processing_time: None,
+        }
+    }
+}
+
+pub fn read_manifest(path: &std::path::Path) -> Result<Vec<ManifestEntry>> {
+    let entries = serde_json::from_str(&std::fs::read_to_string(path)?)
+        .with_context(|| format!("invalid manifest {}", path.display()))?;
+    Ok(entries)
+}
+
+/// Writes the manifest entries as a json array, going through a temporary file so that an
+/// interrupted run does not leave a truncated manifest.
+pub fn write_manifest(path: &std::path::Path, entries: &[ManifestEntry]) -> Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, serde_json::to_string_pretty(entries)?)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Provenance of the transcripts, added to each json output as a `meta` object.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Meta {
+    pub model_repo: String,
+    pub model_path: String,
+    /// Sample rate of the audio fed to the model.
+    pub sample_rate: usize,
+    /// Delay of the text stream with respect to the audio.
+    pub delay_seconds: f64,
+}
+
+/// Collects the results of all the input files so that the outputs are only written once all the
+/// files have been transcribed.
+pub struct BatchInferenceResult {
+    pub format: Format,
+    pub time_unit: TimeUnit,
+    /// Fractional digits of the times in seconds in the text output.
+    pub time_precision: u8,
+    /// Include the token ids of each word in the json output.
+    pub token_ids: bool,
+    /// Only output the speech segments rather than the words.
+    pub vad_only: bool,
+    /// Label the words with their turn.
+    pub turns: Option<TurnLabel>,
+    /// Flag the segments and turns with a mean confidence under this threshold.
+    pub min_segment_confidence: Option<f32>,
+    pub meta: Option<Meta>,
+    pub results: Vec<FileResult>,
+}
+
+impl BatchInferenceResult {
+    pub fn new(format: Format, time_unit: TimeUnit) -> Self {
+        Self {
+            format,
+            time_unit,
+            time_precision: 2,
+            token_ids: false,
+            vad_only: false,
+            turns: None,
+            min_segment_confidence: None,
+            meta: None,
+            results: vec![],
+        }
+    }
+
+    pub fn push(&mut self, result: FileResult) {
+        self.results.push(result)
+    }
+
+    fn to_json(&self, result: &FileResult) -> serde_json::Value {
+        let mut json = self.transcript_to_json(result);
+        if let Some(meta) = self.meta.as_ref() {
+            let mut meta = serde_json::json!(meta);
+            meta["duration"] = json_time(result.duration, self.time_unit);
+            json["meta"] = meta;
+        }
+        json
+    }
+
+    fn transcript_to_json(&self, result: &FileResult) -> serde_json::Value {
+        let transcript = &result.transcript;
+        if self.vad_only {
+            let threshold = self.min_segment_confidence;
+            return transcript.segments_to_json(&result.file, self.time_unit, threshold);
+        }
+        let mut json = transcript.to_json(&result.file, self.time_unit, self.token_ids);
+        if let (Some(label), Some(words)) = (self.turns, json["words"].as_array_mut()) {
+            for (word, w) in words.iter_mut().zip(transcript.words.iter()) {
+                word["turn"] = serde_json::json!(label.label(w.turn));
+            }
+        }
+        if let Some(label) = self.turns {
+            let turns = transcript.segments().into_iter().enumerate();
+            let turns = turns
+                .map(|(turn, segment)| {
+                    let mut json = segment.to_json(self.time_unit, self.min_segment_confidence);
+                    json["label"] = serde_json::json!(label.label(turn));
+                    json
+                })
+                .collect::<Vec<_>>();
+            json["turns"] = serde_json::json!(turns);
+        }
+        json
+    }
+
+    fn render(&self, result: &FileResult) -> Result<String> {
+        let output = match self.format {
+            Format::Text if self.vad_only => {
+                let threshold = self.min_segment_confidence;
+                result
+                    .transcript
+                    .segments_to_text(self.time_unit, self.time_precision, threshold)
+            }
+            Format::Text => match (self.turns, result.text.as_ref()) {
+                (Some(label), _) => {
+                    let threshold = self.min_segment_confidence;
+                    result.transcript.turns_to_text(label, threshold)
+                }
+                (None, Some(text)) => text.clone(),
+                (None, None) => format!("{}\n", result.transcript.text()),
+            },
+            Format::Json => serde_json::to_string_pretty(&self.to_json(result))?,
+            Format::Srt => result.transcript.to_srt(),
+        };
+        Ok(output)
+    }
+
+    /// Prints the outputs on stdout, the json results of several files are combined in a single
+    /// array.
+    pub fn print(&self) -> Result<()> {
+        match self.format {
+            Format::Json if self.results.len() > 1 => {
+                let json = self
+                    .results
+                    .iter()
+                    .map(|r| self.to_json(r))
+                    .collect::<Vec<_>>();
+                println!("{}", serde_json::to_string_pretty(&json)?)
+            }
+            Format::Json => {
+                for result in self.results.iter() {
+                    println!("{}", self.render(result)?)
+                }
+            }
+            Format::Text | Format::Srt => {
+                let multiple_files = self.results.len() > 1;
+                for result in self.results.iter() {
+                    if result.text.is_none()
+                        && self.format == Format::Text
+                        && !self.vad_only
+                        && self.turns.is_none()
+                    {
+                        // Already printed while transcribing.
+                        continue;
+                    }
+                    if multiple_files {
+                        println!("==> {} <==", result.file);
+                    }
+                    print!("{}", self.render(result)?)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the output of a single input to `path`.
+    pub fn write_file(&self, result: &FileResult, path: &std::path::Path) -> Result<()> {
+        std::fs::write(path, self.render(result)?)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Writes the json output to `path` whatever the format, as a single object for one input
+    /// and an array otherwise.
+    pub fn write_json(&self, path: &std::path::Path) -> Result<()> {
+        let json = match self.results.as_slice() {
+            [result] => self.to_json(result),
+            results => results.iter().map(|r| self.to_json(r)).collect(),
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&json)?)?;
+        Ok(())
+    }
+
+    /// Writes one file per input in `dir`, named after the input file. All the outputs are first
+    /// written to temporary files which are only renamed once every output has been written.
+    pub fn write_dir(&self, dir: &std::path::Path) -> Result<Vec<std::path::PathBuf>> {
+        std::fs::create_dir_all(dir)?;
+        let mut paths = vec![];
+        for result in self.results.iter() {
+            let path = self.output_path(dir, &result.file);
+            let tmp_path = path.with_extension(format!("{}.tmp", self.format.extension()));
+            std::fs::write(&tmp_path, self.render(result)?)?;
+            paths.push((tmp_path, path));
+        }
+        let mut written = vec![];
+        for (tmp_path, path) in paths {
+            std::fs::rename(&tmp_path, &path)?;
+            written.push(path)
+        }
+        Ok(written)
+    }
+
+    /// Writes one entry per input in a zip archive, entries are named like the files written by
+    /// `write_dir`. The modification times are fixed so that the archive is reproducible.
+    pub fn write_zip(&self, path: &std::path::Path) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        let mut zip = zip::ZipWriter::new(std::io::BufWriter::new(file));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(zip::DateTime::default());
+        for result in self.results.iter() {
+            let name = self.output_path(std::path::Path::new(""), &result.file);
+            zip.start_file(name.to_string_lossy(), options)?;
+            std::io::Write::write_all(&mut zip, self.render(result)?.as_bytes())?;
+        }
+        zip.finish()?;
+        Ok(())
+    }
+
+    /// Same as `write_zip` but using a gzipped tar archive, entries have a zero modification time
+    /// so that the archive is reproducible.
+    pub fn write_tar_gz(&self, path: &std::path::Path) -> Result<()> {
+        use std::io::Write;
+
+        let file = std::fs::File::create(path)?;
+        let mut gz = flate2::write::GzEncoder::new(
+            std::io::BufWriter::new(file),
+            flate2::Compression::default(),
+        );
+        for result in self.results.iter() {
+            let name = self.output_path(std::path::Path::new(""), &result.file);
+            let data = self.render(result)?;
+            gz.write_all(&tar_header(&name.to_string_lossy(), data.len())?)?;
+            gz.write_all(data.as_bytes())?;
+            let padding = data.len().next_multiple_of(512) - data.len();
+            gz.write_all(&vec![0u8; padding])?;
+        }
+        // The end of the archive is marked by two empty blocks.
+        gz.write_all(&[0u8; 1024])?;
+        gz.finish()?.flush()?;
+        Ok(())
+    }
+
+    pub fn output_path(&self, dir: &std::path::Path, file: &str) -> std::path::PathBuf {
+        let stem = std::path::Path::new(file).file_stem().unwrap_or_default();
+        dir.join(stem).with_extension(self.format.extension())
+    }
+}
+
+/// Appends `<sha256>  <file name>` for each file to the `.checksums` file of its directory, in
+/// the format expected by `sha256sum --check`. Returns the checksum files that were updated.
+pub fn append_checksums(paths: &[std::path::PathBuf]) -> Result<Vec<std::path::PathBuf>> {
+    use std::io::Write;
+
+    let mut checksum_files: Vec<std::path::PathBuf> = vec![];
+    for path in paths.iter() {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &std::fs::read(path)?);
+        let hash = digest.as_ref().iter().map(|b| format!("{b:02x}"));
+        let hash = hash.collect::<String>();
+        let checksum_file = path.with_file_name(".checksums");
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&checksum_file)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        writeln!(file, "{hash}  {name}")?;
+        if !checksum_files.contains(&checksum_file) {
+            checksum_files.push(checksum_file)
+        }
+    }
+    Ok(checksum_files)
+}
+
+/// A ustar header for a regular file, owned by root with a zero modification time.
+fn tar_header(name: &str, size: usize) -> Result<[u8; 512]> {
+    if name.len() >= 100 {
+        anyhow::bail!("file name too long for a tar archive: {name}")
+    }
+    let mut header = [0u8; 512];
+    let mut set =
+        |offset: usize, value: &[u8]| header[offset..offset + value.len()].copy_from_slice(value);
+    set(0, name.as_bytes());
+    set(100, b"0000644\0");
+    set(108, b"0000000\0");
+    set(116, b"0000000\0");
+    set(124, format!("{size:011o}\0").as_bytes());
+    set(136, b"00000000000\0");
+    set(148, b"        ");
+    set(156, b"0");
+    set(257, b"ustar\0");
+    set(263, b"00");
+    let checksum = header.iter().map(|&b| b as u32).sum::<u32>();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(spans: &[(f64, f64)]) -> Vec<Word> {
+        let words = spans
+            .iter()
+            .enumerate()
+            .map(|(idx, &(start_time, stop_time))| Word {
+                text: format!("w{idx}"),
+                tokens: vec![],
+                start_time,
+                stop_time,
+                confidence: 1.,
+                turn: 0,
+            });
+        words.collect()
+    }
+
+    fn spans(words: &[Word]) -> Vec<(f64, f64)> {
+        words.iter().map(|w| (w.start_time, w.stop_time)).collect()
+    }
+
+    #[test]
+    fn smooth_overlapping_words() {
+        let mut overlapping = words(&[
+            // Starts before the previous stop, clamped.
+            (0.0, 0.5),
+            (0.4, 0.9),
+            // Within the previous word, which stops after it, split in the middle.
+            (1.0, 2.0),
+            (1.25, 1.75),
+            // Starts before the previous start.
+            (1.1, 2.5),
+            // Stops before it starts.
+            (3.0, 2.9),
+        ]);
+        smooth_timings(&mut overlapping);
+        let smoothed = spans(&overlapping);
+        assert_eq!(
+            smoothed,
+            [
+                (0.0, 0.5),
+                (0.5, 0.9),
+                (1.0, 1.5),
+                (1.5, 1.75),
+                (1.75, 2.5),
+                (3.0, 3.0)
+            ]
+        );
+        for (prev, next) in smoothed.iter().zip(smoothed.iter().skip(1)) {
+            assert!(prev.0 <= prev.1 && prev.1 <= next.0, "{smoothed:?}");
+        }
+
+        let mut clean = words(&[(0.0, 0.3), (0.5, 0.8), (0.8, 1.0)]);
+        smooth_timings(&mut clean);
+        assert_eq!(spans(&clean), [(0.0, 0.3), (0.5, 0.8), (0.8, 1.0)]);
+    }
+
+    #[test]
+    fn srt_cues_do_not_overlap() {
+        let mut transcript = Transcript {
+            words: words(&[(0.0, 1.2), (1.0, 1.5)]),
+            ..Default::default()
+        };
+        let srt = transcript.to_srt();
+        assert_eq!(srt, "1\n00:00:00,000 --> 00:00:01,500\nw0 w1\n\n");
+        // A gap splits the cues, the second one starts after the first one stops. w1 lies within
+        // w0 so they are split at 0.75, the cue stops with its last word w1.
+        transcript.words = words(&[(0.0, 3.0), (0.5, 1.0), (2.0, 2.5)]);
+        let srt = transcript.to_srt();
+        assert!(
+            srt.contains("00:00:00,000 --> 00:00:01,000\nw0 w1\n\n2\n00:00:02,000 --> "),
+            "{srt}"
+        );
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! The `--manifest` files listing the inputs of a batch run, either as a json array of entries
+//! or as tsv lines.
+use anyhow::{Context, Result};
+
+/// An input to transcribe, the options left unset fall back to the command line ones.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub path: String,
+    /// Where to write the transcript of this input, in `--format`.
+    #[serde(default)]
+    pub out_file: Option<std::path::PathBuf>,
+    /// Start of the audio to transcribe in seconds, overrides `--start`.
+    #[serde(default)]
+    pub start: Option<f64>,
+    /// End of the audio to transcribe in seconds, overrides `--end`.
+    #[serde(default)]
+    pub end: Option<f64>,
+}
+
+/// Reads the entries of a manifest, json when the extension is `.json` or the content starts
+/// with `[`, tsv otherwise.
+///
+/// The tsv columns are the path, the output file, the start and the end, the trailing ones can
+/// be omitted and empty fields are unset. Empty lines and lines starting with `#` are skipped,
+/// as is a header line starting with `path`.
+pub fn read(path: &std::path::Path) -> Result<Vec<Entry>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("cannot read the manifest {}", path.display()))?;
+    let json =
+        path.extension().is_some_and(|e| e == "json") || content.trim_start().starts_with('[');
+    let entries = if json {
+        serde_json::from_str(&content).map_err(anyhow::Error::from)
+    } else {
+        parse_tsv(&content)
+    };
+    let entries = entries.with_context(|| format!("invalid manifest {}", path.display()))?;
+    // The outputs are keyed by the input path.
+    for (idx, entry) in entries.iter().enumerate() {
+        if entries[..idx].iter().any(|e| e.path == entry.path) {
+            anyhow::bail!("{} is listed twice in {}", entry.path, path.display())
+        }
+    }
+    Ok(entries)
+}
+
+fn parse_tsv(content: &str) -> Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for (line_idx, line) in content.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split('\t').map(str::trim).collect::<Vec<_>>();
+        if entries.is_empty() && fields[0] == "path" {
+            continue;
+        }
+        if fields.len() > 4 {
+            anyhow::bail!("line {}: expected at most 4 columns", line_idx + 1)
+        }
+        let field = |idx: usize| fields.get(idx).copied().filter(|f| !f.is_empty());
+        let time = |idx: usize| {
+            field(idx)
+                .map(|f| f.parse::<f64>())
+                .transpose()
+                .with_context(|| format!("line {}: invalid time", line_idx + 1))
+        };
+        entries.push(Entry {
+            path: field(0)
+                .with_context(|| format!("line {}: no path", line_idx + 1))?
+                .into(),
+            out_file: field(1).map(Into::into),
+            start: time(2)?,
+            end: time(3)?,
+        })
+    }
+    Ok(entries)
+}
+
+/// Keeps the samples between `start` and `end` seconds.
+pub fn trim(
+    pcm: Vec<f32>,
+    sample_rate: usize,
+    start: Option<f64>,
+    end: Option<f64>,
+) -> Result<Vec<f32>> {
+    let duration = pcm.len() as f64 / sample_rate as f64;
+    let start = start.unwrap_or(0.);
+    let end = end.map_or(duration, |end| end.min(duration));
+    if start < 0. || start >= end {
+        anyhow::bail!("invalid range {start}s-{end}s for {duration:.2}s of audio")
+    }
+    let sample = |time: f64| ((time * sample_rate as f64) as usize).min(pcm.len());
+    Ok(pcm[sample(start)..sample(end)].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tsv() -> Result<()> {
+        let content = "path\tout_file\tstart\tend\n\
+                       # A comment.\n\
+                       a.wav\n\
+                       \n\
+                       b.wav\tb.txt\n\
+                       c.wav\t\t1.5\t10\n";
+        let entries = parse_tsv(content)?;
+        let entry = |path: &str, out_file: Option<&str>, start, end| Entry {
+            path: path.to_string(),
+            out_file: out_file.map(Into::into),
+            start,
+            end,
+        };
+        assert_eq!(
+            entries,
+            [
+                entry("a.wav", None, None, None),
+                entry("b.wav", Some("b.txt"), None, None),
+                entry("c.wav", None, Some(1.5), Some(10.)),
+            ]
+        );
+        assert!(parse_tsv("a.wav\t\tsoon\n").is_err());
+        assert!(parse_tsv("a.wav\t\t1\t2\t3\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn trim_ranges() -> Result<()> {
+        let pcm = (0..100).map(|i| i as f32).collect::<Vec<_>>();
+        assert_eq!(trim(pcm.clone(), 10, None, None)?, pcm);
+        assert_eq!(trim(pcm.clone(), 10, Some(2.), Some(3.))?, pcm[20..30]);
+        // The end is clamped to the duration of the audio.
+        assert_eq!(trim(pcm.clone(), 10, Some(9.5), Some(20.))?, pcm[95..]);
+        assert!(trim(pcm.clone(), 10, Some(3.), Some(2.)).is_err());
+        assert!(trim(pcm, 10, Some(10.), None).is_err());
+        Ok(())
+    }
+}
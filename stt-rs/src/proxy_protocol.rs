@@ -0,0 +1,185 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! PROXY protocol, v1 and v2, used by HAProxy or nginx to pass on the address of the client to
+//! the server behind them. The header comes first on the connection, before the TLS handshake.
+use anyhow::{Context, Result, bail};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// The longest v1 header, including the final CRLF.
+const V1_MAX_LEN: usize = 107;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Address of the client of a connection, the one from the PROXY header with `--proxy-protocol`
+/// and the peer of the TCP connection otherwise. Set as an extension on each request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// Reads the PROXY header at the start of `stream`, nothing past the header is consumed. Returns
+/// `None` when the proxy does not relay a client, e.g. for its own health checks.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix).await?;
+    if prefix == V1_PREFIX {
+        let mut line = prefix.to_vec();
+        // One byte at a time as the header has no length.
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                bail!("the PROXY v1 header is longer than {V1_MAX_LEN} bytes")
+            }
+            line.push(stream.read_u8().await?)
+        }
+        parse_v1(&line)
+    } else if prefix == V2_SIGNATURE[..6] {
+        let mut header = [0u8; 16];
+        header[..6].copy_from_slice(&prefix);
+        stream.read_exact(&mut header[6..]).await?;
+        let len = u16::from_be_bytes([header[14], header[15]]);
+        let mut addresses = vec![0u8; len as usize];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(&header, &addresses)
+    } else {
+        bail!("the connection does not start with a PROXY header")
+    }
+}
+
+/// Parses a v1 header, e.g. `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .context("invalid PROXY v1 header")?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        [
+            "PROXY",
+            protocol @ ("TCP4" | "TCP6"),
+            src,
+            _dst,
+            src_port,
+            _dst_port,
+        ] => {
+            let ip = src
+                .parse::<IpAddr>()
+                .with_context(|| format!("invalid source address in {line:?}"))?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                bail!("the source address does not match the protocol in {line:?}")
+            }
+            let port = src_port
+                .parse::<u16>()
+                .with_context(|| format!("invalid source port in {line:?}"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("invalid PROXY v1 header {line:?}"),
+    }
+}
+
+/// Parses a v2 header, `header` is the fixed part and `addresses` the variable one whose length
+/// is given by the last two bytes of `header`. The TLVs after the addresses are ignored.
+fn parse_v2(header: &[u8; 16], addresses: &[u8]) -> Result<Option<SocketAddr>> {
+    if &header[..12] != V2_SIGNATURE {
+        bail!("invalid PROXY v2 signature")
+    }
+    let (version, command) = (header[12] >> 4, header[12] & 0x0f);
+    if version != 2 {
+        bail!("unsupported PROXY protocol version {version}")
+    }
+    match command {
+        // LOCAL, a connection opened by the proxy itself.
+        0 => return Ok(None),
+        1 => {}
+        _ => bail!("unknown PROXY v2 command {command}"),
+    }
+    let truncated = || anyhow::anyhow!("truncated PROXY v2 addresses");
+    // The high nibble is the address family, the low one the transport.
+    let addr = match header[13] >> 4 {
+        // AF_UNSPEC and AF_UNIX, the client has no IP address.
+        0 | 3 => None,
+        1 => {
+            let a = addresses.get(..12).ok_or_else(truncated)?;
+            let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            Some(SocketAddr::new(ip.into(), u16::from_be_bytes([a[8], a[9]])))
+        }
+        2 => {
+            let a = addresses.get(..36).ok_or_else(truncated)?;
+            let ip = <[u8; 16]>::try_from(&a[..16]).expect("16 bytes");
+            let ip = Ipv6Addr::from(ip);
+            Some(SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([a[32], a[33]]),
+            ))
+        }
+        family => bail!("unknown PROXY v2 address family {family}"),
+    };
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn v1() -> Result<()> {
+        let addr = parse_v1(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n")?;
+        assert_eq!(addr, Some("192.0.2.1:56324".parse()?));
+        let addr = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")?;
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse()?));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n")?, None);
+        assert!(parse_v1(b"PROXY TCP4 2001:db8::1 192.0.2.2 56324 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_headers() -> Result<()> {
+        let mut addresses = vec![192, 0, 2, 1, 192, 0, 2, 2];
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        // A TLV, skipped.
+        addresses.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let mut stream = v2(1, 0x11, &addresses);
+        stream.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut stream = stream.as_slice();
+        assert_eq!(
+            read_header(&mut stream).await?,
+            Some("192.0.2.1:56324".parse()?)
+        );
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut stream = v2(0, 0x00, &[]);
+        stream.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut stream = stream.as_slice();
+        assert_eq!(read_header(&mut stream).await?, None);
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut stream = &b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET /"[..];
+        assert_eq!(
+            read_header(&mut stream).await?,
+            Some("192.0.2.1:56324".parse()?)
+        );
+        assert_eq!(stream, b"GET /");
+
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\n"[..]).await.is_err());
+        assert!(
+            read_header(&mut v2(1, 0x11, &[192, 0, 2, 1]).as_slice())
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
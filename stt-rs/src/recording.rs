@@ -0,0 +1,166 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Copies of the audio received by the server, written to `--record-audio-dir` as one raw file
+//! of little endian f32 samples at the sample rate of the model per session for auditing. The
+//! files can be played with `ffplay -f f32le -ar <SAMPLE RATE> -ac 1 <FILE>` and are deleted after
+//! `--record-audio-retention-hours`.
+use anyhow::{Context, Result};
+
+const EXTENSION: &str = "f32le.raw";
+
+/// How often the expired recordings are looked for.
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// The directory where the audio is recorded.
+#[derive(Debug)]
+pub struct Recorder {
+    dir: std::path::PathBuf,
+    /// The recordings last modified longer ago than this are deleted.
+    retention: std::time::Duration,
+}
+
+/// The audio of a session, the file is created along with the first samples so that the sessions
+/// without audio leave no file behind.
+#[derive(Debug)]
+pub struct Recording {
+    recorder: std::sync::Arc<Recorder>,
+    /// The request id, see `--request-id-header`.
+    id: String,
+    file: Option<std::io::BufWriter<std::fs::File>>,
+}
+
+impl Recorder {
+    pub fn new(dir: std::path::PathBuf, retention: std::time::Duration) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create the recording dir {}", dir.display()))?;
+        Ok(Self { dir, retention })
+    }
+
+    pub fn start(self: &std::sync::Arc<Self>, id: String) -> Recording {
+        Recording {
+            recorder: self.clone(),
+            id,
+            file: None,
+        }
+    }
+
+    /// Deletes the recordings older than the retention period, returns the number of files
+    /// deleted.
+    pub fn cleanup(&self) -> Result<usize> {
+        let now = std::time::SystemTime::now();
+        let mut deleted = 0;
+        let entries = std::fs::read_dir(&self.dir)
+            .with_context(|| format!("cannot list {}", self.dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let is_recording = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.ends_with(&format!(".{EXTENSION}")));
+            if !is_recording {
+                continue;
+            }
+            let modified = entry.metadata().and_then(|m| m.modified())?;
+            let age = now.duration_since(modified).unwrap_or_default();
+            if age > self.retention {
+                std::fs::remove_file(entry.path())
+                    .with_context(|| format!("cannot delete {}", entry.path().display()))?;
+                deleted += 1
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Runs `cleanup` now and then every `CLEANUP_INTERVAL`, the failures are only logged.
+    pub fn spawn_cleanup(self: std::sync::Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let recorder = self.clone();
+                match tokio::task::spawn_blocking(move || recorder.cleanup()).await {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(deleted)) => eprintln!("Deleted {deleted} expired audio recordings"),
+                    Ok(Err(err)) => eprintln!("Cannot delete the expired recordings: {err:#}"),
+                    Err(err) => eprintln!("Cannot delete the expired recordings: {err}"),
+                }
+            }
+        });
+    }
+}
+
+impl Recording {
+    /// Appends the samples to `<dir>/<session id>.f32le.raw`, see `archive::create_unique`.
+    pub fn write(&mut self, pcm: &[f32]) -> Result<()> {
+        use std::io::Write;
+
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => {
+                let recorder = &self.recorder;
+                let (file, _) = crate::archive::create_unique(&recorder.dir, &self.id, EXTENSION)?;
+                self.file.insert(std::io::BufWriter::new(file))
+            }
+        };
+        for sample in pcm {
+            file.write_all(&sample.to_le_bytes())?
+        }
+        Ok(())
+    }
+
+    /// Flushes the samples written so far.
+    pub fn finish(&mut self) -> Result<()> {
+        use std::io::Write;
+
+        match self.file.as_mut() {
+            Some(file) => Ok(file.flush()?),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_cleanup() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("stt-recording-{}", std::process::id()));
+        let recorder = std::sync::Arc::new(Recorder::new(
+            dir.clone(),
+            std::time::Duration::from_secs(3600),
+        )?);
+        let mut recording = recorder.start("../session".to_string());
+        recording.write(&[0.5, -1.])?;
+        recording.write(&[0.25])?;
+        recording.finish()?;
+        let path = dir.join("___session.f32le.raw");
+        let bytes = std::fs::read(&path)?;
+        let samples = bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>();
+        assert_eq!(samples, [0.5, -1., 0.25]);
+        // Sessions without audio have no recording.
+        drop(recorder.start("empty".to_string()));
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        let other = dir.join("notes.txt");
+        std::fs::write(&other, "kept")?;
+        assert_eq!(recorder.cleanup()?, 0);
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(7200);
+        for path in [&path, &other] {
+            std::fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(old)?;
+        }
+        assert_eq!(recorder.cleanup()?, 1);
+        assert!(!path.exists());
+        assert!(other.exists());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
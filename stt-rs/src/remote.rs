@@ -0,0 +1,206 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! `--remote`, the input files are decoded locally and streamed to the `/transcribe/ws` endpoint
+//! of a server started with `serve` rather than transcribed by a local model. A dropped
+//! connection is reopened with a backoff and the stream resumes after the last word received.
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt, TryFutureExt};
+use kyutai_stt_rs::client::server_sample_rate;
+use kyutai_stt_rs::resample::Resampling;
+use kyutai_stt_rs::stream::{AsrEvent, ClientMessage, SampleFormat};
+use tokio_tungstenite::tungstenite::{self, Message, protocol::frame::coding::CloseCode};
+
+use crate::Printer;
+
+/// Half a second of audio per message.
+const CHUNK_SECONDS: f64 = 0.5;
+
+const INITIAL_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+const MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(30);
+
+pub struct Remote {
+    /// The websocket url, e.g. `ws://localhost:8080/transcribe/ws`.
+    pub url: String,
+    /// Sent as a bearer token, for servers started with `--rest-api-key`.
+    pub api_key: Option<String>,
+    /// Number of reconnections attempted in a row before giving up.
+    pub retries: usize,
+    pub sample_format: SampleFormat,
+}
+
+/// Why a stream was interrupted.
+enum Failure {
+    /// The connection dropped or the server is busy, the stream can be resumed.
+    Transient(anyhow::Error),
+    Fatal(anyhow::Error),
+}
+
+/// The audio of a file, resampled to the sample rate announced by the server on connection.
+struct Audio<'a> {
+    pcm: &'a [f32],
+    sample_rate: usize,
+    /// The last resampled pcm along with its sample rate.
+    resampled: Option<(usize, Vec<f32>)>,
+}
+
+impl Audio<'_> {
+    fn at_rate(&mut self, sample_rate: usize) -> Result<&[f32]> {
+        let pcm = match self.resampled.take() {
+            Some((rate, pcm)) if rate == sample_rate => pcm,
+            _ => Resampling::new(sample_rate).apply(self.pcm.to_vec(), self.sample_rate)?,
+        };
+        Ok(&self.resampled.insert((sample_rate, pcm)).1)
+    }
+}
+
+impl Remote {
+    /// Transcribes pcm at `sample_rate`, the words are printed as they are received.
+    pub async fn transcribe(
+        &self,
+        pcm: &[f32],
+        sample_rate: usize,
+        printer: &mut Printer,
+    ) -> Result<()> {
+        let mut audio = Audio {
+            pcm,
+            sample_rate,
+            resampled: None,
+        };
+        // The audio before this time, in seconds, has been transcribed, a new connection resumes
+        // from there.
+        let mut resume_time = 0.;
+        let mut failures = 0;
+        loop {
+            let mut last_stop = None;
+            let on_event = |event: AsrEvent| match event {
+                AsrEvent::Word(word) => {
+                    printer.word(word.text, word.start_time, word.confidence)?;
+                    printer.end_word(word.stop_time)?;
+                    last_stop = Some(word.stop_time);
+                    Ok(())
+                }
+                AsrEvent::Endpoint { .. } => Ok(()),
+                AsrEvent::Error { message } => anyhow::bail!(message),
+            };
+            let err = match self.stream(&mut audio, resume_time, on_event).await {
+                Ok(()) => return Ok(()),
+                Err(Failure::Fatal(err)) => return Err(err),
+                Err(Failure::Transient(err)) => err,
+            };
+            if let Some(last_stop) = last_stop {
+                resume_time = last_stop;
+                failures = 0
+            }
+            if failures >= self.retries {
+                return Err(err.context(format!("giving up after {failures} reconnections")));
+            }
+            let backoff = INITIAL_BACKOFF
+                .saturating_mul(1 << failures.min(16))
+                .min(MAX_BACKOFF);
+            failures += 1;
+            eprintln!(
+                "Connection to {} lost: {err:#}, reconnecting in {}s",
+                self.url,
+                backoff.as_secs()
+            );
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    /// Streams the audio from `resume_time` over a single connection, `on_event` gets the events
+    /// of the server with their times from the start of the audio.
+    async fn stream(
+        &self,
+        audio: &mut Audio<'_>,
+        resume_time: f64,
+        mut on_event: impl FnMut(AsrEvent) -> Result<()>,
+    ) -> Result<(), Failure> {
+        use tungstenite::client::IntoClientRequest;
+
+        let mut request = self
+            .url
+            .as_str()
+            .into_client_request()
+            .map_err(|err| Failure::Fatal(err.into()))?;
+        if let Some(api_key) = self.api_key.as_ref() {
+            let value = format!("Bearer {api_key}")
+                .parse()
+                .map_err(|_| Failure::Fatal(anyhow::anyhow!("invalid api key")))?;
+            request.headers_mut().insert("authorization", value);
+        }
+        let connected = tokio_tungstenite::connect_async(request).await;
+        let (socket, response) = connected.map_err(|err| match &err {
+            // The request is rejected, e.g. for a wrong api key, rather than the server busy.
+            tungstenite::Error::Http(response)
+                if response.status().is_client_error()
+                    && response.status() != axum::http::StatusCode::TOO_MANY_REQUESTS =>
+            {
+                Failure::Fatal(err.into())
+            }
+            _ => Failure::Transient(err.into()),
+        })?;
+        let sample_rate = server_sample_rate(&response).map_err(Failure::Fatal)?;
+        let pcm = audio.at_rate(sample_rate).map_err(Failure::Fatal)?;
+        let resume_from = ((resume_time * sample_rate as f64) as usize).min(pcm.len());
+        let offset = resume_from as f64 / sample_rate as f64;
+        let pcm = &pcm[resume_from..];
+        let (mut sender, mut receiver) = socket.split();
+        let init = ClientMessage::Init {
+            sample_format: self.sample_format,
+        };
+        let init = serde_json::to_string(&init).map_err(|err| Failure::Fatal(err.into()))?;
+        let send_audio = async {
+            sender.send(Message::Text(init)).await?;
+            let chunk_samples = (CHUNK_SECONDS * sample_rate as f64) as usize;
+            for chunk in pcm.chunks(chunk_samples.max(1)) {
+                let data = self.sample_format.encode(chunk);
+                sender.send(Message::Binary(data)).await?;
+            }
+            sender.send(Message::Text("end".to_string())).await
+        };
+        let send_audio = send_audio.map_err(|err| Failure::Transient(err.into()));
+        let recv_events = async {
+            while let Some(msg) = receiver.next().await {
+                match msg.map_err(|err| Failure::Transient(err.into()))? {
+                    Message::Text(text) => {
+                        let event = serde_json::from_str(&text)
+                            .map_err(|err| Failure::Fatal(err.into()))?;
+                        let event = match event {
+                            AsrEvent::Word(mut word) => {
+                                word.start_time += offset;
+                                word.stop_time += offset;
+                                AsrEvent::Word(word)
+                            }
+                            AsrEvent::Endpoint { time, probability } => AsrEvent::Endpoint {
+                                time: time + offset,
+                                probability,
+                            },
+                            event => event,
+                        };
+                        on_event(event).map_err(Failure::Fatal)?
+                    }
+                    // The server closes the connection once the last word is out.
+                    Message::Close(None) => return Ok(()),
+                    Message::Close(Some(frame)) => {
+                        let err = anyhow::anyhow!("closed with {}: {}", frame.code, frame.reason);
+                        return match frame.code {
+                            CloseCode::Normal => Ok(()),
+                            // All the model streams are in use or the server is restarting.
+                            CloseCode::Again | CloseCode::Away | CloseCode::Error => {
+                                Err(Failure::Transient(err))
+                            }
+                            _ => Err(Failure::Fatal(err)),
+                        };
+                    }
+                    _ => {}
+                }
+            }
+            let err = anyhow::anyhow!("the connection was closed before the end of the stream");
+            Err(Failure::Transient(err))
+        };
+        tokio::try_join!(send_audio, recv_events)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Resampling of the decoded audio to the sample rate expected by the model, shared by the
+//! command line tool, the server and `Transcriber::transcribe`.
+use anyhow::Result;
+use rubato::Resampler;
+
+/// Tradeoff between the cost of the resampling and how much of the original spectrum is kept.
+/// The resampling happens once per file before the transcription so the choice only impacts the
+/// time to first word, not the streaming latency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ResampleQuality {
+    /// Cubic polynomial interpolation, the cheapest option but some aliasing remains which can
+    /// hurt on noisy recordings.
+    Fast,
+    /// The fft based resampler from kaudio, good quality at a small cost.
+    #[default]
+    Balanced,
+    /// Windowed sinc interpolation with a long filter, a sharper cutoff for archival material at
+    /// a few times the cost of `balanced`.
+    High,
+}
+
+/// Relative difference under which two sample rates are considered equal, some encoders report
+/// rates such as 24001Hz for 24kHz audio. The resulting drift is under half a second per hour.
+const SAMPLE_RATE_TOLERANCE: f64 = 1e-4;
+
+/// How the decoded audio is brought to the sample rate of the model.
+#[derive(Debug, Clone, Copy)]
+pub struct Resampling {
+    /// The sample rate of the model.
+    pub target_rate: usize,
+    pub quality: ResampleQuality,
+    /// Resample even if the input rate is within `SAMPLE_RATE_TOLERANCE` of the target.
+    pub always: bool,
+    /// Drop the filter delay left by the `balanced` resampler, see `resample`.
+    pub compensate_delay: bool,
+}
+
+impl Resampling {
+    /// Resampling to `target_rate` with the default options of the command line tool.
+    pub fn new(target_rate: usize) -> Self {
+        Self {
+            target_rate,
+            quality: ResampleQuality::default(),
+            always: false,
+            compensate_delay: false,
+        }
+    }
+
+    /// Whether audio at `sample_rate` can be fed to the model as is.
+    pub fn is_noop(&self, sample_rate: usize) -> bool {
+        if sample_rate == self.target_rate {
+            return true;
+        }
+        let diff = sample_rate.abs_diff(self.target_rate) as f64;
+        !self.always && diff <= self.target_rate as f64 * SAMPLE_RATE_TOLERANCE
+    }
+
+    pub fn apply(&self, pcm: Vec<f32>, sample_rate: usize) -> Result<Vec<f32>> {
+        if self.is_noop(sample_rate) {
+            return Ok(pcm);
+        }
+        resample(
+            &pcm,
+            sample_rate,
+            self.target_rate,
+            self.quality,
+            self.compensate_delay,
+        )
+    }
+}
+
+/// Resamples `pcm`, the output has exactly `pcm.len() * sr_out / sr_in` samples, rounded, and is
+/// aligned with the input. The exception is `balanced` without `compensate_delay`, the output is
+/// then the one of `kaudio::resample`, which is shifted by the filter delay.
+pub fn resample(
+    pcm: &[f32],
+    sr_in: usize,
+    sr_out: usize,
+    quality: ResampleQuality,
+    compensate_delay: bool,
+) -> Result<Vec<f32>> {
+    if sr_in == sr_out {
+        return Ok(pcm.to_vec());
+    }
+    let ratio = sr_out as f64 / sr_in as f64;
+    match quality {
+        ResampleQuality::Balanced if !compensate_delay => Ok(kaudio::resample(pcm, sr_in, sr_out)?),
+        ResampleQuality::Balanced => {
+            // Same resampler as `kaudio::resample`, which leaves the filter delay in the output.
+            let resampler = rubato::FftFixedInOut::new(sr_in, sr_out, 1024, 1)?;
+            let delay = resampler.output_delay();
+            run(resampler, pcm, ratio, delay)
+        }
+        ResampleQuality::Fast => {
+            let degree = rubato::PolynomialDegree::Cubic;
+            let resampler = rubato::FastFixedIn::new(ratio, 1., degree, 1024, 1)?;
+            let delay = resampler.output_delay();
+            run(resampler, pcm, ratio, delay)
+        }
+        ResampleQuality::High => {
+            let params = rubato::SincInterpolationParameters {
+                sinc_len: 256,
+                f_cutoff: 0.95,
+                oversampling_factor: 256,
+                interpolation: rubato::SincInterpolationType::Cubic,
+                window: rubato::WindowFunction::BlackmanHarris2,
+            };
+            let resampler = rubato::SincFixedIn::new(ratio, 1., params, 1024, 1)?;
+            // The sinc filter is centered on the first sample from the start so the output is
+            // already aligned, despite what `output_delay` reports.
+            run(resampler, pcm, ratio, 0)
+        }
+    }
+}
+
+/// Resamples audio that arrives in chunks of arbitrary size, using the same fft resampler as
+/// `ResampleQuality::Balanced`. The output is aligned with the input and, once `finish` has been
+/// called, has the same length as the one of `resample` with `compensate_delay`.
+pub struct StreamResampler {
+    /// `None` when the input is already at the target rate.
+    resampler: Option<rubato::FftFixedIn<f32>>,
+    ratio: f64,
+    /// Samples that do not fill a whole input chunk yet.
+    pcm: Vec<f32>,
+    output_buffer: Vec<Vec<f32>>,
+    /// Output samples still to be dropped to compensate the filter delay.
+    delay: usize,
+    len_in: usize,
+    len_out: usize,
+}
+
+impl StreamResampler {
+    pub fn new(resampling: &Resampling, sr_in: usize) -> Result<Self> {
+        let ratio = resampling.target_rate as f64 / sr_in as f64;
+        let (resampler, output_buffer, delay) = if resampling.is_noop(sr_in) {
+            (None, vec![], 0)
+        } else {
+            let resampler = rubato::FftFixedIn::new(sr_in, resampling.target_rate, 1024, 2, 1)?;
+            let output_buffer = resampler.output_buffer_allocate(true);
+            let delay = resampler.output_delay();
+            (Some(resampler), output_buffer, delay)
+        };
+        Ok(Self {
+            resampler,
+            ratio,
+            pcm: vec![],
+            output_buffer,
+            delay,
+            len_in: 0,
+            len_out: 0,
+        })
+    }
+
+    pub fn push(&mut self, pcm: &[f32]) -> Result<Vec<f32>> {
+        let Some(resampler) = self.resampler.as_mut() else {
+            return Ok(pcm.to_vec());
+        };
+        self.len_in += pcm.len();
+        self.pcm.extend_from_slice(pcm);
+        let mut pcm_out = vec![];
+        let mut pos_in = 0;
+        while pos_in + resampler.input_frames_next() <= self.pcm.len() {
+            let (in_len, len) = resampler.process_into_buffer(
+                &[&self.pcm[pos_in..]],
+                &mut self.output_buffer,
+                None,
+            )?;
+            pos_in += in_len;
+            pcm_out.extend_from_slice(&self.output_buffer[0][..len]);
+        }
+        self.pcm.drain(..pos_in);
+        Ok(self.output(pcm_out))
+    }
+
+    /// Flushes the samples held by the resampler, to be called once all the input has been
+    /// pushed.
+    pub fn finish(mut self) -> Result<Vec<f32>> {
+        let Some(resampler) = self.resampler.as_mut() else {
+            return Ok(vec![]);
+        };
+        let expected = (self.len_in as f64 * self.ratio).round() as usize;
+        let mut pcm_out = vec![];
+        if !self.pcm.is_empty() {
+            let input = [&self.pcm[..]];
+            let (_, len) = resampler.process_partial_into_buffer(
+                Some(&input),
+                &mut self.output_buffer,
+                None,
+            )?;
+            pcm_out.extend_from_slice(&self.output_buffer[0][..len]);
+        }
+        while self.len_out + pcm_out.len() < expected + self.delay {
+            let (_, len) = resampler.process_partial_into_buffer(
+                None::<&[&[f32]]>,
+                &mut self.output_buffer,
+                None,
+            )?;
+            if len == 0 {
+                break;
+            }
+            pcm_out.extend_from_slice(&self.output_buffer[0][..len]);
+        }
+        let len_out = self.len_out;
+        let mut pcm_out = self.output(pcm_out);
+        pcm_out.truncate(expected.saturating_sub(len_out));
+        Ok(pcm_out)
+    }
+
+    /// Drops what remains of the filter delay.
+    fn output(&mut self, mut pcm_out: Vec<f32>) -> Vec<f32> {
+        let skip = self.delay.min(pcm_out.len());
+        pcm_out.drain(..skip);
+        self.delay -= skip;
+        self.len_out += pcm_out.len();
+        pcm_out
+    }
+}
+
+/// Feeds the whole input through the resampler, the first `delay` output samples are dropped so
+/// that the timestamps are not shifted.
+fn run<R: Resampler<f32>>(
+    mut resampler: R,
+    pcm: &[f32],
+    ratio: f64,
+    delay: usize,
+) -> Result<Vec<f32>> {
+    let out_len = (pcm.len() as f64 * ratio).round() as usize;
+    let mut pcm_out = Vec::with_capacity(out_len + delay + 1024);
+    let mut output_buffer = resampler.output_buffer_allocate(true);
+    let mut pos_in = 0;
+    while pos_in + resampler.input_frames_next() <= pcm.len() {
+        let (in_len, len) =
+            resampler.process_into_buffer(&[&pcm[pos_in..]], &mut output_buffer, None)?;
+        pos_in += in_len;
+        pcm_out.extend_from_slice(&output_buffer[0][..len]);
+    }
+    if pos_in < pcm.len() {
+        let input = [&pcm[pos_in..]];
+        let (_, len) =
+            resampler.process_partial_into_buffer(Some(&input), &mut output_buffer, None)?;
+        pcm_out.extend_from_slice(&output_buffer[0][..len]);
+    }
+    // Flushes the samples still held by the filter.
+    while pcm_out.len() < out_len + delay {
+        let (_, len) =
+            resampler.process_partial_into_buffer(None::<&[&[f32]]>, &mut output_buffer, None)?;
+        if len == 0 {
+            break;
+        }
+        pcm_out.extend_from_slice(&output_buffer[0][..len]);
+    }
+    pcm_out.drain(..delay.min(pcm_out.len()));
+    pcm_out.truncate(out_len);
+    Ok(pcm_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUALITIES: [ResampleQuality; 3] = [
+        ResampleQuality::Fast,
+        ResampleQuality::Balanced,
+        ResampleQuality::High,
+    ];
+
+    fn tone(sample_rate: usize, len: usize) -> Vec<f32> {
+        let t = |i: usize| i as f32 / sample_rate as f32;
+        (0..len)
+            .map(|i| (2. * std::f32::consts::PI * 440. * t(i)).sin())
+            .collect()
+    }
+
+    #[test]
+    fn output_length() -> Result<()> {
+        for sr_in in [8_000, 16_000, 44_100, 48_000] {
+            // A few seconds and a length that is not a multiple of the chunk size.
+            for len in [3 * sr_in, sr_in / 3 + 7] {
+                let expected = (len as f64 * 24_000. / sr_in as f64).round() as usize;
+                for quality in QUALITIES {
+                    let pcm = resample(&tone(sr_in, len), sr_in, 24_000, quality, true)?;
+                    assert_eq!(pcm.len(), expected, "{sr_in}Hz {len} samples {quality:?}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The filter delay is removed, a tone that starts at the beginning of the input still
+    /// starts at the beginning of the output.
+    #[test]
+    fn no_delay() -> Result<()> {
+        let mut pcm = vec![0f32; 16_000];
+        pcm[8_000..].copy_from_slice(&tone(16_000, 8_000));
+        for quality in QUALITIES {
+            let pcm = resample(&pcm, 16_000, 24_000, quality, true)?;
+            let first = pcm.iter().position(|v| v.abs() > 0.1).unwrap_or(pcm.len());
+            assert!(
+                first.abs_diff(12_000) < 100,
+                "{quality:?} starts at {first}"
+            );
+        }
+        Ok(())
+    }
+
+    /// The default resampler is the one used before the resamplers could be picked.
+    #[test]
+    fn balanced_matches_kaudio() -> Result<()> {
+        for sr_in in [16_000, 44_100] {
+            let pcm = tone(sr_in, sr_in + 123);
+            let expected = kaudio::resample(&pcm, sr_in, 24_000)?;
+            let resampling = Resampling::new(24_000);
+            assert_eq!(resampling.apply(pcm, sr_in)?, expected, "{sr_in}Hz");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn near_matching_rates() -> Result<()> {
+        let resampling = Resampling::new(24_000);
+        assert!(resampling.is_noop(24_000));
+        assert!(resampling.is_noop(24_001));
+        assert!(!resampling.is_noop(24_100));
+        assert!(!resampling.is_noop(22_050));
+        let pcm = tone(24_001, 24_001);
+        assert_eq!(resampling.apply(pcm.clone(), 24_001)?, pcm);
+
+        let always = Resampling {
+            always: true,
+            ..resampling
+        };
+        assert!(always.is_noop(24_000));
+        assert!(!always.is_noop(24_001));
+        let aligned = Resampling {
+            compensate_delay: true,
+            ..always
+        };
+        assert_eq!(aligned.apply(pcm, 24_001)?.len(), 24_000);
+        Ok(())
+    }
+}
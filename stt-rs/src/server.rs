@@ -0,0 +1,2221 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! HTTP server, the model is owned by a dedicated thread, the model actor, which processes the
+//! transcription jobs one at a time and streams the words back to the request handlers.
+use anyhow::{Context, Result};
+use axum::response::sse::{Event, Sse};
+use futures_util::{SinkExt, StreamExt};
+use kyutai_stt_rs::stream::{AsrEvent, ClientMessage, SampleFormat, Transcriber, Word};
+
+#[derive(Debug, clap::Args)]
+pub struct ServeArgs {
+    /// The repo where to get the model from.
+    #[arg(long, default_value = "kyutai/stt-1b-en_fr-candle")]
+    hf_repo: String,
+
+    /// Run the model on cpu.
+    #[arg(long)]
+    cpu: bool,
+
+    /// Fail if the cuda or metal device cannot be initialized rather than falling back to the
+    /// cpu.
+    #[arg(long)]
+    strict_device: bool,
+
+    /// Number of streams transcribed concurrently, each has its own streaming state while the
+    /// weights are shared. On cpu the pool is capped to what the available memory can hold. Once
+    /// all the streams are in use, the WebSocket connections are closed with a `1013 Try Again
+    /// Later` frame while the other requests wait for a stream.
+    #[arg(long, default_value_t = 1)]
+    pool_size: usize,
+
+    /// Port to listen on.
+    #[arg(long, default_value_t = 8080)]
+    port: u16,
+
+    /// Address of the interface to listen on, e.g. `127.0.0.1` to only accept local clients.
+    /// All the interfaces are used by default.
+    #[arg(long, default_value_t = std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED))]
+    bind_address: std::net::IpAddr,
+
+    /// Listen on all the IPv6 interfaces, `[::]`, the IPv4 clients are also accepted through
+    /// IPv4-mapped addresses.
+    #[arg(long, conflicts_with = "bind_address")]
+    ipv6: bool,
+
+    /// Only accept IPv6 clients when listening on an IPv6 address, for IPv6-only clusters.
+    #[arg(long)]
+    ipv6_only: bool,
+
+    /// Listen on a unix domain socket at this path rather than on `--port`, for clients on the
+    /// same host.
+    #[arg(long, conflicts_with_all = ["port", "bind_address", "ipv6", "tls_cert", "proxy_protocol"])]
+    unix_socket: Option<std::path::PathBuf>,
+
+    /// Expect a PROXY protocol header, v1 or v2, at the start of each connection, as sent by
+    /// HAProxy or by nginx with `proxy_protocol on`, and take the address of the client from it.
+    /// The connections without a header are dropped so only set it behind such a proxy.
+    #[arg(long)]
+    proxy_protocol: bool,
+
+    /// Maximum number of concurrent connections to `/transcribe/ws`, the upgrade requests over
+    /// the limit are answered with a 503. Not limited by default.
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Maximum number of concurrent connections to `/transcribe/ws` from a single client IP, the
+    /// upgrade requests over the limit are answered with a 429. Behind a proxy, this requires
+    /// `--proxy-protocol` for the address of the clients to be known.
+    #[arg(long, env = "MAX_CONNECTIONS_PER_IP")]
+    max_connections_per_ip: Option<usize>,
+
+    /// Uploads larger than this number of bytes are rejected.
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    max_upload_bytes: usize,
+
+    /// Maximum time in seconds spent on a `/transcribe` request, including the time spent
+    /// waiting for the model.
+    #[arg(long, default_value_t = 300)]
+    request_timeout: u64,
+
+    /// Comma separated list of the origins allowed to call the API from a browser, e.g.
+    /// `https://app.example.com`. `*` allows all origins, no cross-origin request is allowed if
+    /// not set.
+    #[arg(long, value_delimiter = ',')]
+    rest_cors_origins: Vec<String>,
+
+    /// Require the requests to carry an `Authorization: Bearer <KEY>` header with this key.
+    #[arg(long, env = "REST_API_KEY", hide_env_values = true)]
+    rest_api_key: Option<String>,
+
+    /// Compress the json responses for the clients that accept it. The streamed responses are
+    /// never compressed so that the events are not held back.
+    #[arg(long, value_enum)]
+    response_compression: Option<ResponseCompression>,
+
+    /// Format of the error responses when the `Accept` header of the request does not prefer one
+    /// of them: `{"error": "message", "code": "ERROR_CODE"}` or the bare message.
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Json)]
+    error_format: ErrorFormat,
+
+    /// Responses smaller than this number of bytes are sent uncompressed.
+    #[arg(long, default_value_t = 1024)]
+    min_size_for_compression: usize,
+
+    /// Header carrying the id used to correlate the requests with the server logs. The id is
+    /// taken from the request when set, generated otherwise, and echoed in the response.
+    #[arg(long, default_value = "X-Request-ID")]
+    request_id_header: String,
+
+    /// Number of `/transcribe` results kept to answer the repeated uploads of the same audio
+    /// without running the model, 0 disables the cache. The uploads are then buffered before
+    /// being transcribed.
+    #[arg(long, default_value_t = 100)]
+    dedupe_cache_size: usize,
+
+    /// Write each completed transcript to `<DIR>/<session id>.json` along with the client IP, the
+    /// model version, the duration of the audio and the time of the transcription, as a server-side
+    /// audit trail. The session id is the request id. The transcripts answered from the dedupe
+    /// cache are not archived again.
+    #[arg(long, env = "ARCHIVE_DIR")]
+    archive_dir: Option<std::path::PathBuf>,
+
+    /// Write the audio received in each session to `<DIR>/<session id>.f32le.raw`, as little
+    /// endian f32 samples at the sample rate of the model, as a server-side audit trail. The
+    /// recordings can be played with `ffplay -f f32le -ar <SAMPLE RATE> -ac 1 <FILE>`.
+    #[arg(long, env = "RECORD_AUDIO_DIR")]
+    record_audio_dir: Option<std::path::PathBuf>,
+
+    /// The audio recordings are deleted once they have not been written to for this number of
+    /// hours, the expired ones are looked for on startup and then every hour.
+    #[arg(long, default_value_t = 24 * 7)]
+    record_audio_retention_hours: u64,
+
+    /// Serve `GET /admin/sessions`, the list of the transcriptions in progress,
+    /// `DELETE /admin/sessions/<ID>`, which terminates one, and `POST /admin/reload-model`, which
+    /// loads the weights again, to the requests with an `Authorization: Bearer <TOKEN>` header
+    /// carrying this token.
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Debugging aid: the audio received on `/transcribe/ws` is buffered for the whole session and
+    /// sent back to the client as a mono wav file at the sample rate of the model, in a binary
+    /// message, once the last word is out, so that clients can check that their audio arrives
+    /// intact. The memory used grows with the length of the sessions.
+    #[arg(long)]
+    replay_pcm: bool,
+
+    /// PEM file with the certificate chain, the API is served over HTTPS when set.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<std::path::PathBuf>,
+
+    /// PEM file with the private key of the certificate.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<std::path::PathBuf>,
+
+    /// On SIGTERM or ctrl-c, new connections are refused and the jobs in flight are given this
+    /// number of seconds to complete before being cancelled.
+    #[arg(long, default_value_t = 30)]
+    graceful_shutdown_timeout: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ResponseCompression {
+    Gzip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ErrorFormat {
+    Json,
+    Text,
+}
+
+#[derive(Clone)]
+struct AppState {
+    actor: ModelActor,
+    request_timeout: std::time::Duration,
+    max_upload_bytes: usize,
+    dedupe_cache: Option<std::sync::Arc<std::sync::Mutex<TranscriptCache>>>,
+    ws_connections: WsConnections,
+    archive: Option<std::sync::Arc<crate::archive::Archive>>,
+    sessions: SessionManager,
+    reload: ModelReload,
+    replay_pcm: bool,
+}
+
+/// What `POST /admin/reload-model` needs to load the model again.
+#[derive(Clone)]
+struct ModelReload {
+    hf_repo: String,
+    cpu: bool,
+    strict_device: bool,
+    pool_size: usize,
+    in_progress: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl ModelReload {
+    /// Loads the model and hands the jobs over to the new transcribers, the jobs running on the
+    /// previous ones are completed with them. Returns the version of the new model.
+    fn run(&self, actor: &ModelActor) -> Result<String> {
+        let device = kyutai_stt_rs::device(self.cpu, self.strict_device)?;
+        eprintln!("Reloading the model from repository: {}", self.hf_repo);
+        let measure_memory = device.is_cpu();
+        let transcriber = Transcriber::load_on(&self.hf_repo, device)?;
+        // The clients and the upload decoding are set up for the sample rate of the current model.
+        if transcriber.sample_rate() != actor.sample_rate {
+            anyhow::bail!(
+                "the model expects {}Hz audio rather than {}Hz, restart the server to load it",
+                transcriber.sample_rate(),
+                actor.sample_rate
+            )
+        }
+        let transcribers = model_pool(transcriber, self.pool_size, measure_memory)?;
+        actor.start_workers(transcribers);
+        Ok(model_version(&self.hf_repo))
+    }
+}
+
+/// The repo along with the commit of the files in the Hugging Face cache, e.g.
+/// `kyutai/stt-1b-en_fr-candle@0123abcd...`, or the repo alone if it cannot be resolved.
+fn model_version(hf_repo: &str) -> String {
+    let config = hf_hub::api::sync::Api::new()
+        .ok()
+        .and_then(|api| api.model(hf_repo.to_string()).get("config.json").ok());
+    // The files are stored as `snapshots/<commit>/<file>`.
+    let commit = config.as_deref().and_then(|config| {
+        let snapshot = config.parent()?.file_name()?;
+        snapshot.to_str().map(str::to_string)
+    });
+    match commit {
+        Some(commit) => format!("{hf_repo}@{commit}"),
+        None => hf_repo.to_string(),
+    }
+}
+
+impl AppState {
+    /// Registers the transcription of a request with the session manager.
+    fn start_session(
+        &self,
+        request_id: Option<axum::Extension<RequestId>>,
+        client: Option<axum::Extension<crate::proxy_protocol::ClientAddr>>,
+    ) -> SessionGuard {
+        let id = request_id.map_or_else(String::new, |id| id.0.0);
+        self.sessions
+            .register(id, client.map(|client| client.0.0.ip()))
+    }
+
+    /// The session under which the transcript of a request is archived, if archiving.
+    fn archive_session(
+        &self,
+        route: &'static str,
+        session: &SessionGuard,
+    ) -> Option<crate::archive::Session> {
+        Some(crate::archive::Session {
+            archive: self.archive.clone()?,
+            id: session.info.id.clone(),
+            route,
+            client_ip: session.info.client_ip,
+        })
+    }
+}
+
+/// The transcriptions in progress, listed by `GET /admin/sessions`.
+#[derive(Clone, Default)]
+struct SessionManager {
+    next_key: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    /// Keyed by registration order, the request ids are not unique as they can be set by the
+    /// clients.
+    sessions: std::sync::Arc<
+        std::sync::Mutex<std::collections::BTreeMap<u64, std::sync::Arc<SessionInfo>>>,
+    >,
+}
+
+#[derive(Debug)]
+struct SessionInfo {
+    /// The request id.
+    id: String,
+    client_ip: Option<std::net::IpAddr>,
+    started_at: std::time::SystemTime,
+    /// Number of samples run through the model, updated by the model thread.
+    samples: std::sync::atomic::AtomicU64,
+    words: std::sync::atomic::AtomicU64,
+    state: tokio::sync::watch::Sender<SessionState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionState {
+    Running,
+    /// Set by `DELETE /admin/sessions/<ID>`, the job stops at the next chunk.
+    Terminating,
+    /// The session has been removed from the manager.
+    Closed,
+}
+
+impl SessionInfo {
+    fn is_terminating(&self) -> bool {
+        *self.state.borrow() != SessionState::Running
+    }
+}
+
+impl SessionManager {
+    fn register(&self, id: String, client_ip: Option<std::net::IpAddr>) -> SessionGuard {
+        let key = self
+            .next_key
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let info = std::sync::Arc::new(SessionInfo {
+            id,
+            client_ip,
+            started_at: std::time::SystemTime::now(),
+            samples: Default::default(),
+            words: Default::default(),
+            state: tokio::sync::watch::Sender::new(SessionState::Running),
+        });
+        self.sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key, info.clone());
+        SessionGuard {
+            sessions: self.clone(),
+            key,
+            info,
+        }
+    }
+
+    /// The sessions with this id, there can be several as the clients can reuse request ids.
+    fn find(&self, id: &str) -> Vec<std::sync::Arc<SessionInfo>> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let sessions = sessions.values().filter(|info| info.id == id);
+        sessions.cloned().collect()
+    }
+
+    /// The sessions as listed by `GET /admin/sessions`, oldest first. `sample_rate` is the one of
+    /// the model.
+    fn list(&self, sample_rate: usize) -> Vec<serde_json::Value> {
+        use std::sync::atomic::Ordering;
+
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions
+            .values()
+            .map(|info| {
+                let samples = info.samples.load(Ordering::Relaxed);
+                serde_json::json!({
+                    "session_id": info.id,
+                    "client_ip": info.client_ip,
+                    "started_at": rfc3339(info.started_at),
+                    "audio_seconds_processed": samples as f64 / sample_rate as f64,
+                    "words_emitted": info.words.load(Ordering::Relaxed),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Removes the session from the manager once dropped.
+struct SessionGuard {
+    sessions: SessionManager,
+    key: u64,
+    info: std::sync::Arc<SessionInfo>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut sessions = self
+            .sessions
+            .sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        sessions.remove(&self.key);
+        self.info.state.send_replace(SessionState::Closed);
+    }
+}
+
+/// A UTC time as `2024-01-31T12:34:56Z`.
+fn rfc3339(time: std::time::SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // The civil date from the number of days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Number of open WebSocket connections, checked against `--max-connections`, and per client
+/// IP, checked against `--max-connections-per-ip`.
+#[derive(Clone)]
+struct WsConnections {
+    count: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    max: Option<usize>,
+    /// Only tracked with a per IP limit, the IPs without connections are removed.
+    per_ip: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<std::net::IpAddr, usize>>>,
+    max_per_ip: Option<usize>,
+}
+
+impl WsConnections {
+    fn new(max: Option<usize>, max_per_ip: Option<usize>) -> Self {
+        Self {
+            count: Default::default(),
+            max,
+            per_ip: Default::default(),
+            max_per_ip,
+        }
+    }
+
+    /// Counts a new connection from `ip`, fails with a 503 when the server is at its limit and
+    /// with a 429 when the client is. The connection is counted until the slot is dropped.
+    fn acquire(&self, ip: Option<std::net::IpAddr>) -> Result<WsConnectionSlot, AppError> {
+        use axum::http::StatusCode;
+
+        let count = self.count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        // Created before the checks so that the connection is uncounted on rejection too.
+        let mut slot = WsConnectionSlot {
+            connections: self.clone(),
+            ip: None,
+        };
+        if self.max.is_some_and(|max| count >= max) {
+            let err = anyhow::anyhow!("too many WebSocket connections, retry later");
+            return Err(AppError::new(StatusCode::SERVICE_UNAVAILABLE, err));
+        }
+        if let (Some(ip), Some(max_per_ip)) = (ip, self.max_per_ip) {
+            // The IPv4 clients of a dual stack socket have IPv4-mapped addresses.
+            let ip = ip.to_canonical();
+            let mut per_ip = self.per_ip.lock().unwrap_or_else(|e| e.into_inner());
+            let count = per_ip.entry(ip).or_default();
+            if *count >= max_per_ip {
+                let err = anyhow::anyhow!("too many WebSocket connections from {ip}");
+                return Err(AppError::new(StatusCode::TOO_MANY_REQUESTS, err));
+            }
+            *count += 1;
+            slot.ip = Some(ip);
+        }
+        Ok(slot)
+    }
+}
+
+struct WsConnectionSlot {
+    connections: WsConnections,
+    /// Set when the connection is counted for its IP.
+    ip: Option<std::net::IpAddr>,
+}
+
+impl Drop for WsConnectionSlot {
+    fn drop(&mut self) {
+        let connections = &self.connections;
+        connections
+            .count
+            .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        if let Some(ip) = self.ip {
+            // The counts stay consistent even if another thread panicked with the lock held.
+            let mut per_ip = connections.per_ip.lock().unwrap_or_else(|e| e.into_inner());
+            if let std::collections::hash_map::Entry::Occupied(mut count) = per_ip.entry(ip) {
+                *count.get_mut() -= 1;
+                if *count.get() == 0 {
+                    count.remove();
+                }
+            }
+        }
+    }
+}
+
+/// The most recent transcripts, keyed by the SHA-256 of the audio file.
+struct TranscriptCache {
+    capacity: usize,
+    /// From the least to the most recently used.
+    entries: std::collections::VecDeque<([u8; 32], Vec<Word>)>,
+}
+
+impl TranscriptCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: std::collections::VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn get(&mut self, key: &[u8; 32]) -> Option<Vec<Word>> {
+        let idx = self.entries.iter().position(|(k, _)| k == key)?;
+        let entry = self.entries.remove(idx)?;
+        let transcript = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(transcript)
+    }
+
+    fn insert(&mut self, key: [u8; 32], transcript: Vec<Word>) {
+        self.entries.retain(|(k, _)| *k != key);
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, transcript))
+    }
+}
+
+struct Job {
+    /// The audio of the job, the job is over once all the senders have been dropped.
+    pcm: tokio::sync::mpsc::UnboundedReceiver<Vec<f32>>,
+    events: tokio::sync::mpsc::UnboundedSender<AsrEvent>,
+    /// Sent once all the audio has been transcribed.
+    stats: Option<tokio::sync::oneshot::Sender<JobStats>>,
+    /// The progress of the job is reported there.
+    session: std::sync::Arc<SessionInfo>,
+    /// Set with `--record-audio-dir`.
+    recording: Option<crate::recording::Recording>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct JobStats {
+    /// Duration of the audio received, without the padding added to flush the model.
+    audio_seconds: f64,
+    /// Time spent running the model, not counting the time spent waiting for the audio.
+    processing_time: std::time::Duration,
+}
+
+impl JobStats {
+    /// The `X-Processing-Time` (ms), `X-Audio-Duration` (s) and `X-RTF` response headers.
+    fn headers(&self) -> [(&'static str, String); 3] {
+        let processing_time = self.processing_time.as_secs_f64();
+        let rtf = if self.audio_seconds > 0. {
+            processing_time / self.audio_seconds
+        } else {
+            0.
+        };
+        [
+            (
+                "x-processing-time",
+                self.processing_time.as_millis().to_string(),
+            ),
+            ("x-audio-duration", format!("{:.3}", self.audio_seconds)),
+            ("x-rtf", format!("{rtf:.3}")),
+        ]
+    }
+}
+
+/// How often the idle model threads check whether their transcriber has been replaced.
+const RETIRED_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Handle on the model thread, cloning it is cheap and all the clones share the same model.
+#[derive(Clone)]
+struct ModelActor {
+    jobs: std::sync::mpsc::Sender<Job>,
+    /// Shared by the model threads, the lock is only held while waiting for a job.
+    pending: std::sync::Arc<std::sync::Mutex<std::sync::mpsc::Receiver<Job>>>,
+    /// Number of jobs that can run concurrently, one per transcriber of the pool.
+    capacity: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    /// Bumped when the transcribers are replaced, the threads of the previous ones then exit.
+    generation: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    /// Set once a transcription has succeeded, starting with the warmup one.
+    ready: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    /// Number of jobs queued or running.
+    in_flight: std::sync::Arc<tokio::sync::watch::Sender<usize>>,
+    /// Set when the jobs in flight are abandoned on shutdown, the running one stops at the next
+    /// chunk and the queued ones are not started.
+    cancel: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    /// The sample rate of the pcm sent to the jobs, the one of the model.
+    sample_rate: usize,
+    /// Where the audio of the jobs is recorded, see `--record-audio-dir`.
+    recorder: Option<std::sync::Arc<crate::recording::Recorder>>,
+}
+
+impl ModelActor {
+    fn spawn(
+        transcribers: Vec<Transcriber>,
+        sample_rate: usize,
+        recorder: Option<std::sync::Arc<crate::recording::Recorder>>,
+    ) -> Self {
+        let (jobs, pending) = std::sync::mpsc::channel::<Job>();
+        let actor = Self {
+            jobs,
+            pending: std::sync::Arc::new(std::sync::Mutex::new(pending)),
+            capacity: Default::default(),
+            generation: Default::default(),
+            ready: Default::default(),
+            in_flight: std::sync::Arc::new(tokio::sync::watch::Sender::new(0)),
+            cancel: Default::default(),
+            sample_rate,
+            recorder,
+        };
+        actor.start_workers(transcribers);
+        actor
+    }
+
+    /// Runs each transcriber on its own thread, the jobs go to the first thread available. The
+    /// threads of the previous transcribers, if any, exit once they are done with their job.
+    fn start_workers(&self, transcribers: Vec<Transcriber>) {
+        use std::sync::atomic::Ordering;
+        use std::sync::mpsc::RecvTimeoutError;
+
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.capacity.store(transcribers.len(), Ordering::SeqCst);
+        for mut transcriber in transcribers {
+            let actor = self.clone();
+            std::thread::spawn(move || {
+                // A second of silence so that the server reports ready without waiting for a
+                // first request, which would not come while it is not ready.
+                let sample_rate = transcriber.sample_rate();
+                match transcriber.transcribe(&vec![0.; sample_rate], sample_rate) {
+                    Ok(_) => actor.ready.store(true, Ordering::Relaxed),
+                    Err(err) => eprintln!("Warmup transcription failed: {err:#}"),
+                }
+                loop {
+                    let pending = actor.pending.lock().unwrap_or_else(|e| e.into_inner());
+                    let job = pending.recv_timeout(RETIRED_CHECK_INTERVAL);
+                    drop(pending);
+                    let retired = actor.generation.load(Ordering::SeqCst) != generation;
+                    let mut job = match job {
+                        // Handed over to the current transcribers.
+                        Ok(job) if retired => {
+                            let _ = actor.jobs.send(job);
+                            break;
+                        }
+                        Ok(job) => job,
+                        Err(RecvTimeoutError::Timeout) if retired => break,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    match run_job(&mut transcriber, &mut job, &actor.cancel) {
+                        Ok(()) => actor.ready.store(true, Ordering::Relaxed),
+                        Err(err) => {
+                            let message = format!("{err:#}");
+                            let _ = job.events.send(AsrEvent::Error { message });
+                        }
+                    }
+                    actor.in_flight.send_modify(|n| *n -= 1);
+                }
+            });
+        }
+    }
+
+    /// Waits for the jobs queued or running to be over.
+    async fn drain(&self) {
+        let mut in_flight = self.in_flight.subscribe();
+        let _ = in_flight.wait_for(|&n| n == 0).await;
+    }
+
+    /// Queues the transcription of the audio received on `pcm`, the words are sent on the
+    /// returned channel as they are decoded and the channel is closed once the job is over. The
+    /// stats of the job are sent just before.
+    fn start_with_stats(
+        &self,
+        pcm: tokio::sync::mpsc::UnboundedReceiver<Vec<f32>>,
+        session: &SessionGuard,
+    ) -> Result<(
+        tokio::sync::mpsc::UnboundedReceiver<AsrEvent>,
+        tokio::sync::oneshot::Receiver<JobStats>,
+    )> {
+        self.in_flight.send_modify(|n| *n += 1);
+        self.queue(pcm, session)
+    }
+
+    /// Same as `start_with_stats` when a transcriber is available, `None` rather than waiting
+    /// otherwise.
+    fn try_start(
+        &self,
+        pcm: tokio::sync::mpsc::UnboundedReceiver<Vec<f32>>,
+        session: &SessionGuard,
+    ) -> Result<Option<tokio::sync::mpsc::UnboundedReceiver<AsrEvent>>> {
+        let available = self.in_flight.send_if_modified(|n| {
+            let capacity = self.capacity.load(std::sync::atomic::Ordering::SeqCst);
+            let available = *n < capacity;
+            if available {
+                *n += 1
+            }
+            available
+        });
+        if !available {
+            return Ok(None);
+        }
+        let (events, _) = self.queue(pcm, session)?;
+        Ok(Some(events))
+    }
+
+    /// Queues a job that has already been counted in `in_flight`.
+    fn queue(
+        &self,
+        pcm: tokio::sync::mpsc::UnboundedReceiver<Vec<f32>>,
+        session: &SessionGuard,
+    ) -> Result<(
+        tokio::sync::mpsc::UnboundedReceiver<AsrEvent>,
+        tokio::sync::oneshot::Receiver<JobStats>,
+    )> {
+        let (events, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (stats, stats_rx) = tokio::sync::oneshot::channel();
+        let job = Job {
+            pcm,
+            events,
+            stats: Some(stats),
+            session: session.info.clone(),
+            recording: self
+                .recorder
+                .as_ref()
+                .map(|recorder| recorder.start(session.info.id.clone())),
+        };
+        if self.jobs.send(job).is_err() {
+            self.in_flight.send_modify(|n| *n -= 1);
+            anyhow::bail!("the model thread has stopped")
+        }
+        Ok((rx, stats_rx))
+    }
+
+    /// Transcribes an uploaded audio file, the audio is decoded and transcribed as it is
+    /// received.
+    fn transcribe_upload(
+        &self,
+        headers: axum::http::HeaderMap,
+        body: axum::body::Body,
+        max_upload_bytes: usize,
+        session: SessionGuard,
+    ) -> Result<UploadJob> {
+        let (pcm_tx, pcm_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (events, stats) = self.start_with_stats(pcm_rx, &session)?;
+        let decoding =
+            crate::upload::start(headers, body, max_upload_bytes, self.sample_rate, pcm_tx);
+        Ok(UploadJob {
+            events,
+            decoding,
+            stats,
+            session,
+        })
+    }
+}
+
+struct UploadJob {
+    events: tokio::sync::mpsc::UnboundedReceiver<AsrEvent>,
+    /// Resolves once the whole upload has been decoded.
+    decoding: tokio::task::JoinHandle<Result<()>>,
+    stats: tokio::sync::oneshot::Receiver<JobStats>,
+    /// Listed until the events have all been consumed.
+    session: SessionGuard,
+}
+
+fn run_job(
+    transcriber: &mut Transcriber,
+    job: &mut Job,
+    cancel: &std::sync::atomic::AtomicBool,
+) -> Result<()> {
+    let cancelled = || cancel.load(std::sync::atomic::Ordering::Relaxed);
+    if cancelled() {
+        anyhow::bail!("the server is shutting down")
+    }
+    transcriber.reset()?;
+    let events = job.events.clone();
+    let session = job.session.clone();
+    let send_words = |transcriber: &mut Transcriber| {
+        while let Some(event) = transcriber.next_event() {
+            if let AsrEvent::Word(_) = event {
+                session
+                    .words
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            // The client has gone away, there is no point in finishing the job.
+            if events.send(event).is_err() {
+                return false;
+            }
+        }
+        true
+    };
+    let mut stats = JobStats::default();
+    while let Some(pcm) = job.pcm.blocking_recv() {
+        stats.audio_seconds += pcm.len() as f64 / transcriber.sample_rate() as f64;
+        record(job, &pcm);
+        for chunk in pcm.chunks(transcriber.frame_size()) {
+            if cancelled() {
+                anyhow::bail!("the server is shutting down")
+            }
+            if job.session.is_terminating() {
+                anyhow::bail!("the session has been terminated")
+            }
+            let start_time = std::time::Instant::now();
+            transcriber.push_pcm(chunk)?;
+            stats.processing_time += start_time.elapsed();
+            let samples = chunk.len() as u64;
+            job.session
+                .samples
+                .fetch_add(samples, std::sync::atomic::Ordering::Relaxed);
+            if !send_words(transcriber) {
+                return Ok(());
+            }
+        }
+    }
+    if let Some(Err(err)) = job.recording.as_mut().map(|r| r.finish()) {
+        eprintln!("[{}] Cannot record the audio: {err:#}", job.session.id)
+    }
+    let start_time = std::time::Instant::now();
+    transcriber.flush()?;
+    stats.processing_time += start_time.elapsed();
+    send_words(transcriber);
+    if let Some(stats_tx) = job.stats.take() {
+        let _ = stats_tx.send(stats);
+    }
+    Ok(())
+}
+
+/// Appends the audio to the recording of the job, a failure is logged and ends the recording
+/// rather than the job.
+fn record(job: &mut Job, pcm: &[f32]) {
+    let Some(recording) = job.recording.as_mut() else {
+        return;
+    };
+    if let Err(err) = recording.write(pcm) {
+        eprintln!("[{}] Cannot record the audio: {err:#}", job.session.id);
+        job.recording = None
+    }
+}
+
+struct AppError {
+    status: axum::http::StatusCode,
+    err: anyhow::Error,
+}
+
+impl AppError {
+    fn new(status: axum::http::StatusCode, err: anyhow::Error) -> Self {
+        Self { status, err }
+    }
+}
+
+/// The message of an error response, kept as an extension so that `render_errors` can format
+/// the response according to the request.
+#[derive(Debug, Clone)]
+struct ErrorMessage(String);
+
+impl ErrorMessage {
+    /// The json body, the code is the name of the status, e.g. `PAYLOAD_TOO_LARGE`.
+    fn json(&self, status: axum::http::StatusCode) -> serde_json::Value {
+        let code = status
+            .canonical_reason()
+            .unwrap_or("ERROR")
+            .to_uppercase()
+            .replace([' ', '-'], "_");
+        serde_json::json!({ "error": self.0, "code": code })
+    }
+}
+
+impl axum::response::IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        let message = ErrorMessage(format!("{:#}", self.err));
+        let body = axum::Json(message.json(self.status));
+        (self.status, axum::Extension(message), body).into_response()
+    }
+}
+
+/// Errors are attributed to the request unless stated otherwise.
+impl<E: Into<anyhow::Error>> From<E> for AppError {
+    fn from(err: E) -> Self {
+        let err = err.into();
+        let status = if err.is::<crate::upload::UploadTooLarge>() {
+            axum::http::StatusCode::PAYLOAD_TOO_LARGE
+        } else {
+            axum::http::StatusCode::BAD_REQUEST
+        };
+        Self::new(status, err)
+    }
+}
+
+/// `POST /transcribe`, the body is an audio file, either raw or as the first part of a multipart
+/// upload. The audio is transcribed while it is uploaded, the whole transcript is returned as
+/// json once the transcription is over. With the dedupe cache, the upload is buffered and the
+/// transcript of an audio file that has already been transcribed is returned right away, the
+/// `X-Cache` header tells whether that was the case. The `Accept` header selects the format of the
+/// transcript, see `ResponseFormat`. The responses of the uploads that went through the model
+/// have the headers of `JobStats`. With the dedupe cache, the responses also have an `ETag` and
+/// a request whose `If-None-Match` matches it gets a 304 without a body.
+async fn transcribe(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request_id: Option<axum::Extension<RequestId>>,
+    client: Option<axum::Extension<crate::proxy_protocol::ClientAddr>>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Body,
+) -> Result<axum::response::Response, AppError> {
+    use axum::response::IntoResponse;
+
+    crate::upload::check_content_length(&headers, state.max_upload_bytes)?;
+    let format = ResponseFormat::negotiate(&headers);
+    let session = state.start_session(request_id, client);
+    let archive_session = state.archive_session("/transcribe", &session);
+    let archive = |transcript: &[Word], stats: JobStats| {
+        if let Some(archive_session) = archive_session {
+            archive_session.save(transcript.to_vec(), stats.audio_seconds)
+        }
+    };
+    let Some(cache) = state.dedupe_cache.clone() else {
+        let (transcript, stats) = run_transcription(&state, headers, body, &session).await?;
+        archive(&transcript, stats);
+        return Ok((stats.headers(), format.render(&transcript)).into_response());
+    };
+    let body = crate::upload::read_body(body, state.max_upload_bytes).await?;
+    // The multipart framing is not part of the key, its boundary differs between uploads.
+    let key = match crate::upload::MultipartStream::from_headers(&headers) {
+        None => sha256(&body),
+        Some(mut multipart) => {
+            let audio = multipart.push(&body);
+            multipart.finish()?;
+            sha256(&audio)
+        }
+    };
+    let if_none_match = headers.get(axum::http::header::IF_NONE_MATCH).cloned();
+    let cached = cache
+        .lock()
+        .map_err(|_| anyhow::anyhow!("dedupe cache lock poisoned"))?
+        .get(&key);
+    if let Some(transcript) = cached {
+        let response = format.render(&transcript);
+        let response = with_etag(if_none_match.as_ref(), response).await?;
+        return Ok(([("x-cache", "HIT")], response).into_response());
+    }
+    let (transcript, stats) = run_transcription(&state, headers, body.into(), &session).await?;
+    archive(&transcript, stats);
+    cache
+        .lock()
+        .map_err(|_| anyhow::anyhow!("dedupe cache lock poisoned"))?
+        .insert(key, transcript.clone());
+    let response = with_etag(if_none_match.as_ref(), format.render(&transcript)).await?;
+    Ok(([("x-cache", "MISS")], stats.headers(), response).into_response())
+}
+
+/// Adds a strong `ETag` to the response, the SHA-256 of its body, and replaces the response with a
+/// 304 when the etag is one of the `If-None-Match` ones. The body depends on the format negotiated
+/// from the `Accept` header, hence the `Vary: Accept` for the caches.
+async fn with_etag(
+    if_none_match: Option<&axum::http::HeaderValue>,
+    response: axum::response::Response,
+) -> Result<axum::response::Response, AppError> {
+    use axum::http::{StatusCode, header};
+
+    let (mut parts, body) = response.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.into()))?;
+    let digest = sha256(&body);
+    let etag = format!(
+        "\"{}\"",
+        digest
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+    );
+    // The comparison is weak as recommended for `If-None-Match`, `W/` prefixes are ignored.
+    let matches = if_none_match
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .map(|t| t.trim())
+                .any(|t| t == "*" || t.trim_start_matches("W/") == etag)
+        });
+    parts.headers.insert(header::ETAG, etag.parse()?);
+    let vary = axum::http::HeaderValue::from_static("accept");
+    parts.headers.append(header::VARY, vary);
+    if matches {
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(header::CONTENT_TYPE);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        return Ok(axum::response::Response::from_parts(
+            parts,
+            axum::body::Body::empty(),
+        ));
+    }
+    Ok(axum::response::Response::from_parts(parts, body.into()))
+}
+
+/// The value of `format` with the highest quality value in the `Accept` header, the first one
+/// listed on a tie. `None` when there is no header or when `format` recognizes none of the media
+/// types.
+fn negotiate<T>(
+    headers: &axum::http::HeaderMap,
+    format: impl Fn(&mime::Mime) -> Option<T>,
+) -> Option<T> {
+    let accept = headers.get(axum::http::header::ACCEPT)?;
+    let accept = accept.to_str().unwrap_or_default();
+    let mut best = None;
+    for media_type in accept.split(',') {
+        let Ok(media_type) = media_type.trim().parse::<mime::Mime>() else {
+            continue;
+        };
+        let Some(format) = format(&media_type) else {
+            continue;
+        };
+        let q = media_type
+            .get_param("q")
+            .and_then(|q| q.as_str().parse::<f32>().ok())
+            .unwrap_or(1.);
+        if best.as_ref().is_none_or(|(_, best_q)| q > *best_q) && q > 0. {
+            best = Some((format, q))
+        }
+    }
+    best.map(|(format, _)| format)
+}
+
+/// Formats the error responses as json or plain text, `default` unless the `Accept` header of the
+/// request prefers one of them.
+async fn render_errors(
+    axum::extract::State(default): axum::extract::State<ErrorFormat>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::http::header;
+    use axum::response::IntoResponse;
+
+    let format = negotiate(request.headers(), |media_type| {
+        match (media_type.type_(), media_type.subtype().as_str()) {
+            (mime::TEXT, "plain") => Some(ErrorFormat::Text),
+            (mime::APPLICATION, "json") => Some(ErrorFormat::Json),
+            (mime::STAR, "*") => Some(default),
+            _ => None,
+        }
+    });
+    let format = format.unwrap_or(default);
+    let response = next.run(request).await;
+    let Some(message) = response.extensions().get::<ErrorMessage>().cloned() else {
+        return response;
+    };
+    let (mut parts, _) = response.into_parts();
+    // The json body may have been compressed on the way out.
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let body = match format {
+        ErrorFormat::Json => axum::Json(message.json(parts.status)).into_response(),
+        ErrorFormat::Text => message.0.into_response(),
+    };
+    let (body_parts, body) = body.into_parts();
+    parts.headers.extend(body_parts.headers);
+    axum::response::Response::from_parts(parts, body)
+}
+
+/// Format of the `/transcribe` responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResponseFormat {
+    /// `application/json`, the text and the words with their timestamps.
+    Json,
+    /// `text/plain`, the bare text.
+    Text,
+    /// `application/x-subrip`, the words grouped in subtitles.
+    Srt,
+}
+
+impl ResponseFormat {
+    /// The format with the highest quality value in the `Accept` header, the first one listed on
+    /// a tie. Json is used when there is no header or when none of the formats is accepted.
+    fn negotiate(headers: &axum::http::HeaderMap) -> Self {
+        let format = negotiate(headers, |media_type| {
+            match (media_type.type_(), media_type.subtype().as_str()) {
+                (mime::TEXT, "plain") => Some(Self::Text),
+                (mime::APPLICATION, "x-subrip") => Some(Self::Srt),
+                (mime::APPLICATION, "json") | (mime::APPLICATION | mime::STAR, "*") => {
+                    Some(Self::Json)
+                }
+                _ => None,
+            }
+        });
+        format.unwrap_or(Self::Json)
+    }
+
+    fn render(self, words: &[Word]) -> axum::response::Response {
+        use axum::http::header::CONTENT_TYPE;
+        use axum::response::IntoResponse;
+
+        let text = || {
+            let text = words.iter().map(|w| w.text.as_str()).collect::<Vec<_>>();
+            text.join(" ")
+        };
+        match self {
+            Self::Json => {
+                axum::Json(serde_json::json!({ "text": text(), "words": words })).into_response()
+            }
+            Self::Text => text().into_response(),
+            Self::Srt => {
+                let mut transcript = crate::output::Transcript::default();
+                for word in words.iter() {
+                    transcript.word(word.text.clone(), vec![], word.start_time, word.confidence);
+                    transcript.end_word(word.stop_time);
+                }
+                let srt = transcript.to_srt();
+                ([(CONTENT_TYPE, "application/x-subrip")], srt).into_response()
+            }
+        }
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, data).as_ref());
+    digest
+}
+
+async fn run_transcription(
+    state: &AppState,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Body,
+    session: &SessionGuard,
+) -> Result<(Vec<Word>, JobStats), AppError> {
+    let (pcm_tx, pcm_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (mut events, stats) = state
+        .actor
+        .start_with_stats(pcm_rx, session)
+        .map_err(|err| AppError::new(axum::http::StatusCode::SERVICE_UNAVAILABLE, err))?;
+    let sample_rate = state.actor.sample_rate;
+    let decoding = crate::upload::start(headers, body, state.max_upload_bytes, sample_rate, pcm_tx);
+    let collect_words = async {
+        let mut words = vec![];
+        while let Some(event) = events.recv().await {
+            match event {
+                AsrEvent::Word(word) => words.push(word),
+                AsrEvent::Endpoint { .. } => {}
+                AsrEvent::Error { message } => anyhow::bail!(message),
+            }
+        }
+        Ok(words)
+    };
+    // Dropping the receiver on timeout stops the job in the model thread.
+    let words = tokio::time::timeout(state.request_timeout, collect_words)
+        .await
+        .map_err(|_| {
+            let err = anyhow::anyhow!("transcription timed out");
+            AppError::new(axum::http::StatusCode::GATEWAY_TIMEOUT, err)
+        })?
+        .map_err(|err| AppError::new(axum::http::StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    // An invalid upload ends the job early, the words decoded so far are discarded.
+    decoding.await??;
+    let stats = stats.await.map_err(|_| {
+        let err = anyhow::anyhow!("the model thread has stopped");
+        AppError::new(axum::http::StatusCode::INTERNAL_SERVER_ERROR, err)
+    })?;
+    Ok((words, stats))
+}
+
+/// The events of an upload job, followed by the decoding error if any once the last word is out.
+/// The transcript is archived under `session` when the whole upload has been transcribed.
+fn upload_events(
+    job: UploadJob,
+    session: Option<crate::archive::Session>,
+) -> impl futures_util::Stream<Item = AsrEvent> {
+    let UploadJob {
+        events,
+        decoding,
+        stats,
+        session: guard,
+    } = job;
+    let archive = session.map(|session| (session, vec![], stats));
+    futures_util::stream::unfold(
+        (events, Some(decoding), archive, guard),
+        |(mut events, mut decoding, mut archive, guard)| async move {
+            let event = match events.recv().await {
+                Some(event) => event,
+                None => {
+                    let err = match decoding.take()?.await {
+                        Ok(Ok(())) => {
+                            let (session, words, stats) = archive?;
+                            // The stats are sent before the events channel is closed.
+                            if let Ok(stats) = stats.await {
+                                session.save(words, stats.audio_seconds)
+                            }
+                            return None;
+                        }
+                        Ok(Err(err)) => err,
+                        Err(err) => err.into(),
+                    };
+                    let message = format!("{err:#}");
+                    AsrEvent::Error { message }
+                }
+            };
+            match &event {
+                AsrEvent::Word(word) => {
+                    if let Some((_, words, _)) = archive.as_mut() {
+                        words.push(word.clone())
+                    }
+                }
+                AsrEvent::Endpoint { .. } => {}
+                AsrEvent::Error { .. } => archive = None,
+            }
+            Some((event, (events, decoding, archive, guard)))
+        },
+    )
+}
+
+/// `POST /transcribe/events`, the body is an audio file and the words are returned as server
+/// sent events: a `word` event with the json word for each word, and an `error` event if the
+/// transcription fails.
+async fn transcribe_events(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request_id: Option<axum::Extension<RequestId>>,
+    client: Option<axum::Extension<crate::proxy_protocol::ClientAddr>>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Body,
+) -> Result<Sse<impl futures_util::Stream<Item = Result<Event, std::convert::Infallible>>>, AppError>
+{
+    crate::upload::check_content_length(&headers, state.max_upload_bytes)?;
+    let session = state.start_session(request_id, client);
+    let archive_session = state.archive_session("/transcribe/events", &session);
+    let job = state
+        .actor
+        .transcribe_upload(headers, body, state.max_upload_bytes, session)
+        .map_err(|err| AppError::new(axum::http::StatusCode::SERVICE_UNAVAILABLE, err))?;
+    let stream = upload_events(job, archive_session).filter_map(|event| async move {
+        let event = match event {
+            AsrEvent::Word(word) => Event::default().event("word").json_data(word).ok()?,
+            event @ AsrEvent::Endpoint { .. } => {
+                Event::default().event("endpoint").json_data(event).ok()?
+            }
+            AsrEvent::Error { message } => Event::default().event("error").data(message),
+        };
+        Some(Ok(event))
+    });
+    Ok(Sse::new(stream))
+}
+
+/// `POST /transcribe/stream`, same as `/transcribe/events` with unnamed events whose data is the
+/// json `AsrEvent`, as sent on the websocket, e.g. `{"type":"word","text":"hello",...}`.
+async fn transcribe_stream(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request_id: Option<axum::Extension<RequestId>>,
+    client: Option<axum::Extension<crate::proxy_protocol::ClientAddr>>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Body,
+) -> Result<Sse<impl futures_util::Stream<Item = Result<Event, std::convert::Infallible>>>, AppError>
+{
+    crate::upload::check_content_length(&headers, state.max_upload_bytes)?;
+    let session = state.start_session(request_id, client);
+    let archive_session = state.archive_session("/transcribe/stream", &session);
+    let job = state
+        .actor
+        .transcribe_upload(headers, body, state.max_upload_bytes, session)
+        .map_err(|err| AppError::new(axum::http::StatusCode::SERVICE_UNAVAILABLE, err))?;
+    let stream = upload_events(job, archive_session)
+        .filter_map(|event| async move { Event::default().json_data(event).ok().map(Ok) });
+    Ok(Sse::new(stream))
+}
+
+/// `GET /transcribe/ws`, the client sends the audio as binary messages of little endian f32
+/// samples at the sample rate of the model, announced in the `x-sample-rate` header of the upgrade
+/// response, and a text message `end` once all the audio has been sent. The samples can be sent as
+/// i16 instead with a `{"type": "init", "sample_format": "i16"}` message before the audio, see
+/// `ClientMessage`. The server sends the `AsrEvent`s as json text messages and closes the socket
+/// once the last word is out. A binary message that is not a whole number of samples closes the
+/// socket with a `1007 Invalid Data` frame. With `--replay-pcm`, the audio received is sent back as
+/// a wav file in a binary message before the socket is closed. Over `--max-connections` or
+/// `--max-connections-per-ip` the upgrade is refused and the connection is closed.
+async fn transcribe_ws(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    request_id: Option<axum::Extension<RequestId>>,
+    client: Option<axum::Extension<crate::proxy_protocol::ClientAddr>>,
+    ws: axum::extract::ws::WebSocketUpgrade,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let ip = client.as_ref().map(|client| client.0.0.ip());
+    let slot = match state.ws_connections.acquire(ip) {
+        Ok(slot) => slot,
+        Err(err) => {
+            let mut response = err.into_response();
+            let close = axum::http::HeaderValue::from_static("close");
+            response
+                .headers_mut()
+                .insert(axum::http::header::CONNECTION, close);
+            return response;
+        }
+    };
+    let session = state.start_session(request_id, client);
+    let archive_session = state.archive_session("/transcribe/ws", &session);
+    let replay_pcm = state.replay_pcm;
+    let sample_rate = axum::http::HeaderValue::from(state.actor.sample_rate);
+    let mut response = ws.on_upgrade(move |socket| {
+        handle_socket(
+            socket,
+            state.actor,
+            slot,
+            session,
+            archive_session,
+            replay_pcm,
+        )
+    });
+    response
+        .headers_mut()
+        .insert(kyutai_stt_rs::stream::SAMPLE_RATE_HEADER, sample_rate);
+    response
+}
+
+async fn handle_socket(
+    socket: axum::extract::ws::WebSocket,
+    actor: ModelActor,
+    _slot: WsConnectionSlot,
+    session: SessionGuard,
+    archive_session: Option<crate::archive::Session>,
+    replay_pcm: bool,
+) {
+    use axum::extract::ws::Message;
+
+    let sample_rate = actor.sample_rate;
+    let (pcm_tx, pcm_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (mut sender, mut receiver) = socket.split();
+    let mut events = match actor.try_start(pcm_rx, &session) {
+        Ok(Some(events)) => events,
+        Ok(None) => {
+            let frame = axum::extract::ws::CloseFrame {
+                code: axum::extract::ws::close_code::AGAIN,
+                reason: "all the model streams are in use, retry later".into(),
+            };
+            let _ = sender.send(Message::Close(Some(frame))).await;
+            return;
+        }
+        Err(err) => {
+            let message = format!("{err:#}");
+            if let Ok(msg) = serde_json::to_string(&AsrEvent::Error { message }) {
+                let _ = sender.send(Message::Text(msg)).await;
+            }
+            return;
+        }
+    };
+    let mut state = session.info.state.subscribe();
+    let terminated = async {
+        let _ = state
+            .wait_for(|&state| state != SessionState::Running)
+            .await;
+    };
+    let transcription = async {
+        let receiver = &mut receiver;
+        let sender = &mut sender;
+        // The number of samples received along with the samples themselves for `--replay-pcm`,
+        // or the frame closing the connection when the client breaks the protocol.
+        let recv_audio = async move {
+            use axum::extract::ws::{CloseFrame, close_code};
+
+            let mut samples = 0;
+            let mut replay = replay_pcm.then(Vec::new);
+            let mut sample_format = SampleFormat::default();
+            while let Some(Ok(msg)) = receiver.next().await {
+                match msg {
+                    Message::Binary(data) => {
+                        let pcm = sample_format.decode(&data).map_err(|err| CloseFrame {
+                            code: close_code::INVALID,
+                            reason: err.to_string().into(),
+                        })?;
+                        samples += pcm.len();
+                        if let Some(replay) = replay.as_mut() {
+                            replay.extend_from_slice(&pcm)
+                        }
+                        if pcm_tx.send(pcm).is_err() {
+                            break;
+                        }
+                    }
+                    Message::Text(text) if text == "end" => break,
+                    Message::Text(text) => match serde_json::from_str(&text) {
+                        Ok(ClientMessage::Init { .. }) if samples > 0 => {
+                            return Err(CloseFrame {
+                                code: close_code::PROTOCOL,
+                                reason: "the init message must be sent before the audio".into(),
+                            });
+                        }
+                        Ok(ClientMessage::Init { sample_format: f }) => sample_format = f,
+                        // Unknown messages are ignored for compatibility with other clients.
+                        Err(_) => {}
+                    },
+                    Message::Close(_) => break,
+                    _ => {}
+                }
+            }
+            // Dropping the sender ends the job, the last words are then flushed.
+            Ok((samples, replay))
+        };
+        // The words of the transcript, `None` if it is incomplete.
+        let send_events = async move {
+            let mut words = Some(vec![]);
+            while let Some(event) = events.recv().await {
+                match &event {
+                    AsrEvent::Word(word) => words.iter_mut().for_each(|w| w.push(word.clone())),
+                    AsrEvent::Endpoint { .. } => {}
+                    AsrEvent::Error { .. } => words = None,
+                }
+                let Ok(msg) = serde_json::to_string(&event) else {
+                    continue;
+                };
+                if sender.send(Message::Text(msg)).await.is_err() {
+                    return None;
+                }
+            }
+            words
+        };
+        tokio::join!(recv_audio, send_events)
+    };
+    let (received, words) = tokio::select! {
+        transcript = transcription => transcript,
+        _ = terminated => {
+            // Dropping the audio and events channels has stopped the job.
+            let frame = axum::extract::ws::CloseFrame {
+                code: axum::extract::ws::close_code::POLICY,
+                reason: "the session has been terminated".into(),
+            };
+            let _ = sender.send(Message::Close(Some(frame))).await;
+            let closed = async {
+                while let Some(Ok(msg)) = receiver.next().await {
+                    if let Message::Close(_) = msg {
+                        break;
+                    }
+                }
+            };
+            let _ = tokio::time::timeout(SESSION_CLOSE_TIMEOUT, closed).await;
+            return;
+        }
+    };
+    let (samples, replay) = match received {
+        Ok(received) => received,
+        Err(frame) => {
+            let _ = sender.send(Message::Close(Some(frame))).await;
+            return;
+        }
+    };
+    if let Some(pcm) = replay {
+        let mut wav = vec![];
+        match kaudio::wav::write_pcm_as_wav(&mut wav, &pcm, sample_rate as u32, 1) {
+            Ok(()) => {
+                let _ = sender.send(Message::Binary(wav)).await;
+            }
+            Err(err) => eprintln!("[{}] Cannot replay the audio: {err:#}", session.info.id),
+        }
+    }
+    let _ = sender.close().await;
+    if let (Some(archive_session), Some(words)) = (archive_session, words) {
+        archive_session.save(words, samples as f64 / sample_rate as f64)
+    }
+}
+
+fn cors_layer(origins: &[String]) -> Result<tower_http::cors::CorsLayer> {
+    use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+    let allow_origin = if origins.iter().any(|o| o.trim() == "*") {
+        AllowOrigin::from(Any)
+    } else {
+        let origins = origins
+            .iter()
+            .map(|o| axum::http::HeaderValue::from_str(o.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+    let layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(Any)
+        .allow_headers(Any);
+    Ok(layer)
+}
+
+/// Whether the `Accept-Encoding` header of the request allows gzip.
+fn accepts_gzip(headers: &axum::http::HeaderMap) -> bool {
+    let Some(accept) = headers.get(axum::http::header::ACCEPT_ENCODING) else {
+        return false;
+    };
+    let accept = accept.to_str().unwrap_or_default();
+    accept.split(',').any(|coding| {
+        let mut params = coding.split(';').map(|p| p.trim());
+        let name = params.next().unwrap_or_default();
+        let disabled = params.any(|p| p.strip_prefix("q=").is_some_and(|q| q.parse() == Ok(0.)));
+        (name == "gzip" || name == "*") && !disabled
+    })
+}
+
+/// Gzips the json responses of at least `min_size` bytes.
+async fn compress_json(
+    axum::extract::State(min_size): axum::extract::State<usize>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Result<axum::response::Response, AppError> {
+    use axum::http::{HeaderValue, header};
+    use std::io::Write;
+
+    let accepts_gzip = accepts_gzip(request.headers());
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if !accepts_gzip || !is_json || response.headers().contains_key(header::CONTENT_ENCODING) {
+        return Ok(response);
+    }
+    let internal_error = |err| AppError::new(axum::http::StatusCode::INTERNAL_SERVER_ERROR, err);
+    let (mut parts, body) = response.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|err| internal_error(err.into()))?;
+    if body.len() < min_size {
+        return Ok(axum::response::Response::from_parts(parts, body.into()));
+    }
+    let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    gz.write_all(&body)
+        .map_err(|err| internal_error(err.into()))?;
+    let body = gz.finish().map_err(|err| internal_error(err.into()))?;
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    Ok(axum::response::Response::from_parts(parts, body.into()))
+}
+
+/// The id of a request, set as an extension by `request_id`.
+#[derive(Debug, Clone)]
+struct RequestId(String);
+
+/// Tags each request with an id, taken from the `header` of the request or generated, sets it on
+/// the response and logs the request along with its id.
+async fn request_id(
+    axum::extract::State(header): axum::extract::State<axum::http::HeaderName>,
+    mut request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let id = match request.headers().get(&header) {
+        Some(id) => id.clone(),
+        None => {
+            let mut bytes = [0u8; 16];
+            // The id is only used to correlate the logs, a failure of the rng is not fatal.
+            let _ = ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut bytes);
+            let id = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+            let id = axum::http::HeaderValue::from_str(&id).expect("hex is a valid header value");
+            request.headers_mut().insert(&header, id.clone());
+            id
+        }
+    };
+    let request_id = RequestId(String::from_utf8_lossy(id.as_bytes()).into_owned());
+    request.extensions_mut().insert(request_id);
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let client = request
+        .extensions()
+        .get::<crate::proxy_protocol::ClientAddr>();
+    let client = client.map_or(String::new(), |client| format!(" from {}", client.0));
+    let start_time = std::time::Instant::now();
+    let mut response = next.run(request).await;
+    eprintln!(
+        "[{}] {method} {path}{client} {} in {}ms",
+        String::from_utf8_lossy(id.as_bytes()),
+        response.status().as_u16(),
+        start_time.elapsed().as_millis()
+    );
+    response.headers_mut().insert(header, id);
+    response
+}
+
+/// `GET /livez`, always succeeds while the process is up.
+async fn livez() -> &'static str {
+    "ok"
+}
+
+/// `GET /readyz`, succeeds once the model has transcribed some audio successfully.
+async fn readyz(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> (axum::http::StatusCode, &'static str) {
+    if state.actor.ready.load(std::sync::atomic::Ordering::Relaxed) {
+        (axum::http::StatusCode::OK, "ready")
+    } else {
+        (axum::http::StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+/// `GET /admin/sessions`, the transcriptions in progress as a json array.
+async fn admin_sessions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> axum::Json<Vec<serde_json::Value>> {
+    axum::Json(state.sessions.list(state.actor.sample_rate))
+}
+
+/// `POST /admin/reload-model`, loads the weights from `--hf-repo` again, without interrupting
+/// the transcriptions. The files are taken from the Hugging Face cache when present, e.g. as
+/// updated by `huggingface-cli download`. Answers once the new model serves the requests, with
+/// `{"status": "reloaded", "model_version": "<repo>@<commit>"}`, or with a 503 if a reload is
+/// already in progress.
+async fn admin_reload_model(
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<axum::Json<serde_json::Value>, AppError> {
+    use std::sync::atomic::Ordering;
+
+    let reload = state.reload.clone();
+    if reload.in_progress.swap(true, Ordering::SeqCst) {
+        let err = anyhow::anyhow!("the model is already being reloaded");
+        return Err(AppError::new(
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            err,
+        ));
+    }
+    // The reload goes on if the request is dropped, the flag is only cleared once it is over.
+    let reloading = tokio::task::spawn_blocking(move || {
+        let version = reload.run(&state.actor);
+        if let (Ok(version), Some(archive)) = (&version, state.archive.as_ref()) {
+            archive.set_model(version.clone())
+        }
+        reload.in_progress.store(false, Ordering::SeqCst);
+        version
+    });
+    let version = reloading
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|version| version)
+        .map_err(|err| {
+            eprintln!("Model reload failed: {err:#}");
+            AppError::new(axum::http::StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+    eprintln!("Model reloaded: {version}");
+    Ok(axum::Json(serde_json::json!({
+        "status": "reloaded",
+        "model_version": version,
+    })))
+}
+
+/// Time given to a WebSocket client to acknowledge the close frame of a terminated session.
+const SESSION_CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// `DELETE /admin/sessions/<ID>`, terminates the sessions with this id. The WebSocket clients
+/// are sent a close frame and given `SESSION_CLOSE_TIMEOUT` to acknowledge it, the other
+/// requests fail. Answers a 204 once the sessions are over, a 202 if they are still closing, or
+/// a 404 if there is no session with this id.
+async fn admin_terminate_session(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<axum::http::StatusCode, AppError> {
+    let sessions = state.sessions.find(&id);
+    if sessions.is_empty() {
+        let err = anyhow::anyhow!("no session {id}");
+        return Err(AppError::new(axum::http::StatusCode::NOT_FOUND, err));
+    }
+    let closed = sessions.iter().map(|info| {
+        let mut session_state = info.state.subscribe();
+        info.state.send_if_modified(|state| {
+            let running = *state == SessionState::Running;
+            if running {
+                *state = SessionState::Terminating
+            }
+            running
+        });
+        async move {
+            let _ = session_state
+                .wait_for(|&state| state == SessionState::Closed)
+                .await;
+        }
+    });
+    eprintln!("Terminating the session {id}");
+    // A little longer than the time given to the clients so that their sessions are dropped.
+    let timeout = SESSION_CLOSE_TIMEOUT + std::time::Duration::from_secs(1);
+    match tokio::time::timeout(timeout, futures_util::future::join_all(closed)).await {
+        Ok(_) => Ok(axum::http::StatusCode::NO_CONTENT),
+        Err(_) => Ok(axum::http::StatusCode::ACCEPTED),
+    }
+}
+
+/// Routes that can be called without the api key, e.g. by a load balancer.
+const PUBLIC_ROUTES: [&str; 4] = ["/health", "/info", "/livez", "/readyz"];
+
+/// Rejects the requests that do not carry the api key with a 401.
+async fn check_api_key(
+    axum::extract::State(api_key): axum::extract::State<std::sync::Arc<str>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if PUBLIC_ROUTES.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let token = request
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    // The digests are compared rather than the keys so that the time taken by the comparison
+    // does not tell how much of the key is right.
+    let digest = |v: &str| ring::digest::digest(&ring::digest::SHA256, v.as_bytes());
+    let authorized = token.is_some_and(|token| digest(token).as_ref() == digest(&api_key).as_ref());
+    if authorized {
+        return next.run(request).await;
+    }
+    let message = match token {
+        None => "missing api key",
+        Some(_) => "invalid api key",
+    };
+    let err = anyhow::anyhow!(message);
+    AppError::new(axum::http::StatusCode::UNAUTHORIZED, err).into_response()
+}
+
+async fn serve(args: &ServeArgs, actor: ModelActor) -> Result<()> {
+    if let Some(recorder) = actor.recorder.clone() {
+        recorder.spawn_cleanup()
+    }
+    let state = AppState {
+        actor,
+        request_timeout: std::time::Duration::from_secs(args.request_timeout),
+        max_upload_bytes: args.max_upload_bytes,
+        dedupe_cache: (args.dedupe_cache_size > 0).then(|| {
+            let cache = TranscriptCache::new(args.dedupe_cache_size);
+            std::sync::Arc::new(std::sync::Mutex::new(cache))
+        }),
+        ws_connections: WsConnections::new(args.max_connections, args.max_connections_per_ip),
+        sessions: SessionManager::default(),
+        reload: ModelReload {
+            hf_repo: args.hf_repo.clone(),
+            cpu: args.cpu,
+            strict_device: args.strict_device,
+            pool_size: args.pool_size,
+            in_progress: Default::default(),
+        },
+        replay_pcm: args.replay_pcm,
+        archive: match args.archive_dir.clone() {
+            None => None,
+            Some(dir) => {
+                eprintln!("Archiving the transcripts to {}", dir.display());
+                let archive = crate::archive::Archive::new(dir, model_version(&args.hf_repo))?;
+                Some(std::sync::Arc::new(archive))
+            }
+        },
+    };
+    let actor = state.actor.clone();
+    let mut app = axum::Router::new()
+        .route("/transcribe", axum::routing::post(transcribe))
+        .route("/transcribe/events", axum::routing::post(transcribe_events))
+        .route("/transcribe/stream", axum::routing::post(transcribe_stream))
+        .route("/transcribe/ws", axum::routing::get(transcribe_ws))
+        .route("/livez", axum::routing::get(livez))
+        .route("/readyz", axum::routing::get(readyz))
+        .with_state(state.clone());
+    if let Some(ResponseCompression::Gzip) = args.response_compression {
+        let min_size = args.min_size_for_compression;
+        app = app.layer(axum::middleware::from_fn_with_state(
+            min_size,
+            compress_json,
+        ));
+    }
+    if let Some(api_key) = args.rest_api_key.as_deref() {
+        let api_key: std::sync::Arc<str> = api_key.into();
+        app = app.layer(axum::middleware::from_fn_with_state(api_key, check_api_key));
+    }
+    // Merged after the api key check, the admin routes require the admin token instead.
+    if let Some(admin_token) = args.admin_token.as_deref() {
+        let admin_token: std::sync::Arc<str> = admin_token.into();
+        let admin = axum::Router::new()
+            .route("/admin/sessions", axum::routing::get(admin_sessions))
+            .route(
+                "/admin/sessions/:id",
+                axum::routing::delete(admin_terminate_session),
+            )
+            .route(
+                "/admin/reload-model",
+                axum::routing::post(admin_reload_model),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                admin_token,
+                check_api_key,
+            ))
+            .with_state(state);
+        app = app.merge(admin);
+    }
+    // Added last so that the preflight requests, which have no credentials, are answered before
+    // the api key check.
+    if !args.rest_cors_origins.is_empty() {
+        app = app.layer(cors_layer(&args.rest_cors_origins)?);
+    }
+    let header = axum::http::HeaderName::from_bytes(args.request_id_header.as_bytes())
+        .with_context(|| format!("invalid header name {}", args.request_id_header))?;
+    // Outside of the other layers so that their errors are formatted too.
+    app = app.layer(axum::middleware::from_fn_with_state(
+        args.error_format,
+        render_errors,
+    ));
+    app = app.layer(axum::middleware::from_fn_with_state(header, request_id));
+    // The connections are watched so that the responses in progress are sent before exiting.
+    let connections = hyper_util::server::graceful::GracefulShutdown::new();
+    match args.unix_socket.as_deref() {
+        Some(path) => serve_unix(path, app, &connections).await?,
+        None => {
+            let listener = bind_tcp(args)?;
+            let tls = match (args.tls_cert.as_deref(), args.tls_key.as_deref()) {
+                (Some(cert), Some(key)) => {
+                    eprintln!("Listening on https://{}", listener.local_addr()?);
+                    Some(tls_config(cert, key)?)
+                }
+                _ => {
+                    eprintln!("Listening on {}", listener.local_addr()?);
+                    None
+                }
+            };
+            serve_tcp(listener, app, tls, args.proxy_protocol, &connections).await
+        }
+    }
+    let timeout = std::time::Duration::from_secs(args.graceful_shutdown_timeout);
+    let jobs = *actor.in_flight.borrow();
+    eprintln!(
+        "Shutting down, waiting up to {}s for {jobs} jobs in flight",
+        timeout.as_secs()
+    );
+    let drain = async {
+        connections.shutdown().await;
+        actor.drain().await
+    };
+    if tokio::time::timeout(timeout, drain).await.is_err() {
+        let jobs = *actor.in_flight.borrow();
+        eprintln!("Graceful shutdown timed out, cancelling {jobs} jobs");
+        actor
+            .cancel
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Resolves on SIGTERM, as sent by process managers, or on ctrl-c.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => tokio::select! {
+                _ = sigterm.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            },
+            Err(err) => {
+                eprintln!("Cannot listen for SIGTERM: {err}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Binds the listener on `--bind-address`, or on `[::]` with `--ipv6`. An IPv6 socket is dual
+/// stack unless `--ipv6-only` is set, whatever the default of the system is.
+fn bind_tcp(args: &ServeArgs) -> Result<tokio::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let ip = if args.ipv6 {
+        std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED)
+    } else {
+        args.bind_address
+    };
+    let addr = std::net::SocketAddr::new(ip, args.port);
+    if args.ipv6_only && !addr.is_ipv6() {
+        anyhow::bail!("--ipv6-only requires --ipv6 or an IPv6 --bind-address, got {ip}")
+    }
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(args.ipv6_only)?;
+    }
+    // Same as `TcpListener::bind`, so that a restarted server does not wait for the connections
+    // of the previous one to time out.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("cannot bind {addr}"))?;
+    socket.listen(1024)?;
+    Ok(tokio::net::TcpListener::from_std(socket.into())?)
+}
+
+fn tls_config(
+    cert: &std::path::Path,
+    key: &std::path::Path,
+) -> Result<tokio_rustls::rustls::ServerConfig> {
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("cannot read the certificates from {}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("cannot read the private key from {}", key.display()))?;
+    let mut config = tokio_rustls::rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Time given to the proxy to send the PROXY header once the connection is accepted.
+const PROXY_HEADER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Time given to the client to complete the TLS handshake once the connection is accepted.
+const TLS_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Serves the connections accepted on `listener` until the shutdown signal, with a TLS handshake
+/// on each connection when `tls` is set. A failed or stalled handshake only drops the connection.
+/// With `proxy_protocol` the PROXY header is read first and the connections without one are
+/// dropped. The TLS connections go through the same hyper-util loop as the plain ones, rather
+/// than `axum-server`, so that both share the graceful shutdown and the PROXY header handling.
+async fn serve_tcp(
+    listener: tokio::net::TcpListener,
+    app: axum::Router,
+    tls: Option<tokio_rustls::rustls::ServerConfig>,
+    proxy_protocol: bool,
+    connections: &hyper_util::server::graceful::GracefulShutdown,
+) {
+    let acceptor = tls.map(|config| tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config)));
+    let mut signal = std::pin::pin!(shutdown_signal());
+    loop {
+        let (mut stream, peer) = tokio::select! {
+            _ = &mut signal => return,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                // E.g. too many open files, retrying right away would spin.
+                Err(err) => {
+                    eprintln!("Error accepting a connection: {err}");
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+        };
+        let app = app.clone();
+        let acceptor = acceptor.clone();
+        let watcher = connections.watcher();
+        tokio::spawn(async move {
+            let client = if proxy_protocol {
+                let header = crate::proxy_protocol::read_header(&mut stream);
+                match tokio::time::timeout(PROXY_HEADER_TIMEOUT, header).await {
+                    Ok(Ok(client)) => client.unwrap_or(peer),
+                    Ok(Err(err)) => {
+                        eprintln!("Dropping the connection from {peer}: {err:#}");
+                        return;
+                    }
+                    Err(_) => {
+                        eprintln!("Dropping the connection from {peer}: no PROXY header received");
+                        return;
+                    }
+                }
+            } else {
+                peer
+            };
+            let client = crate::proxy_protocol::ClientAddr(client);
+            let app = app.layer(axum::Extension(client));
+            match acceptor {
+                None => serve_connection(stream, app, watcher).await,
+                Some(acceptor) => {
+                    let handshake = acceptor.accept(stream);
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(stream)) => serve_connection(stream, app, watcher).await,
+                        Ok(Err(_)) => {}
+                        Err(_) => eprintln!("Dropping the connection from {peer}: TLS timeout"),
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn serve_unix(
+    path: &std::path::Path,
+    app: axum::Router,
+    connections: &hyper_util::server::graceful::GracefulShutdown,
+) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // The socket of a previous run is left behind when the server is killed.
+    if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    eprintln!("Listening on {}", path.display());
+    let mut signal = std::pin::pin!(shutdown_signal());
+    loop {
+        tokio::select! {
+            _ = &mut signal => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let watcher = connections.watcher();
+                    tokio::spawn(serve_connection(stream, app.clone(), watcher));
+                }
+                Err(err) => {
+                    eprintln!("Error accepting a connection: {err}");
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                }
+            },
+        }
+    }
+    // Dropping the listener does not remove the socket file.
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(
+    _path: &std::path::Path,
+    _app: axum::Router,
+    _connections: &hyper_util::server::graceful::GracefulShutdown,
+) -> Result<()> {
+    anyhow::bail!("--unix-socket is only supported on unix")
+}
+
+/// Serves the requests of a single connection, the connection is closed once its requests in
+/// progress are over when a shutdown is signaled on `watcher`.
+async fn serve_connection<I>(
+    io: I,
+    app: axum::Router,
+    watcher: hyper_util::server::graceful::Watcher,
+) where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let service = hyper_util::service::TowerToHyperService::new(app);
+    let builder =
+        hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+    let io = hyper_util::rt::TokioIo::new(io);
+    let connection = builder.serve_connection_with_upgrades(io, service);
+    // The errors are the ones of the connection, e.g. the client going away.
+    let _ = watcher.watch(connection).await;
+}
+
+/// Available memory in MB as reported by the kernel, `None` when not on linux.
+fn available_memory_mb() -> Option<f64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<f64>().ok()?;
+    Some(kb / 1024.)
+}
+
+/// Forks `transcriber` so that `size` streams can be transcribed concurrently. With
+/// `measure_memory`, the memory taken by a fork is measured once it has processed some audio and
+/// the pool stops growing when the available memory would not hold two more forks.
+fn model_pool(
+    transcriber: Transcriber,
+    size: usize,
+    measure_memory: bool,
+) -> Result<Vec<Transcriber>> {
+    let measure = || measure_memory.then(available_memory_mb).flatten();
+    let mut pool = vec![];
+    let mut fork_mb: Option<f64> = None;
+    for _ in 1..size {
+        let before = measure();
+        match (before, fork_mb) {
+            (Some(before), Some(fork_mb)) if before < 2. * fork_mb => {
+                eprintln!(
+                    "Capping the pool to {} streams, {before:.0}MB of memory left for {fork_mb:.0}MB \
+                     per stream",
+                    pool.len() + 1
+                );
+                break;
+            }
+            _ => {}
+        }
+        let mut fork = transcriber.fork()?;
+        // The caches are only allocated once some audio has been processed.
+        let sample_rate = fork.sample_rate();
+        fork.transcribe(&vec![0.; sample_rate], sample_rate)?;
+        if let (Some(before), Some(after)) = (before, measure()) {
+            fork_mb = Some(f64::max(fork_mb.unwrap_or(0.), before - after));
+        }
+        pool.push(fork)
+    }
+    pool.insert(0, transcriber);
+    match fork_mb {
+        Some(fork_mb) => eprintln!("Pool of {} streams, {fork_mb:.0}MB each", pool.len()),
+        None => eprintln!("Pool of {} streams", pool.len()),
+    }
+    Ok(pool)
+}
+
+pub fn run(args: &ServeArgs) -> Result<()> {
+    let device = kyutai_stt_rs::device(args.cpu, args.strict_device)?;
+    eprintln!("Using device: {:?}", device);
+    eprintln!("Loading model from repository: {}", args.hf_repo);
+    let measure_memory = device.is_cpu();
+    let transcriber = Transcriber::load_on(&args.hf_repo, device)?;
+    let sample_rate = transcriber.sample_rate();
+    let transcribers = model_pool(transcriber, args.pool_size, measure_memory)?;
+    let recorder = match args.record_audio_dir.clone() {
+        None => None,
+        Some(dir) => {
+            eprintln!("Recording the audio to {}", dir.display());
+            let retention =
+                std::time::Duration::from_secs(args.record_audio_retention_hours * 3600);
+            let recorder = crate::recording::Recorder::new(dir, retention)?;
+            Some(std::sync::Arc::new(recorder))
+        }
+    };
+    let actor = ModelActor::spawn(transcribers, sample_rate, recorder);
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(serve(args, actor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+
+    fn ip(ip: &str) -> Option<std::net::IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    fn rejection(slot: Result<WsConnectionSlot, AppError>) -> StatusCode {
+        match slot {
+            Ok(_) => panic!("the connection was accepted"),
+            Err(err) => err.status,
+        }
+    }
+
+    #[test]
+    fn ws_connection_limits() {
+        let connections = WsConnections::new(Some(3), Some(2));
+        let count = || {
+            let count = connections.count.load(std::sync::atomic::Ordering::SeqCst);
+            let per_ip = connections.per_ip.lock().unwrap().clone();
+            (count, per_ip.into_iter().collect::<Vec<_>>())
+        };
+        let first = connections.acquire(ip("10.0.0.1")).ok().unwrap();
+        // The IPv4-mapped address of a dual stack socket is the same client.
+        let second = connections.acquire(ip("::ffff:10.0.0.1")).ok().unwrap();
+        assert_eq!(count(), (2, vec![(ip("10.0.0.1").unwrap(), 2)]));
+        let status = rejection(connections.acquire(ip("10.0.0.1")));
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(count(), (2, vec![(ip("10.0.0.1").unwrap(), 2)]));
+
+        let third = connections.acquire(ip("10.0.0.2")).ok().unwrap();
+        for client in [ip("10.0.0.3"), None] {
+            let status = rejection(connections.acquire(client));
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        }
+        assert_eq!(count().0, 3);
+        assert_eq!(count().1.len(), 2);
+
+        drop(first);
+        assert_eq!(count().0, 2);
+        assert!(count().1.contains(&(ip("10.0.0.1").unwrap(), 1)));
+        drop((second, third));
+        assert_eq!(count(), (0, vec![]));
+        // Without an address only the total is limited.
+        let slots = (0..3).map(|_| connections.acquire(None).ok().unwrap());
+        let slots = slots.collect::<Vec<_>>();
+        assert_eq!(count(), (3, vec![]));
+        drop(slots);
+        assert_eq!(count(), (0, vec![]));
+    }
+
+    async fn with_if_none_match(if_none_match: Option<&str>) -> axum::response::Response {
+        use axum::response::IntoResponse;
+
+        let if_none_match = if_none_match.map(|v| axum::http::HeaderValue::from_str(v).unwrap());
+        let response = "transcript".into_response();
+        with_etag(if_none_match.as_ref(), response)
+            .await
+            .ok()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn etag_if_none_match() -> Result<()> {
+        use axum::http::header;
+
+        let response = with_if_none_match(None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::VARY], "accept");
+        let etag = response.headers()[header::ETAG].to_str()?.to_string();
+        assert_eq!(etag.len(), 66, "{etag}");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(body, "transcript");
+
+        let weak = format!("W/{etag}");
+        let listed = format!("\"abc\", {weak}");
+        for if_none_match in [etag.as_str(), &weak, "*", &listed] {
+            let response = with_if_none_match(Some(if_none_match)).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::NOT_MODIFIED,
+                "{if_none_match}"
+            );
+            assert_eq!(response.headers()[header::ETAG], etag.as_str());
+            assert_eq!(response.headers()[header::VARY], "accept");
+            assert!(response.headers().get(header::CONTENT_TYPE).is_none());
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+            assert!(body.is_empty());
+        }
+        for if_none_match in ["\"abc\"", "\"abc\", W/\"def\"", &etag[1..]] {
+            let response = with_if_none_match(Some(if_none_match)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{if_none_match}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn negotiate_response_format() {
+        let format = |accept: Option<&str>| {
+            let mut headers = axum::http::HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(axum::http::header::ACCEPT, accept.parse().unwrap());
+            }
+            ResponseFormat::negotiate(&headers)
+        };
+        let cases = [
+            (None, ResponseFormat::Json),
+            (
+                Some("text/plain;q=0.9, application/json"),
+                ResponseFormat::Json,
+            ),
+            (
+                Some("text/plain, application/json;q=0.5"),
+                ResponseFormat::Text,
+            ),
+            (Some("application/x-subrip"), ResponseFormat::Srt),
+            (Some("*/*"), ResponseFormat::Json),
+            (Some("text/html, */*;q=0.8"), ResponseFormat::Json),
+            // The first one listed on a tie.
+            (
+                Some("text/plain, application/x-subrip"),
+                ResponseFormat::Text,
+            ),
+            // Excluded with a zero quality value.
+            (
+                Some("text/plain;q=0, application/x-subrip;q=0.1"),
+                ResponseFormat::Srt,
+            ),
+            (Some("text/plain;q=0"), ResponseFormat::Json),
+            (Some("image/png"), ResponseFormat::Json),
+        ];
+        for (accept, expected) in cases {
+            assert_eq!(format(accept), expected, "{accept:?}");
+        }
+    }
+}
@@ -0,0 +1,482 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Streaming transcription of a single audio stream, this is what the C API and the server use.
+use std::collections::VecDeque;
+
+use crate::asr;
+use crate::config::Config;
+use crate::decode::WordDecoder;
+use crate::error::{Result, SttError};
+
+/// A decoded word, times are in seconds from the start of the stream.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Word {
+    pub text: String,
+    pub start_time: f64,
+    pub stop_time: f64,
+    pub confidence: f32,
+}
+
+/// The messages sent by the server, serialized as json with a `type` field.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AsrEvent {
+    Word(Word),
+    /// The speaker has likely stopped speaking, sent once per turn when endpointing is enabled.
+    Endpoint {
+        /// In seconds from the start of the stream, on the same clock as the word times.
+        time: f64,
+        /// Probability of no voice activity over the horizon of the VAD head.
+        probability: f32,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Header of the response to the websocket upgrade with the sample rate of the model, the audio
+/// must be sent at this rate.
+pub const SAMPLE_RATE_HEADER: &str = "x-sample-rate";
+
+/// The json text messages sent by the websocket clients, besides `end` once all the audio has
+/// been sent.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Optional, only accepted before the first audio message.
+    Init {
+        #[serde(default)]
+        sample_format: SampleFormat,
+    },
+}
+
+/// Encoding of the little endian pcm sent in the binary websocket messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleFormat {
+    /// Samples in [-1, 1].
+    #[default]
+    F32,
+    I16,
+}
+
+impl SampleFormat {
+    pub fn sample_size(self) -> usize {
+        match self {
+            Self::F32 => 4,
+            Self::I16 => 2,
+        }
+    }
+
+    /// Decodes a message as f32 samples, it must hold a whole number of samples.
+    pub fn decode(self, data: &[u8]) -> Result<Vec<f32>> {
+        if !data.len().is_multiple_of(self.sample_size()) {
+            return Err(SttError::InvalidInput(format!(
+                "{} bytes is not a whole number of {self:?} samples",
+                data.len()
+            )));
+        }
+        let pcm = match self {
+            Self::F32 => data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            Self::I16 => data
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.)
+                .collect(),
+        };
+        Ok(pcm)
+    }
+
+    /// Encodes f32 samples, they are clamped to [-1, 1] for `I16`.
+    pub fn encode(self, pcm: &[f32]) -> Vec<u8> {
+        match self {
+            Self::F32 => pcm.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            Self::I16 => pcm
+                .iter()
+                .flat_map(|v| ((v.clamp(-1., 1.) * 32767.).round() as i16).to_le_bytes())
+                .collect(),
+        }
+    }
+}
+
+impl std::str::FromStr for SampleFormat {
+    type Err = SttError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "f32" => Ok(Self::F32),
+            "i16" => Ok(Self::I16),
+            _ => Err(SttError::InvalidInput(format!(
+                "unknown sample format {s}, expected f32 or i16"
+            ))),
+        }
+    }
+}
+
+/// Parameters of the end of turn detection, see `Transcriber::start`.
+#[derive(Debug, Clone, Copy)]
+pub struct Endpointing {
+    /// Index of the VAD head, in `kyutai/stt-1b-en_fr-candle` the heads predict the absence of
+    /// voice over 0.5s, 1s, 2s and 3s.
+    pub horizon: usize,
+    /// An endpoint is sent when the probability of no voice activity exceeds this value.
+    pub threshold: f32,
+}
+
+impl Default for Endpointing {
+    fn default() -> Self {
+        Self {
+            horizon: 2,
+            threshold: 0.5,
+        }
+    }
+}
+
+/// Transcribes a single audio stream, the pcm can be pushed in chunks of any size.
+pub struct Transcriber {
+    state: asr::State,
+    text_tokenizer: std::sync::Arc<sentencepiece::SentencePieceProcessor>,
+    audio_delay_seconds: f64,
+    silence_prefix_seconds: f64,
+    /// The sample rate and frame rate of the mimi config, a step of the model consumes one frame
+    /// of `frame_size` samples.
+    sample_rate: usize,
+    frame_size: usize,
+    frame_rate: f64,
+    /// Number of steps the text lags behind the audio, see `asr::State`.
+    asr_delay_in_tokens: usize,
+    /// Samples that do not fill a whole frame yet.
+    pcm: Vec<f32>,
+    /// Number of samples fed to the model, including the silence prefix.
+    num_samples: usize,
+    /// The last word, kept until its stop time is known.
+    pending: Option<Word>,
+    events: VecDeque<AsrEvent>,
+    /// Number of VAD heads of the model, zero if it has none.
+    vad_num_heads: usize,
+    endpointing: Option<Endpointing>,
+    /// Whether an endpoint has been sent since the last word, so that it is sent once per turn.
+    endpoint_sent: bool,
+    dev: candle::Device,
+}
+
+impl Transcriber {
+    /// Loads the model from a Hugging Face repo, e.g. `kyutai/stt-1b-en_fr-candle`.
+    pub fn load(hf_repo: &str, cpu: bool) -> Result<Self> {
+        Self::load_on(hf_repo, crate::device(cpu, false)?)
+    }
+
+    /// Same as `load` with an explicit device.
+    pub fn load_on(hf_repo: &str, dev: candle::Device) -> Result<Self> {
+        let api = hf_hub::api::sync::Api::new().map_err(|source| SttError::Download {
+            file: hf_repo.to_string(),
+            source,
+        })?;
+        let repo = api.model(hf_repo.to_string());
+        let get = |file: &str| {
+            repo.get(file).map_err(|source| SttError::Download {
+                file: format!("{hf_repo}/{file}"),
+                source,
+            })
+        };
+        let config = Config::load(&get("config.json")?)?;
+        let tokenizer_file = get(&config.tokenizer_name)?;
+        let model_file = get("model.safetensors")?;
+        let mimi_file = get(&config.mimi_name)?;
+        let text_tokenizer =
+            sentencepiece::SentencePieceProcessor::open(&tokenizer_file).map_err(|source| {
+                SttError::Tokenizer {
+                    path: tokenizer_file.clone(),
+                    source,
+                }
+            })?;
+        // The VAD heads are only needed for endpointing, which not all the models support.
+        let vad = config.has_vad_heads(&model_file, false)?;
+        let lm = config.load_lm(&model_file, false, None, vad, &dev)?;
+        let audio_tokenizer = mimi_file
+            .to_str()
+            .ok_or_else(|| SttError::Config(format!("invalid mimi path {}", mimi_file.display())))
+            .and_then(|path| {
+                moshi::mimi::load(path, Some(config.n_q), &dev).map_err(|source| {
+                    SttError::Weights {
+                        path: mimi_file.clone(),
+                        source,
+                    }
+                })
+            })?;
+        let mimi_config = audio_tokenizer.config();
+        let (sample_rate, frame_rate) = (mimi_config.sample_rate, mimi_config.frame_rate);
+        let frame_size = sample_rate / frame_rate;
+        if sample_rate.fract() != 0. || frame_size.fract() != 0. || frame_size < 1. {
+            return Err(SttError::Config(format!(
+                "mimi frame rate {frame_rate}Hz does not split its sample rate {sample_rate}Hz in \
+                 whole frames"
+            )));
+        }
+        let audio_delay_seconds = config.stt_config.audio_delay_seconds;
+        let asr_delay_in_tokens = (audio_delay_seconds * frame_rate) as usize;
+        let state = asr::State::new(1, asr_delay_in_tokens, 0., audio_tokenizer, lm)?;
+        let mut model = Self {
+            state,
+            text_tokenizer: std::sync::Arc::new(text_tokenizer),
+            audio_delay_seconds,
+            silence_prefix_seconds: config.stt_config.audio_silence_prefix_seconds,
+            sample_rate: sample_rate as usize,
+            frame_size: frame_size as usize,
+            frame_rate,
+            asr_delay_in_tokens,
+            pcm: vec![],
+            num_samples: 0,
+            pending: None,
+            events: VecDeque::new(),
+            vad_num_heads: if vad { config.vad_num_heads } else { 0 },
+            endpointing: None,
+            endpoint_sent: false,
+            dev,
+        };
+        model.reset()?;
+        Ok(model)
+    }
+
+    /// A transcriber sharing the weights of this one, with its own stream. Only the streaming
+    /// state is allocated, so this is much cheaper than loading the model again.
+    pub fn fork(&self) -> Result<Self> {
+        let mut fork = Self {
+            state: self.state.clone(),
+            text_tokenizer: self.text_tokenizer.clone(),
+            audio_delay_seconds: self.audio_delay_seconds,
+            silence_prefix_seconds: self.silence_prefix_seconds,
+            sample_rate: self.sample_rate,
+            frame_size: self.frame_size,
+            frame_rate: self.frame_rate,
+            asr_delay_in_tokens: self.asr_delay_in_tokens,
+            pcm: vec![],
+            num_samples: 0,
+            pending: None,
+            events: VecDeque::new(),
+            vad_num_heads: self.vad_num_heads,
+            endpointing: None,
+            endpoint_sent: false,
+            dev: self.dev.clone(),
+        };
+        fork.reset()?;
+        Ok(fork)
+    }
+
+    /// The sample rate of the model, the pcm given to `push_pcm` is expected at this rate.
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    /// Number of samples consumed by each step of the model.
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    /// Number of steps of the model per second of audio.
+    pub fn frame_rate(&self) -> f64 {
+        self.frame_rate
+    }
+
+    /// Discards the current stream so that a new one can be transcribed, with the endpointing
+    /// parameters of the previous stream.
+    pub fn reset(&mut self) -> Result<()> {
+        self.start(self.endpointing)
+    }
+
+    /// Discards the current stream and starts a new one, `Endpoint` events are sent for this
+    /// stream when `endpointing` is set.
+    pub fn start(&mut self, endpointing: Option<Endpointing>) -> Result<()> {
+        if let Some(endpointing) = endpointing {
+            if self.vad_num_heads == 0 {
+                return Err(SttError::InvalidInput(
+                    "this model has no VAD heads, endpointing is not supported".to_string(),
+                ));
+            }
+            if endpointing.horizon >= self.vad_num_heads {
+                return Err(SttError::InvalidInput(format!(
+                    "the endpointing horizon must be lower than the number of VAD heads, {}",
+                    self.vad_num_heads
+                )));
+            }
+        }
+        self.endpointing = endpointing;
+        self.endpoint_sent = false;
+        self.state.reset()?;
+        self.pcm.clear();
+        self.num_samples = 0;
+        self.pending = None;
+        self.events.clear();
+        let silence_len = (self.silence_prefix_seconds * self.sample_rate as f64) as usize;
+        self.push_pcm(&vec![0.; silence_len])
+    }
+
+    /// Feeds mono pcm at `sample_rate`, the completed words can then be retrieved with
+    /// `next_word`. The samples are expected in [-1, 1], the ones outside of this range are clamped
+    /// and non finite samples are an error.
+    pub fn push_pcm(&mut self, pcm: &[f32]) -> Result<()> {
+        if let Some(idx) = pcm.iter().position(|v| !v.is_finite()) {
+            return Err(SttError::InvalidInput(format!(
+                "sample {idx} is not finite: {}",
+                pcm[idx]
+            )));
+        }
+        self.pcm.extend(pcm.iter().map(|v| v.clamp(-1., 1.)));
+        let frame_size = self.frame_size;
+        let num_chunks = self.pcm.len() / frame_size;
+        for chunk_idx in 0..num_chunks {
+            let chunk = &self.pcm[chunk_idx * frame_size..(chunk_idx + 1) * frame_size];
+            let chunk = candle::Tensor::new(chunk, &self.dev)?.reshape((1, 1, ()))?;
+            let asr_msgs = self.state.step_pcm(chunk, None, &().into(), |_, _, _| ())?;
+            for asr_msg in asr_msgs {
+                self.handle_msg(asr_msg)
+            }
+        }
+        self.pcm.drain(..num_chunks * frame_size);
+        self.num_samples += num_chunks * frame_size;
+        Ok(())
+    }
+
+    fn handle_msg(&mut self, asr_msg: asr::AsrMsg) {
+        match asr_msg {
+            asr::AsrMsg::Word {
+                tokens,
+                start_time,
+                confidence,
+                ..
+            } => {
+                self.end_word(start_time);
+                let decoded = self.text_tokenizer.decode_piece_ids(&tokens);
+                // Not strict, the word is skipped with a warning.
+                let text = WordDecoder::default()
+                    .word(&tokens, decoded)
+                    .unwrap_or_default();
+                self.pending = Some(Word {
+                    text,
+                    start_time,
+                    stop_time: start_time,
+                    confidence,
+                })
+            }
+            asr::AsrMsg::EndWord { stop_time, .. } => self.end_word(stop_time),
+            asr::AsrMsg::Step { step_idx, prs } => {
+                let Some(endpointing) = self.endpointing else {
+                    return;
+                };
+                let probability = prs[endpointing.horizon][0];
+                if !self.endpoint_sent && probability > endpointing.threshold {
+                    self.endpoint_sent = true;
+                    // Aligned with the audio as the word times are.
+                    let step_idx = step_idx.saturating_sub(self.asr_delay_in_tokens);
+                    let time = step_idx as f64 / self.frame_rate;
+                    self.events
+                        .push_back(AsrEvent::Endpoint { time, probability })
+                }
+            }
+            asr::AsrMsg::Partial { .. } | asr::AsrMsg::TextLogits { .. } => {}
+        }
+    }
+
+    fn end_word(&mut self, stop_time: f64) {
+        if let Some(mut word) = self.pending.take() {
+            word.stop_time = f64::max(word.start_time, stop_time);
+            self.events.push_back(AsrEvent::Word(word));
+            self.endpoint_sent = false;
+        }
+    }
+
+    /// Feeds enough silence for the words of the audio pushed so far to be decoded.
+    pub fn flush(&mut self) -> Result<()> {
+        let (sample_rate, frame_size) = (self.sample_rate, self.frame_size);
+        let end_time = (self.num_samples + self.pcm.len()) as f64 / sample_rate as f64;
+        let suffix = (self.audio_delay_seconds * sample_rate as f64) as usize + sample_rate;
+        let padding = suffix + (frame_size - self.pcm.len() % frame_size) % frame_size;
+        self.push_pcm(&vec![0.; padding])?;
+        self.end_word(end_time);
+        Ok(())
+    }
+
+    /// Transcribes a whole buffer of mono pcm at `sample_rate`, resampled to the sample rate of the
+    /// model if needed, in a new stream. The samples are expected in [-1, 1] as with `push_pcm`.
+    pub fn transcribe(&mut self, pcm: &[f32], sample_rate: usize) -> Result<Vec<Word>> {
+        self.reset()?;
+        // The same resampling as the command line tool with its default options.
+        let resampling = crate::resample::Resampling::new(self.sample_rate);
+        let mut events = if resampling.is_noop(sample_rate) {
+            self.push(pcm)?
+        } else {
+            let pcm = resampling
+                .apply(pcm.to_vec(), sample_rate)
+                .map_err(|err| SttError::Resample(format!("{err:#}")))?;
+            self.push(&pcm)?
+        };
+        events.extend(self.finish()?);
+        let words = events.into_iter().filter_map(|event| match event {
+            AsrEvent::Word(word) => Some(word),
+            AsrEvent::Endpoint { .. } | AsrEvent::Error { .. } => None,
+        });
+        Ok(words.collect())
+    }
+
+    /// Feeds a burst of mono pcm at `sample_rate` and returns the events completed so far. The
+    /// stream is kept across calls, so the bursts of a push-to-talk session share their context.
+    pub fn push(&mut self, pcm: &[f32]) -> Result<Vec<AsrEvent>> {
+        self.push_pcm(pcm)?;
+        Ok(self.events.drain(..).collect())
+    }
+
+    /// Ends the stream and returns its remaining events, the audio is padded with silence so that
+    /// the last words get decoded. The transcriber is then ready for a new stream.
+    pub fn finish(&mut self) -> Result<Vec<AsrEvent>> {
+        self.flush()?;
+        let events = self.events.drain(..).collect();
+        self.reset()?;
+        Ok(events)
+    }
+
+    /// Pops the oldest event, either a completed word or an endpoint.
+    pub fn next_event(&mut self) -> Option<AsrEvent> {
+        self.events.pop_front()
+    }
+
+    /// Pops the oldest completed word, discarding the endpoints that come before it.
+    pub fn next_word(&mut self) -> Option<Word> {
+        while let Some(event) = self.events.pop_front() {
+            if let AsrEvent::Word(word) = event {
+                return Some(word);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_formats() -> Result<()> {
+        let pcm = [0., 0.5, -0.25, 1.];
+        for format in [SampleFormat::F32, SampleFormat::I16] {
+            let data = format.encode(&pcm);
+            assert_eq!(data.len(), pcm.len() * format.sample_size());
+            let decoded = format.decode(&data)?;
+            for (v, d) in pcm.iter().zip(decoded.iter()) {
+                assert!((v - d).abs() < 1e-4, "{format:?} {v} {d}");
+            }
+            assert!(format.decode(&data[1..]).is_err());
+        }
+        let init = serde_json::from_str(r#"{"type": "init", "sample_format": "i16"}"#);
+        assert!(matches!(
+            init,
+            Ok(ClientMessage::Init {
+                sample_format: SampleFormat::I16
+            })
+        ));
+        Ok(())
+    }
+}
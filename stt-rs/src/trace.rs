@@ -0,0 +1,82 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Timings of the model steps recorded with `--trace-chunks`, used to look for latency spikes
+//! such as periodic stalls.
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+/// Each chunk is 80ms of audio, a step that takes longer falls behind real time.
+const REAL_TIME_BUDGET: Duration = Duration::from_millis(80);
+
+/// Upper bounds of the histogram buckets, in milliseconds.
+const BUCKETS_MS: [u64; 7] = [5, 10, 20, 40, 80, 160, 320];
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkTiming {
+    pub start: Instant,
+    /// Wall-clock time of the step, including the device synchronization.
+    pub duration: Duration,
+}
+
+/// Prints a histogram of the step durations on stderr, along with the steps over the real-time
+/// budget.
+pub fn print_histogram(timings: &[ChunkTiming]) {
+    if timings.is_empty() {
+        eprintln!("No chunk has been traced");
+        return;
+    }
+    let mut counts = [0usize; BUCKETS_MS.len() + 1];
+    for timing in timings.iter() {
+        let ms = timing.duration.as_secs_f64() * 1000.;
+        let bucket = BUCKETS_MS.iter().position(|&b| ms < b as f64);
+        counts[bucket.unwrap_or(BUCKETS_MS.len())] += 1
+    }
+    let max_count = counts.iter().copied().max().unwrap_or(1);
+    eprintln!("Chunk timings over {} steps", timings.len());
+    for (idx, &count) in counts.iter().enumerate() {
+        let label = match (
+            idx.checked_sub(1).map(|i| BUCKETS_MS[i]),
+            BUCKETS_MS.get(idx),
+        ) {
+            (None, Some(hi)) => format!("<{hi}ms"),
+            (Some(lo), Some(hi)) => format!("{lo}-{hi}ms"),
+            (Some(lo), None) => format!(">={lo}ms"),
+            (None, None) => unreachable!("there is at least one bucket"),
+        };
+        let bar = "#".repeat((count * 40).div_ceil(max_count));
+        eprintln!("{label:>10} {count:>8} {bar}");
+    }
+    let mut durations = timings.iter().map(|t| t.duration).collect::<Vec<_>>();
+    durations.sort();
+    let percentile = |p: f64| {
+        let idx = ((durations.len() - 1) as f64 * p).round() as usize;
+        durations[idx].as_secs_f64() * 1000.
+    };
+    let slow = durations.iter().filter(|&&d| d > REAL_TIME_BUDGET).count();
+    eprintln!(
+        "median {:.2}ms, p99 {:.2}ms, max {:.2}ms, {slow} steps over {}ms",
+        percentile(0.5),
+        percentile(0.99),
+        percentile(1.),
+        REAL_TIME_BUDGET.as_millis()
+    );
+}
+
+/// Writes the raw timings as csv, with the index of the step, its start time relative to the
+/// first step and its duration, both in milliseconds.
+pub fn write_csv(path: &std::path::Path, timings: &[ChunkTiming]) -> Result<()> {
+    use std::fmt::Write;
+
+    let mut csv = String::from("chunk,start_ms,duration_ms\n");
+    let first = timings.first().map(|t| t.start);
+    for (idx, timing) in timings.iter().enumerate() {
+        let start = first.map_or(Duration::ZERO, |first| timing.start - first);
+        let start_ms = start.as_secs_f64() * 1000.;
+        let duration_ms = timing.duration.as_secs_f64() * 1000.;
+        writeln!(csv, "{idx},{start_ms:.3},{duration_ms:.3}")?;
+    }
+    std::fs::write(path, csv)?;
+    Ok(())
+}
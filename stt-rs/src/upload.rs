@@ -0,0 +1,439 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! Streaming decoding of the uploaded audio files, the audio is decoded and fed to the model as
+//! the upload progresses so that only a few chunks are held in memory at any time.
+use anyhow::Result;
+use axum::body::Bytes;
+use symphonia::core::audio::SampleBuffer;
+
+use kyutai_stt_rs::resample::{Resampling, StreamResampler};
+
+/// Number of body chunks buffered between the request and the decoder.
+const CHUNKS_IN_FLIGHT: usize = 16;
+
+/// The upload is larger than `--max-upload-bytes`, reported as a 413 by the server.
+#[derive(Debug, Clone, Copy)]
+pub struct UploadTooLarge {
+    pub max_bytes: usize,
+}
+
+impl std::fmt::Display for UploadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the upload exceeds {} bytes", self.max_bytes)
+    }
+}
+
+impl std::error::Error for UploadTooLarge {}
+
+/// Rejects the request upfront when its `Content-Length` is over `max_bytes`. Chunked uploads
+/// have no length, they are checked by `limit_body` as they arrive.
+pub fn check_content_length(headers: &axum::http::HeaderMap, max_bytes: usize) -> Result<()> {
+    let content_length = headers
+        .get(axum::http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match content_length {
+        Some(len) if len > max_bytes as u64 => Err(UploadTooLarge { max_bytes }.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The chunks of `body`, the stream ends with an `UploadTooLarge` error as soon as more than
+/// `max_bytes` have been received, without buffering the rest of the body.
+pub fn limit_body(
+    body: axum::body::Body,
+    max_bytes: usize,
+) -> impl futures_util::Stream<Item = Result<Bytes>> {
+    use futures_util::StreamExt;
+
+    // `None` once the limit has been reached, which ends the stream.
+    body.into_data_stream()
+        .scan(Some(0), move |received, chunk| {
+            let item = received.as_mut().map(|received| {
+                let chunk = chunk?;
+                *received += chunk.len();
+                if *received > max_bytes {
+                    return Err(UploadTooLarge { max_bytes }.into());
+                }
+                Ok(chunk)
+            });
+            if matches!(item, Some(Err(_))) {
+                *received = None
+            }
+            std::future::ready(item)
+        })
+}
+
+/// Reads the whole body in memory, up to `max_bytes`.
+pub async fn read_body(body: axum::body::Body, max_bytes: usize) -> Result<Vec<u8>> {
+    use futures_util::TryStreamExt;
+
+    limit_body(body, max_bytes)
+        .try_fold(vec![], |mut buf, chunk| {
+            buf.extend_from_slice(&chunk);
+            std::future::ready(Ok(buf))
+        })
+        .await
+}
+
+/// Extracts the data of the first part of a `multipart/form-data` body as it arrives.
+pub struct MultipartStream {
+    /// `--boundary`.
+    delimiter: Vec<u8>,
+    state: MultipartState,
+    buf: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MultipartState {
+    Preamble,
+    Headers,
+    Data,
+    Done,
+}
+
+impl MultipartStream {
+    /// `None` if the request is not a multipart upload.
+    pub fn from_headers(headers: &axum::http::HeaderMap) -> Option<Self> {
+        let content_type = headers
+            .get(axum::http::header::CONTENT_TYPE)?
+            .to_str()
+            .ok()?;
+        if !content_type.starts_with("multipart/form-data") {
+            return None;
+        }
+        let boundary = content_type
+            .split(';')
+            .find_map(|p| p.trim().strip_prefix("boundary="))?;
+        Some(Self {
+            delimiter: format!("--{}", boundary.trim_matches('"')).into_bytes(),
+            state: MultipartState::Preamble,
+            buf: vec![],
+        })
+    }
+
+    /// Returns the bytes of the file part that are complete in `chunk`, the end of the chunk is
+    /// held back if it could be the start of the closing delimiter.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let find = |haystack: &[u8], needle: &[u8]| {
+            haystack.windows(needle.len()).position(|w| w == needle)
+        };
+        self.buf.extend_from_slice(chunk);
+        let mut data = vec![];
+        loop {
+            match self.state {
+                MultipartState::Preamble => match find(&self.buf, &self.delimiter) {
+                    Some(pos) => {
+                        self.buf.drain(..pos + self.delimiter.len());
+                        self.state = MultipartState::Headers
+                    }
+                    None => {
+                        let keep = self.delimiter.len().min(self.buf.len());
+                        self.buf.drain(..self.buf.len() - keep);
+                        break;
+                    }
+                },
+                MultipartState::Headers => match find(&self.buf, b"\r\n\r\n") {
+                    Some(pos) => {
+                        self.buf.drain(..pos + 4);
+                        self.state = MultipartState::Data
+                    }
+                    None => break,
+                },
+                MultipartState::Data => {
+                    let end = [b"\r\n", self.delimiter.as_slice()].concat();
+                    match find(&self.buf, &end) {
+                        Some(pos) => {
+                            data.extend_from_slice(&self.buf[..pos]);
+                            self.buf.clear();
+                            self.state = MultipartState::Done
+                        }
+                        None => {
+                            let len = self.buf.len().saturating_sub(end.len());
+                            data.extend(self.buf.drain(..len));
+                        }
+                    }
+                    break;
+                }
+                MultipartState::Done => {
+                    self.buf.clear();
+                    break;
+                }
+            }
+        }
+        data
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        match self.state {
+            MultipartState::Done => Ok(()),
+            MultipartState::Preamble | MultipartState::Headers => {
+                anyhow::bail!("no file in the multipart body")
+            }
+            MultipartState::Data => anyhow::bail!("truncated multipart body"),
+        }
+    }
+}
+
+/// Blocking reader over the chunks of the request body.
+struct ChannelReader {
+    chunks: tokio::sync::mpsc::Receiver<std::io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl std::io::Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.chunks.blocking_recv() {
+                None => return Ok(0),
+                Some(chunk) => self.chunk = chunk?,
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+/// Forwards the body to the decoder, stripping the multipart framing if any and stopping with an
+/// error once more than `max_bytes` have been received. The upload being too large is also
+/// returned so that it takes precedence over the decoding error it causes.
+async fn forward_body(
+    headers: axum::http::HeaderMap,
+    body: axum::body::Body,
+    max_bytes: usize,
+    chunks: tokio::sync::mpsc::Sender<std::io::Result<Bytes>>,
+) -> Result<()> {
+    use futures_util::StreamExt;
+
+    let mut multipart = MultipartStream::from_headers(&headers);
+    let mut stream = std::pin::pin!(limit_body(body, max_bytes));
+    let err = loop {
+        let chunk = match stream.next().await {
+            None => break multipart.as_ref().and_then(|m| m.finish().err()),
+            Some(Err(err)) => break Some(err),
+            Some(Ok(chunk)) => chunk,
+        };
+        let chunk = match multipart.as_mut() {
+            Some(multipart) => Bytes::from(multipart.push(&chunk)),
+            None => chunk,
+        };
+        // The decoder has stopped, either on an error or because the job is over.
+        if chunks.send(Ok(chunk)).await.is_err() {
+            return Ok(());
+        }
+    };
+    match err {
+        None => Ok(()),
+        Some(err) => {
+            let _ = chunks
+                .send(Err(std::io::Error::other(format!("{err:#}"))))
+                .await;
+            match err.downcast::<UploadTooLarge>() {
+                Ok(too_large) => Err(too_large.into()),
+                Err(_) => Ok(()),
+            }
+        }
+    }
+}
+
+/// Decodes the audio read from `reader` as mono pcm at the target rate, keeping the first channel
+/// like `kaudio::pcm_decode`, and sends it to `pcm` packet by packet.
+fn decode(
+    reader: ChannelReader,
+    resampling: &Resampling,
+    pcm: tokio::sync::mpsc::UnboundedSender<Vec<f32>>,
+) -> Result<()> {
+    let source = symphonia::core::io::ReadOnlySource::new(reader);
+    let mss = symphonia::core::io::MediaSourceStream::new(Box::new(source), Default::default());
+    let probed = symphonia::default::get_probe().format(
+        &Default::default(),
+        mss,
+        &Default::default(),
+        &Default::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != symphonia::core::codecs::CODEC_TYPE_NULL)
+        .ok_or_else(|| anyhow::anyhow!("no supported audio track"))?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or_else(|| anyhow::anyhow!("unknown sample rate"))?;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &Default::default())?;
+    let mut resampler = StreamResampler::new(resampling, sample_rate as usize)?;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(symphonia::core::errors::Error::IoError(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = decoder.decode(&packet)?;
+        let channels = decoded.spec().channels.count();
+        let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+        samples.copy_interleaved_ref(decoded);
+        let chunk = samples.samples().iter().step_by(channels).copied();
+        let chunk = resampler.push(&chunk.collect::<Vec<_>>())?;
+        // The job is over, e.g. the client has gone away.
+        if pcm.send(chunk).is_err() {
+            return Ok(());
+        }
+    }
+    let _ = pcm.send(resampler.finish()?);
+    Ok(())
+}
+
+/// Starts decoding the request body, the pcm at `sample_rate` is sent on `pcm` as it is decoded.
+/// The returned handle resolves once the whole body has been decoded, to the decoding error if
+/// any or to `UploadTooLarge`.
+pub fn start(
+    headers: axum::http::HeaderMap,
+    body: axum::body::Body,
+    max_bytes: usize,
+    sample_rate: usize,
+    pcm: tokio::sync::mpsc::UnboundedSender<Vec<f32>>,
+) -> tokio::task::JoinHandle<Result<()>> {
+    let (tx, rx) = tokio::sync::mpsc::channel(CHUNKS_IN_FLIGHT);
+    let forwarding = tokio::spawn(forward_body(headers, body, max_bytes, tx));
+    let reader = ChannelReader {
+        chunks: rx,
+        chunk: Bytes::new(),
+    };
+    let resampling = Resampling::new(sample_rate);
+    let decoding = tokio::task::spawn_blocking(move || decode(reader, &resampling, pcm));
+    tokio::spawn(async move {
+        let decoded = decoding.await?;
+        forwarding.await??;
+        decoded
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a.wav\"\r\n\
+        Content-Type: audio/wav\r\n\r\n\
+        RIFF\r\n--xy data\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"language\"\r\n\r\n\
+        en\r\n--xyz--\r\n";
+
+    fn multipart(content_type: &str) -> MultipartStream {
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert(
+            axum::http::header::CONTENT_TYPE,
+            content_type.parse().unwrap(),
+        );
+        MultipartStream::from_headers(&headers).unwrap()
+    }
+
+    /// The data of the file part along with the result of `finish`.
+    fn extract(content_type: &str, chunks: &[&[u8]]) -> (Vec<u8>, Result<()>) {
+        let mut stream = multipart(content_type);
+        let data = chunks.iter().flat_map(|chunk| stream.push(chunk)).collect();
+        (data, stream.finish())
+    }
+
+    #[test]
+    fn multipart_split_anywhere() {
+        // Every split position, including in the middle of the opening and closing delimiters.
+        for idx in 0..=BODY.len() {
+            let (head, tail) = BODY.split_at(idx);
+            let (data, finished) = extract("multipart/form-data; boundary=xyz", &[head, tail]);
+            assert_eq!(data, b"RIFF\r\n--xy data", "split at {idx}");
+            assert!(finished.is_ok(), "split at {idx}");
+        }
+        // One byte at a time.
+        let chunks = BODY.chunks(1).collect::<Vec<_>>();
+        let (data, finished) = extract("multipart/form-data; boundary=xyz", &chunks);
+        assert_eq!(data, b"RIFF\r\n--xy data");
+        assert!(finished.is_ok());
+    }
+
+    #[test]
+    fn multipart_quoted_boundary() {
+        let content_type = "multipart/form-data; boundary=\"xyz\"; charset=utf-8";
+        let (data, finished) = extract(content_type, &[BODY]);
+        assert_eq!(data, b"RIFF\r\n--xy data");
+        assert!(finished.is_ok());
+        let mut headers = axum::http::HeaderMap::new();
+        assert!(MultipartStream::from_headers(&headers).is_none());
+        headers.insert(
+            axum::http::header::CONTENT_TYPE,
+            "audio/wav".parse().unwrap(),
+        );
+        assert!(MultipartStream::from_headers(&headers).is_none());
+    }
+
+    #[test]
+    fn multipart_without_file() {
+        let content_type = "multipart/form-data; boundary=xyz";
+        for body in [&b"--xyz--\r\n"[..], b"", b"no delimiter at all"] {
+            let (data, finished) = extract(content_type, &[body]);
+            assert!(data.is_empty());
+            let err = finished.unwrap_err().to_string();
+            assert_eq!(err, "no file in the multipart body");
+        }
+        // A different boundary than the one of the body.
+        let (data, finished) = extract("multipart/form-data; boundary=abc", &[BODY]);
+        assert!(data.is_empty());
+        assert!(finished.is_err());
+    }
+
+    #[test]
+    fn multipart_truncated() {
+        let content_type = "multipart/form-data; boundary=xyz";
+        // Right before the delimiter closing the file part, then in the middle of it.
+        let end = BODY
+            .windows(12)
+            .position(|w| w == b" data\r\n--xyz")
+            .unwrap()
+            + 5;
+        for len in [end, end + 5] {
+            let (data, finished) = extract(content_type, &[&BODY[..len]]);
+            assert!(b"RIFF\r\n--xy data".starts_with(&data), "{len}");
+            let err = finished.unwrap_err().to_string();
+            assert_eq!(err, "truncated multipart body");
+        }
+        // Cut in the headers of the file part.
+        let (data, finished) = extract(content_type, &[&BODY[..40]]);
+        assert!(data.is_empty());
+        assert!(finished.is_err());
+    }
+
+    #[test]
+    fn content_length_over_limit() {
+        let mut headers = axum::http::HeaderMap::new();
+        assert!(check_content_length(&headers, 10).is_ok());
+        headers.insert(axum::http::header::CONTENT_LENGTH, "10".parse().unwrap());
+        assert!(check_content_length(&headers, 10).is_ok());
+        headers.insert(axum::http::header::CONTENT_LENGTH, "11".parse().unwrap());
+        let err = check_content_length(&headers, 10).unwrap_err();
+        assert!(err.is::<UploadTooLarge>(), "{err}");
+    }
+
+    #[tokio::test]
+    async fn chunked_body_over_limit() {
+        let chunks = |n: usize| {
+            let chunks = (0..n).map(|_| Ok::<_, std::io::Error>(Bytes::from_static(b"abcd")));
+            axum::body::Body::from_stream(futures_util::stream::iter(chunks))
+        };
+        assert_eq!(read_body(chunks(3), 12).await.unwrap().len(), 12);
+        let err = read_body(chunks(4), 12).await.unwrap_err();
+        assert!(err.is::<UploadTooLarge>(), "{err}");
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+//! End to end transcription of the audio files in `tests/fixtures` on cpu. The model is
+//! downloaded from the hub on first use so these tests only run with the `golden` feature:
+//!
+//! ```bash
+//! cargo test --features golden --test golden
+//! ```
+//!
+//! Each `<name>.wav` fixture is checked against the `<name>.json` golden file next to it, a
+//! missing golden file is an error. Run with `UPDATE_GOLDEN=1` to write the golden files from the
+//! current output. `speech_16k.wav` is the first 6 seconds of `audio/bria.mp3`.
+#![cfg(feature = "golden")]
+
+use anyhow::{Context, Result};
+use kyutai_stt_rs::stream::{Transcriber, Word};
+use std::path::{Path, PathBuf};
+
+const HF_REPO: &str = "kyutai/stt-1b-en_fr-candle";
+
+/// The timestamps are expected within one step of the model.
+const TIME_TOLERANCE: f64 = 0.08;
+
+fn fixtures() -> Result<Vec<PathBuf>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut fixtures = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "wav") {
+            fixtures.push(path)
+        }
+    }
+    fixtures.sort();
+    Ok(fixtures)
+}
+
+fn transcribe(transcriber: &mut Transcriber, path: &Path) -> Result<(Vec<Word>, f64)> {
+    let (pcm, sample_rate) =
+        kaudio::pcm_decode(path).with_context(|| format!("cannot decode {}", path.display()))?;
+    let model_rate = transcriber.sample_rate();
+    let pcm = kaudio::resample(&pcm, sample_rate as usize, model_rate)?;
+    transcriber.reset()?;
+    // Odd sized chunks so that the buffering of partial model steps is exercised.
+    for chunk in pcm.chunks(1000) {
+        transcriber.push_pcm(chunk)?;
+    }
+    transcriber.flush()?;
+    let mut words = vec![];
+    while let Some(word) = transcriber.next_word() {
+        words.push(word)
+    }
+    Ok((words, pcm.len() as f64 / model_rate as f64))
+}
+
+#[test]
+fn pipeline_runs_end_to_end() -> Result<()> {
+    let mut transcriber = Transcriber::load(HF_REPO, true)?;
+    for path in fixtures()? {
+        let (words, duration) = transcribe(&mut transcriber, &path)?;
+        let mut last_stop = 0.;
+        for word in words.iter() {
+            assert!(word.start_time >= last_stop - TIME_TOLERANCE, "{word:?}");
+            assert!(word.stop_time >= word.start_time, "{word:?}");
+            assert!(word.stop_time <= duration + TIME_TOLERANCE, "{word:?}");
+            last_stop = word.stop_time;
+        }
+    }
+    Ok(())
+}
+
+/// The state is cleared between streams, a file gives the same words whether it is transcribed
+/// first or after another one.
+#[test]
+fn no_context_between_streams() -> Result<()> {
+    let mut transcriber = Transcriber::load(HF_REPO, true)?;
+    let noise = (0..48_000u32)
+        .map(|i| ((i.wrapping_mul(2_654_435_761) >> 16) as f32 / 65_536. - 0.5) * 0.2)
+        .collect::<Vec<_>>();
+    let mut n_words = 0;
+    for path in fixtures()? {
+        let (first, _) = transcribe(&mut transcriber, &path)?;
+        n_words += first.len();
+        let model_rate = transcriber.sample_rate();
+        transcriber.transcribe(&noise, model_rate)?;
+        let (second, _) = transcribe(&mut transcriber, &path)?;
+        let words = |words: &[Word]| {
+            let words = words
+                .iter()
+                .map(|w| (w.text.clone(), w.start_time, w.stop_time));
+            words.collect::<Vec<_>>()
+        };
+        assert_eq!(words(&first), words(&second), "{}", path.display());
+    }
+    // Otherwise only empty transcripts have been compared.
+    assert!(n_words > 0, "no words in the fixtures");
+    Ok(())
+}
+
+#[test]
+fn transcripts_match_golden() -> Result<()> {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let mut transcriber = Transcriber::load(HF_REPO, true)?;
+    for path in fixtures()? {
+        let golden_path = path.with_extension("json");
+        if !update && !golden_path.exists() {
+            anyhow::bail!(
+                "no golden file for {}, run with UPDATE_GOLDEN=1 to write it",
+                path.display()
+            )
+        }
+        let (words, _) = transcribe(&mut transcriber, &path)?;
+        if update {
+            std::fs::write(&golden_path, serde_json::to_string_pretty(&words)? + "\n")?;
+            continue;
+        }
+        let golden: Vec<Word> = serde_json::from_str(&std::fs::read_to_string(&golden_path)?)?;
+        let text = |words: &[Word]| words.iter().map(|w| w.text.clone()).collect::<Vec<_>>();
+        assert_eq!(text(&words), text(&golden), "{}", path.display());
+        for (word, expected) in words.iter().zip(golden.iter()) {
+            let close = |a: f64, b: f64| (a - b).abs() <= TIME_TOLERANCE;
+            assert!(
+                close(word.start_time, expected.start_time)
+                    && close(word.stop_time, expected.stop_time),
+                "{}: {word:?} expected {expected:?}",
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}